@@ -0,0 +1,25 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use user_mode_riscv::cpu::aot::{translate, Image};
+
+// Translates every test binary into OUT_DIR, named after the binary with dashes turned into underscores
+fn main() {
+    let tests = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../test");
+    let out = PathBuf::from(env::var("OUT_DIR").expect("OUT_DIR is set by cargo"));
+    println!("cargo:rerun-if-changed={}", tests.display());
+
+    for entry in fs::read_dir(&tests).expect("can't read the test binaries") {
+        let path = entry.expect("can't read the test binaries").path();
+        // the objdump listings and the README are not ELF files
+        let mut image = match Image::from_elf(&fs::read(&path).expect("can't read a test binary")) {
+            Some(image) => image,
+            None => continue
+        };
+
+        // the tests load every image at address 0
+        image.relocate(0);
+        let name = path.file_name().and_then(|name| name.to_str()).expect("test binary names are UTF-8").replace('-', "_");
+        fs::write(out.join(format!("{}.rs", name)), translate(&image)).expect("can't write the translated code");
+    }
+}
@@ -0,0 +1,607 @@
+// Runs the bundled riscv-tests binaries from the Rust that build.rs translated them into, the same
+// way the main crate's tests run them through the interpreter
+#[cfg(test)]
+mod test {
+    use user_mode_riscv::cpu::aot::Image;
+    use user_mode_riscv::cpu::instruction::Instruction;
+    use user_mode_riscv::cpu::*;
+    use user_mode_riscv::memory::PAGE_SIZE;
+
+    const MAX_SIZE: usize = 1024 * 128;
+    const STACK_SIZE: usize = 1024 * 16;
+
+    fn run_test(binary_blob: &[u8], tick_block: fn(&mut Cpu, &mut Vec<u8>) -> Result<usize, Trap>) {
+        let mut image = Image::from_elf(binary_blob).expect("Got proper ELF file");
+        image.relocate(0);
+        let mut target: Vec<u8> = vec![0; MAX_SIZE + STACK_SIZE];
+        image.load(&mut target).expect("Can't load the binary?");
+
+        let mut cpu = Cpu::new();
+        cpu.set_decode_cache(true);
+        cpu.set_ecall_handler(Some(Instruction{
+            name: "ECALL",
+            operation: |cpu, _memory, _word, _address| {
+                match cpu.get_register(Register::A7) {
+                    64 => Ok(()), // WRITE
+                    93 => Err(Trap { trap_type: TrapType::Stop, value: cpu.get_register(Register::A0) as u64 }),
+                    num => Err(Trap { trap_type: TrapType::SupervisorSoftwareInterrupt, value: num as u64})
+                }
+            }
+        }));
+
+        cpu.update_pc(image.entry);
+        cpu.set_stack(MAX_SIZE + STACK_SIZE - 1, STACK_SIZE, PAGE_SIZE);
+        let mut fuel: usize = 1_000_000_000;
+
+        loop {
+            let pc = cpu.get_pc();
+            match tick_block(&mut cpu, &mut target) {
+                Ok(retired) => {
+                    fuel = fuel.saturating_sub(retired);
+                    if fuel == 0 {
+                        panic!("out of fuel");
+                    }
+                },
+                Err(e) => {
+                    match e.trap_type {
+                        TrapType::Stop => {
+                            if e.value != 0 {
+                                panic!("CPU test {:?} failed a0={:#x} a1={:#x} a2={:#x} a3={:#x} a4={:#x} t2={:#x}", e.value >> 1, cpu.get_register(Register::A0), cpu.get_register(Register::A1), cpu.get_register(Register::A2), cpu.get_register(Register::A3), cpu.get_register(Register::A4), cpu.get_register(Register::T2));
+                            }
+                            break;
+                        },
+                        _ => panic!("CPU failure: pc = {:#x} - {:?}", pc, e)
+                    }
+                }
+            }
+        }
+    }
+
+    macro_rules! aot_test {
+        ( $name:ident, $binary:literal ) => {
+            #[allow(clippy::all)]
+            mod translated {
+                include!(concat!(env!("OUT_DIR"), "/", stringify!($name), ".rs"));
+            }
+
+            run_test(include_bytes!(concat!("../../test/", $binary)), translated::tick_block);
+        }
+    }
+
+        #[test]
+        fn mandelbrot() {
+            aot_test!(mandelbrot, "mandelbrot");
+        }
+
+        #[test]
+        fn mandelbrot_debug() {
+            aot_test!(mandelbrot_debug, "mandelbrot-debug");
+        }
+
+        #[test]
+        fn rv64ua_p_amoadd_d() {
+            aot_test!(rv64ua_p_amoadd_d, "rv64ua-p-amoadd_d");
+        }
+
+        #[test]
+        fn rv64ua_p_amoadd_w() {
+            aot_test!(rv64ua_p_amoadd_w, "rv64ua-p-amoadd_w");
+        }
+
+        #[test]
+        fn rv64ua_p_amoand_d() {
+            aot_test!(rv64ua_p_amoand_d, "rv64ua-p-amoand_d");
+        }
+
+        #[test]
+        fn rv64ua_p_amoand_w() {
+            aot_test!(rv64ua_p_amoand_w, "rv64ua-p-amoand_w");
+        }
+
+        #[test]
+        fn rv64ua_p_amomax_d() {
+            aot_test!(rv64ua_p_amomax_d, "rv64ua-p-amomax_d");
+        }
+
+        #[test]
+        fn rv64ua_p_amomax_w() {
+            aot_test!(rv64ua_p_amomax_w, "rv64ua-p-amomax_w");
+        }
+
+        #[test]
+        fn rv64ua_p_amomaxu_d() {
+            aot_test!(rv64ua_p_amomaxu_d, "rv64ua-p-amomaxu_d");
+        }
+
+        #[test]
+        fn rv64ua_p_amomaxu_w() {
+            aot_test!(rv64ua_p_amomaxu_w, "rv64ua-p-amomaxu_w");
+        }
+
+        #[test]
+        fn rv64ua_p_amomin_d() {
+            aot_test!(rv64ua_p_amomin_d, "rv64ua-p-amomin_d");
+        }
+
+        #[test]
+        fn rv64ua_p_amomin_w() {
+            aot_test!(rv64ua_p_amomin_w, "rv64ua-p-amomin_w");
+        }
+
+        #[test]
+        fn rv64ua_p_amominu_d() {
+            aot_test!(rv64ua_p_amominu_d, "rv64ua-p-amominu_d");
+        }
+
+        #[test]
+        fn rv64ua_p_amominu_w() {
+            aot_test!(rv64ua_p_amominu_w, "rv64ua-p-amominu_w");
+        }
+
+        #[test]
+        fn rv64ua_p_amoor_d() {
+            aot_test!(rv64ua_p_amoor_d, "rv64ua-p-amoor_d");
+        }
+
+        #[test]
+        fn rv64ua_p_amoor_w() {
+            aot_test!(rv64ua_p_amoor_w, "rv64ua-p-amoor_w");
+        }
+
+        #[test]
+        fn rv64ua_p_amoswap_d() {
+            aot_test!(rv64ua_p_amoswap_d, "rv64ua-p-amoswap_d");
+        }
+
+        #[test]
+        fn rv64ua_p_amoswap_w() {
+            aot_test!(rv64ua_p_amoswap_w, "rv64ua-p-amoswap_w");
+        }
+
+        #[test]
+        fn rv64ua_p_amoxor_d() {
+            aot_test!(rv64ua_p_amoxor_d, "rv64ua-p-amoxor_d");
+        }
+
+        #[test]
+        fn rv64ua_p_amoxor_w() {
+            aot_test!(rv64ua_p_amoxor_w, "rv64ua-p-amoxor_w");
+        }
+
+        #[test]
+        fn rv64ua_p_lrsc() {
+            aot_test!(rv64ua_p_lrsc, "rv64ua-p-lrsc");
+        }
+
+        #[test]
+        fn rv64uc_p_rvc() {
+            aot_test!(rv64uc_p_rvc, "rv64uc-p-rvc");
+        }
+
+        #[test]
+        fn rv64ud_p_fadd() {
+            aot_test!(rv64ud_p_fadd, "rv64ud-p-fadd");
+        }
+
+        #[test]
+        fn rv64ud_p_fcmp() {
+            aot_test!(rv64ud_p_fcmp, "rv64ud-p-fcmp");
+        }
+
+        #[test]
+        fn rv64ud_p_fcvt() {
+            aot_test!(rv64ud_p_fcvt, "rv64ud-p-fcvt");
+        }
+
+        #[test]
+        fn rv64ud_p_fcvt_w() {
+            aot_test!(rv64ud_p_fcvt_w, "rv64ud-p-fcvt_w");
+        }
+
+        #[test]
+        fn rv64ud_p_fdiv() {
+            aot_test!(rv64ud_p_fdiv, "rv64ud-p-fdiv");
+        }
+
+        #[test]
+        fn rv64ud_p_fmadd() {
+            aot_test!(rv64ud_p_fmadd, "rv64ud-p-fmadd");
+        }
+
+        #[test]
+        fn rv64ud_p_fmin() {
+            aot_test!(rv64ud_p_fmin, "rv64ud-p-fmin");
+        }
+
+        #[test]
+        fn rv64ud_p_ldst() {
+            aot_test!(rv64ud_p_ldst, "rv64ud-p-ldst");
+        }
+
+        #[test]
+        #[ignore]
+        fn rv64ud_p_move() {
+            aot_test!(rv64ud_p_move, "rv64ud-p-move");
+        }
+
+        #[test]
+        fn rv64ud_p_recoding() {
+            aot_test!(rv64ud_p_recoding, "rv64ud-p-recoding");
+        }
+
+        #[test]
+        fn rv64ud_p_structural() {
+            aot_test!(rv64ud_p_structural, "rv64ud-p-structural");
+        }
+
+        #[test]
+        fn rv64uf_p_fadd() {
+            aot_test!(rv64uf_p_fadd, "rv64uf-p-fadd");
+        }
+
+        #[test]
+        fn rv64uf_p_fcmp() {
+            aot_test!(rv64uf_p_fcmp, "rv64uf-p-fcmp");
+        }
+
+        #[test]
+        fn rv64uf_p_fcvt() {
+            aot_test!(rv64uf_p_fcvt, "rv64uf-p-fcvt");
+        }
+
+        #[test]
+        fn rv64uf_p_fcvt_w() {
+            aot_test!(rv64uf_p_fcvt_w, "rv64uf-p-fcvt_w");
+        }
+
+        #[test]
+        fn rv64uf_p_fdiv() {
+            aot_test!(rv64uf_p_fdiv, "rv64uf-p-fdiv");
+        }
+
+        #[test]
+        fn rv64uf_p_fmadd() {
+            aot_test!(rv64uf_p_fmadd, "rv64uf-p-fmadd");
+        }
+
+        #[test]
+        fn rv64uf_p_fmin() {
+            aot_test!(rv64uf_p_fmin, "rv64uf-p-fmin");
+        }
+
+        #[test]
+        fn rv64uf_p_ldst() {
+            aot_test!(rv64uf_p_ldst, "rv64uf-p-ldst");
+        }
+
+        #[test]
+        #[ignore]
+        fn rv64uf_p_move() {
+            aot_test!(rv64uf_p_move, "rv64uf-p-move");
+        }
+
+        #[test]
+        fn rv64uf_p_recoding() {
+            aot_test!(rv64uf_p_recoding, "rv64uf-p-recoding");
+        }
+
+        #[test]
+        fn rv64ui_p_add() {
+            aot_test!(rv64ui_p_add, "rv64ui-p-add");
+        }
+
+        #[test]
+        fn rv64ui_p_addi() {
+            aot_test!(rv64ui_p_addi, "rv64ui-p-addi");
+        }
+
+        #[test]
+        fn rv64ui_p_addiw() {
+            aot_test!(rv64ui_p_addiw, "rv64ui-p-addiw");
+        }
+
+        #[test]
+        fn rv64ui_p_addw() {
+            aot_test!(rv64ui_p_addw, "rv64ui-p-addw");
+        }
+
+        #[test]
+        fn rv64ui_p_and() {
+            aot_test!(rv64ui_p_and, "rv64ui-p-and");
+        }
+
+        #[test]
+        fn rv64ui_p_andi() {
+            aot_test!(rv64ui_p_andi, "rv64ui-p-andi");
+        }
+
+        #[test]
+        fn rv64ui_p_auipc() {
+            aot_test!(rv64ui_p_auipc, "rv64ui-p-auipc");
+        }
+
+        #[test]
+        fn rv64ui_p_beq() {
+            aot_test!(rv64ui_p_beq, "rv64ui-p-beq");
+        }
+
+        #[test]
+        fn rv64ui_p_bge() {
+            aot_test!(rv64ui_p_bge, "rv64ui-p-bge");
+        }
+
+        #[test]
+        fn rv64ui_p_bgeu() {
+            aot_test!(rv64ui_p_bgeu, "rv64ui-p-bgeu");
+        }
+
+        #[test]
+        fn rv64ui_p_blt() {
+            aot_test!(rv64ui_p_blt, "rv64ui-p-blt");
+        }
+
+        #[test]
+        fn rv64ui_p_bltu() {
+            aot_test!(rv64ui_p_bltu, "rv64ui-p-bltu");
+        }
+
+        #[test]
+        fn rv64ui_p_bne() {
+            aot_test!(rv64ui_p_bne, "rv64ui-p-bne");
+        }
+
+        #[test]
+        fn rv64ui_p_fence_i() {
+            aot_test!(rv64ui_p_fence_i, "rv64ui-p-fence_i");
+        }
+
+        #[test]
+        fn rv64ui_p_jal() {
+            aot_test!(rv64ui_p_jal, "rv64ui-p-jal");
+        }
+
+        #[test]
+        fn rv64ui_p_jalr() {
+            aot_test!(rv64ui_p_jalr, "rv64ui-p-jalr");
+        }
+
+        #[test]
+        fn rv64ui_p_lb() {
+            aot_test!(rv64ui_p_lb, "rv64ui-p-lb");
+        }
+
+        #[test]
+        fn rv64ui_p_lbu() {
+            aot_test!(rv64ui_p_lbu, "rv64ui-p-lbu");
+        }
+
+        #[test]
+        fn rv64ui_p_ld() {
+            aot_test!(rv64ui_p_ld, "rv64ui-p-ld");
+        }
+
+        #[test]
+        fn rv64ui_p_lh() {
+            aot_test!(rv64ui_p_lh, "rv64ui-p-lh");
+        }
+
+        #[test]
+        fn rv64ui_p_lhu() {
+            aot_test!(rv64ui_p_lhu, "rv64ui-p-lhu");
+        }
+
+        #[test]
+        fn rv64ui_p_lui() {
+            aot_test!(rv64ui_p_lui, "rv64ui-p-lui");
+        }
+
+        #[test]
+        fn rv64ui_p_lw() {
+            aot_test!(rv64ui_p_lw, "rv64ui-p-lw");
+        }
+
+        #[test]
+        fn rv64ui_p_lwu() {
+            aot_test!(rv64ui_p_lwu, "rv64ui-p-lwu");
+        }
+
+        #[test]
+        fn rv64ui_p_or() {
+            aot_test!(rv64ui_p_or, "rv64ui-p-or");
+        }
+
+        #[test]
+        fn rv64ui_p_ori() {
+            aot_test!(rv64ui_p_ori, "rv64ui-p-ori");
+        }
+
+        #[test]
+        fn rv64ui_p_sb() {
+            aot_test!(rv64ui_p_sb, "rv64ui-p-sb");
+        }
+
+        #[test]
+        fn rv64ui_p_sd() {
+            aot_test!(rv64ui_p_sd, "rv64ui-p-sd");
+        }
+
+        #[test]
+        fn rv64ui_p_sh() {
+            aot_test!(rv64ui_p_sh, "rv64ui-p-sh");
+        }
+
+        #[test]
+        fn rv64ui_p_simple() {
+            aot_test!(rv64ui_p_simple, "rv64ui-p-simple");
+        }
+
+        #[test]
+        fn rv64ui_p_sll() {
+            aot_test!(rv64ui_p_sll, "rv64ui-p-sll");
+        }
+
+        #[test]
+        fn rv64ui_p_slli() {
+            aot_test!(rv64ui_p_slli, "rv64ui-p-slli");
+        }
+
+        #[test]
+        fn rv64ui_p_slliw() {
+            aot_test!(rv64ui_p_slliw, "rv64ui-p-slliw");
+        }
+
+        #[test]
+        fn rv64ui_p_sllw() {
+            aot_test!(rv64ui_p_sllw, "rv64ui-p-sllw");
+        }
+
+        #[test]
+        fn rv64ui_p_slt() {
+            aot_test!(rv64ui_p_slt, "rv64ui-p-slt");
+        }
+
+        #[test]
+        fn rv64ui_p_slti() {
+            aot_test!(rv64ui_p_slti, "rv64ui-p-slti");
+        }
+
+        #[test]
+        fn rv64ui_p_sltiu() {
+            aot_test!(rv64ui_p_sltiu, "rv64ui-p-sltiu");
+        }
+
+        #[test]
+        fn rv64ui_p_sltu() {
+            aot_test!(rv64ui_p_sltu, "rv64ui-p-sltu");
+        }
+
+        #[test]
+        fn rv64ui_p_sra() {
+            aot_test!(rv64ui_p_sra, "rv64ui-p-sra");
+        }
+
+        #[test]
+        fn rv64ui_p_srai() {
+            aot_test!(rv64ui_p_srai, "rv64ui-p-srai");
+        }
+
+        #[test]
+        fn rv64ui_p_sraiw() {
+            aot_test!(rv64ui_p_sraiw, "rv64ui-p-sraiw");
+        }
+
+        #[test]
+        fn rv64ui_p_sraw() {
+            aot_test!(rv64ui_p_sraw, "rv64ui-p-sraw");
+        }
+
+        #[test]
+        fn rv64ui_p_srl() {
+            aot_test!(rv64ui_p_srl, "rv64ui-p-srl");
+        }
+
+        #[test]
+        fn rv64ui_p_srli() {
+            aot_test!(rv64ui_p_srli, "rv64ui-p-srli");
+        }
+
+        #[test]
+        fn rv64ui_p_srliw() {
+            aot_test!(rv64ui_p_srliw, "rv64ui-p-srliw");
+        }
+
+        #[test]
+        fn rv64ui_p_srlw() {
+            aot_test!(rv64ui_p_srlw, "rv64ui-p-srlw");
+        }
+
+        #[test]
+        fn rv64ui_p_sub() {
+            aot_test!(rv64ui_p_sub, "rv64ui-p-sub");
+        }
+
+        #[test]
+        fn rv64ui_p_subw() {
+            aot_test!(rv64ui_p_subw, "rv64ui-p-subw");
+        }
+
+        #[test]
+        fn rv64ui_p_sw() {
+            aot_test!(rv64ui_p_sw, "rv64ui-p-sw");
+        }
+
+        #[test]
+        fn rv64ui_p_xor() {
+            aot_test!(rv64ui_p_xor, "rv64ui-p-xor");
+        }
+
+        #[test]
+        fn rv64ui_p_xori() {
+            aot_test!(rv64ui_p_xori, "rv64ui-p-xori");
+        }
+
+        #[test]
+        fn rv64um_p_div() {
+            aot_test!(rv64um_p_div, "rv64um-p-div");
+        }
+
+        #[test]
+        fn rv64um_p_divu() {
+            aot_test!(rv64um_p_divu, "rv64um-p-divu");
+        }
+
+        #[test]
+        fn rv64um_p_divuw() {
+            aot_test!(rv64um_p_divuw, "rv64um-p-divuw");
+        }
+
+        #[test]
+        fn rv64um_p_divw() {
+            aot_test!(rv64um_p_divw, "rv64um-p-divw");
+        }
+
+        #[test]
+        fn rv64um_p_mul() {
+            aot_test!(rv64um_p_mul, "rv64um-p-mul");
+        }
+
+        #[test]
+        fn rv64um_p_mulh() {
+            aot_test!(rv64um_p_mulh, "rv64um-p-mulh");
+        }
+
+        #[test]
+        fn rv64um_p_mulhsu() {
+            aot_test!(rv64um_p_mulhsu, "rv64um-p-mulhsu");
+        }
+
+        #[test]
+        fn rv64um_p_mulhu() {
+            aot_test!(rv64um_p_mulhu, "rv64um-p-mulhu");
+        }
+
+        #[test]
+        fn rv64um_p_mulw() {
+            aot_test!(rv64um_p_mulw, "rv64um-p-mulw");
+        }
+
+        #[test]
+        fn rv64um_p_rem() {
+            aot_test!(rv64um_p_rem, "rv64um-p-rem");
+        }
+
+        #[test]
+        fn rv64um_p_remu() {
+            aot_test!(rv64um_p_remu, "rv64um-p-remu");
+        }
+
+        #[test]
+        fn rv64um_p_remuw() {
+            aot_test!(rv64um_p_remuw, "rv64um-p-remuw");
+        }
+
+        #[test]
+        fn rv64um_p_remw() {
+            aot_test!(rv64um_p_remw, "rv64um-p-remw");
+        }
+}
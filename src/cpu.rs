@@ -1,3 +1,11 @@
+use block::{Block, BlockCache, MAX_BLOCK_LENGTH};
+#[cfg(feature = "jit")]
+use block::Jit;
+use cache::{DecodeCache, DecodedInstruction};
+use csr::Csrs;
+use fusion::Fusion;
+pub use fusion::FusionCounts;
+pub use run::{ExitReason, StopHandle};
 use instruction::Instruction;
 use rv64ua::*;
 use rv64ud::*;
@@ -6,57 +14,61 @@ use rv64ui::*;
 use rv64um::*;
 use std::fmt::{Debug, Formatter};
 use std::fmt;
-use crate::memory::Memory;
+use crate::memory::{AsMemory, Memory};
 
+pub mod aot;
+mod block;
+mod cache;
+mod csr;
+mod fusion;
 pub mod instruction;
 mod rv64ui;
 mod rv64um;
 mod rv64ua;
 mod rv64uf;
 mod rv64ud;
+mod run;
 
-const CSR_CAPACITY: usize = 4096;
-const _CSR_USTATUS_ADDRESS: u16 = 0x000;
+const CSR_USTATUS_ADDRESS: u16 = 0x000;
 const CSR_FFLAGS_ADDRESS: u16 = 0x001;
 const CSR_FRM_ADDRESS: u16 = 0x002;
 const CSR_FCSR_ADDRESS: u16 = 0x003;
-const _CSR_UIE_ADDRESS: u16 = 0x004;
-const _CSR_UTVEC_ADDRESS: u16 = 0x005;
-const _CSR_USCRATCH_ADDRESS: u16 = 0x040;
-const _CSR_UEPC_ADDRESS: u16 = 0x041;
-const _CSR_UCAUSE_ADDRESS: u16 = 0x042;
-const _CSR_UTVAL_ADDRESS: u16 = 0x043;
-const _CSR_UIP_ADDRESS: u16 = 0x044;
+const CSR_UIE_ADDRESS: u16 = 0x004;
+const CSR_UTVEC_ADDRESS: u16 = 0x005;
+const CSR_USCRATCH_ADDRESS: u16 = 0x040;
+const CSR_UEPC_ADDRESS: u16 = 0x041;
+const CSR_UCAUSE_ADDRESS: u16 = 0x042;
+const CSR_UTVAL_ADDRESS: u16 = 0x043;
+const CSR_UIP_ADDRESS: u16 = 0x044;
 const CSR_SSTATUS_ADDRESS: u16 = 0x100;
-const _CSR_SEDELEG_ADDRESS: u16 = 0x102;
-const _SR_SIDELEG_ADDRESS: u16 = 0x103;
+const CSR_SEDELEG_ADDRESS: u16 = 0x102;
+const CSR_SIDELEG_ADDRESS: u16 = 0x103;
 const CSR_SIE_ADDRESS: u16 = 0x104;
-const _CSR_STVEC_ADDRESS: u16 = 0x105;
-const _CSR_SSCRATCH_ADDRESS: u16 = 0x140;
-const _CSR_SEPC_ADDRESS: u16 = 0x141;
-const _CSR_SCAUSE_ADDRESS: u16 = 0x142;
-const _CSR_STVAL_ADDRESS: u16 = 0x143;
+const CSR_STVEC_ADDRESS: u16 = 0x105;
+const CSR_SSCRATCH_ADDRESS: u16 = 0x140;
+const CSR_SEPC_ADDRESS: u16 = 0x141;
+const CSR_SCAUSE_ADDRESS: u16 = 0x142;
+const CSR_STVAL_ADDRESS: u16 = 0x143;
 const CSR_SIP_ADDRESS: u16 = 0x144;
-const _CSR_SATP_ADDRESS: u16 = 0x180;
+const CSR_SATP_ADDRESS: u16 = 0x180;
 const CSR_MSTATUS_ADDRESS: u16 = 0x300;
-const _CSR_MISA_ADDRESS: u16 = 0x301;
-const _CSR_MEDELEG_ADDRESS: u16 = 0x302;
+const CSR_MISA_ADDRESS: u16 = 0x301;
+const CSR_MEDELEG_ADDRESS: u16 = 0x302;
 const CSR_MIDELEG_ADDRESS: u16 = 0x303;
 const CSR_MIE_ADDRESS: u16 = 0x304;
-
-const _CSR_MTVEC_ADDRESS: u16 = 0x305;
-const _CSR_MSCRATCH_ADDRESS: u16 = 0x340;
+const CSR_MTVEC_ADDRESS: u16 = 0x305;
+const CSR_MSCRATCH_ADDRESS: u16 = 0x340;
 const CSR_MEPC_ADDRESS: u16 = 0x341;
-const _CSR_MCAUSE_ADDRESS: u16 = 0x342;
-const _CSR_MTVAL_ADDRESS: u16 = 0x343;
+const CSR_MCAUSE_ADDRESS: u16 = 0x342;
+const CSR_MTVAL_ADDRESS: u16 = 0x343;
 const CSR_MIP_ADDRESS: u16 = 0x344;
-const _CSR_PMPCFG0_ADDRESS: u16 = 0x3a0;
-const _CSR_PMPADDR0_ADDRESS: u16 = 0x3b0;
-const _CSR_MCYCLE_ADDRESS: u16 = 0xb00;
-const _CSR_CYCLE_ADDRESS: u16 = 0xc00;
+const CSR_PMPCFG0_ADDRESS: u16 = 0x3a0;
+const CSR_PMPADDR0_ADDRESS: u16 = 0x3b0;
+const CSR_MCYCLE_ADDRESS: u16 = 0xb00;
+const CSR_CYCLE_ADDRESS: u16 = 0xc00;
 const CSR_TIME_ADDRESS: u16 = 0xc01;
-const _CSR_INSERT_ADDRESS: u16 = 0xc02;
-const _CSR_MHARTID_ADDRESS: u16 = 0xf14;
+const CSR_INSTRET_ADDRESS: u16 = 0xc02;
+const CSR_MHARTID_ADDRESS: u16 = 0xf14;
 
 #[derive(Clone, Debug)]
 pub enum Xlen {
@@ -64,6 +76,33 @@ pub enum Xlen {
     Bit64
 }
 
+// Which data accesses a watchpoint fires on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access
+}
+
+#[derive(Clone)]
+struct Watchpoint {
+    id: usize,
+    start: usize,
+    end: usize,
+    kind: WatchKind
+}
+
+// What to do when a load, store or atomic is not naturally aligned
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MisalignedAccess {
+    // let the memory deal with it, even for atomics
+    Emulate,
+    // atomics trap as the spec requires, everything else is emulated
+    TrapAtomics,
+    // behave like strict hardware and trap on every misaligned access
+    Trap
+}
+
 #[derive(Debug)]
 pub struct Trap {
     pub trap_type: TrapType,
@@ -96,6 +135,10 @@ pub enum TrapType {
     UserExternalInterrupt,
     SupervisorExternalInterrupt,
     MachineExternalInterrupt,
+    // a data access hit the guard below the stack set with set_stack, the value is the address
+    StackOverflow { stack_pointer: u64, pc: u64 },
+    // a data watchpoint was hit, the value is the id add_watchpoint returned
+    Watchpoint,
     Stop
 }
 
@@ -192,23 +235,76 @@ pub struct Cpu {
     pub x: [i64; 32],
     pub f: [f64; 32],
     xlen: Xlen,
-    pub csr: [u64; CSR_CAPACITY],
+    csr: Csrs,
+    pub time: u64, // the time CSR, counts every instruction retired
     reservation: u64, // @TODO: Should support multiple address reservations
+    reservation_value: u64,
     is_reservation_set: bool,
-    ecall_handler: Option<Instruction>
+    ecall_handler: Option<Instruction>,
+    misaligned_access: MisalignedAccess,
+    compressed_instructions: bool,
+    watchpoints: Vec<Watchpoint>,
+    next_watchpoint_id: usize,
+    watchpoint_hit: Option<usize>,
+    stack_guard: Option<(usize, usize)>,
+    instruction_address: usize,
+    decode_cache: Option<DecodeCache>,
+    block_cache: Option<BlockCache>,
+    exit_block: bool,
+    running_block: (usize, usize), // start and end of the block tick_block is running, if any
+    fusion: bool,
+    fusion_counts: FusionCounts,
+    stop: StopHandle,
+    #[cfg(feature = "jit")]
+    jit: Option<Jit>
 }
 
 impl Debug for Cpu {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         f.debug_struct("Cpu")
             .field("pc", &self.pc)
-            .field("fcsr", &self.csr[CSR_FCSR_ADDRESS as usize])
+            .field("fcsr", &self.csr.get(CSR_FCSR_ADDRESS))
             .field("x", &self.x)
             .field("f", &self.f)
             .finish()
     }
 }
 
+// A clone carries over the architectural state and the configuration, but starts with empty caches
+// since the code they were filled from belongs to the original's memory.
+impl Clone for Cpu {
+    fn clone(&self) -> Self {
+        Cpu {
+            pc: self.pc,
+            x: self.x,
+            f: self.f,
+            xlen: self.xlen.clone(),
+            csr: self.csr.clone(),
+            time: self.time,
+            reservation: self.reservation,
+            reservation_value: self.reservation_value,
+            is_reservation_set: self.is_reservation_set,
+            ecall_handler: self.ecall_handler,
+            misaligned_access: self.misaligned_access,
+            compressed_instructions: self.compressed_instructions,
+            watchpoints: self.watchpoints.clone(),
+            next_watchpoint_id: self.next_watchpoint_id,
+            watchpoint_hit: self.watchpoint_hit,
+            stack_guard: self.stack_guard,
+            instruction_address: self.instruction_address,
+            decode_cache: self.decode_cache.as_ref().map(|_| DecodeCache::new()),
+            block_cache: self.block_cache.as_ref().map(|_| BlockCache::new()),
+            exit_block: false,
+            running_block: (0, 0),
+            fusion: self.fusion,
+            fusion_counts: self.fusion_counts,
+            stop: StopHandle::default(),
+            #[cfg(feature = "jit")]
+            jit: self.jit.as_ref().map(|jit| Jit::new(jit.threshold()))
+        }
+    }
+}
+
 impl Cpu {
     pub fn new() -> Self {
         Cpu {
@@ -216,25 +312,66 @@ impl Cpu {
             x: [0; 32],
             f: [0.0; 32],
             xlen: Xlen::Bit64,
-            csr: [0; CSR_CAPACITY],
+            csr: Csrs::new(),
+            time: 0,
             reservation: 0,
+            reservation_value: 0,
             is_reservation_set: false,
-            ecall_handler: None
+            ecall_handler: None,
+            misaligned_access: MisalignedAccess::TrapAtomics,
+            compressed_instructions: true,
+            watchpoints: Vec::new(),
+            next_watchpoint_id: 0,
+            watchpoint_hit: None,
+            stack_guard: None,
+            instruction_address: 0,
+            decode_cache: None,
+            block_cache: None,
+            exit_block: false,
+            running_block: (0, 0),
+            fusion: false,
+            fusion_counts: FusionCounts::default(),
+            stop: StopHandle::default(),
+            #[cfg(feature = "jit")]
+            jit: Some(Jit::default())
         }
     }
 
-    pub fn fetch(&mut self, memory: &dyn Memory) -> Result<u32, Trap> {
-        let result = memory.read_u32(self.pc)?;
+    // Puts the cpu back into the state new left it in, but keeps the configuration: the ecall
+    // handler, access policies, stack guard, watchpoints and the cache, fusion and jit settings.
+    // The caches are flushed as the next guest will most likely bring different code.
+    pub fn reset(&mut self) {
+        self.pc = 0;
+        self.x = [0; 32];
+        self.f = [0.0; 32];
+        self.csr.clear();
+        self.time = 0;
+        self.reservation = 0;
+        self.reservation_value = 0;
+        self.is_reservation_set = false;
+        self.watchpoint_hit = None;
+        self.instruction_address = 0;
+        self.fusion_counts = FusionCounts::default();
+        self.stop.clear();
+        self.flush_decode_cache();
+    }
+
+    pub fn fetch<M: Memory + ?Sized>(&mut self, memory: &M) -> Result<u32, Trap> {
+        let (word, length) = self.fetch_at(memory, self.pc)?;
+        self.pc = self.pc + length;
+
+        Ok(word)
+    }
+
+    // the instruction at address, already expanded if it was compressed, along with its length
+    fn fetch_at<M: Memory + ?Sized>(&self, memory: &M, address: usize) -> Result<(u32, usize), Trap> {
+        let result = memory.fetch_u32(address)?;
         match result & 3 {
-            3 => {
-                self.pc = self.pc + 4;
-                Ok(result)
+            3 => Ok((result, 4)),
+            _ if !self.compressed_instructions => {
+                Err(Trap { trap_type: TrapType::IllegalInstruction, value: (result & 0xffff) as u64 })
             },
-            _ => {
-                self.pc = self.pc + 2;
-
-                Ok(Cpu::uncompress(result & 0xffff))
-            }
+            _ => Ok((Cpu::uncompress(result & 0xffff), 2))
         }
     }
 
@@ -262,21 +399,340 @@ impl Cpu {
         self.x[Register::SP as usize] = stack_pointer as i64;
     }
 
-    pub fn tick(&mut self, memory: &mut dyn Memory) -> Result<(), Trap> {
-        let instruction_address = self.pc;
-        self.csr[CSR_TIME_ADDRESS as usize] = self.csr[CSR_TIME_ADDRESS as usize].wrapping_add(1);
+    // Puts the stack pointer at top and declares the size bytes below it as the stack. Any data
+    // access to the guard bytes below that raises StackOverflow instead of corrupting what is there.
+    pub fn set_stack(&mut self, top: usize, size: usize, guard: usize) {
+        let bottom = top.saturating_sub(size);
+        self.stack_guard = Some((bottom.saturating_sub(guard), bottom));
+        self.update_stack_pointer(top);
+    }
+
+    pub fn set_misaligned_access(&mut self, policy: MisalignedAccess) {
+        self.misaligned_access = policy;
+    }
+
+    // enables or disables the C extension, without it jump targets must be 4 byte aligned
+    pub fn set_compressed_instructions(&mut self, enabled: bool) {
+        self.compressed_instructions = enabled;
+        self.flush_decode_cache();
+    }
+
+    // Off by default. Once enabled, decoded instructions and translated blocks are cached by pc and a
+    // cached instruction is not fetched again. Guest stores keep the caches coherent by themselves,
+    // but if the host changes code or execute permissions behind the guest's back, or runs the cpu
+    // against a different memory, it has to flush them. Fusion and the jit only work with the caches.
+    pub fn set_decode_cache(&mut self, enabled: bool) {
+        self.flush_decode_cache();
+        (self.decode_cache, self.block_cache) = match enabled {
+            true => (Some(DecodeCache::new()), Some(BlockCache::new())),
+            false => (None, None)
+        };
+    }
+
+    pub fn flush_decode_cache(&mut self) {
+        if let Some(cache) = &mut self.decode_cache {
+            cache.clear();
+        }
+        if let Some(cache) = &mut self.block_cache {
+            cache.clear();
+        }
+        // none of the compiled code is reachable any more, apart from a block that is still running
+        // and will be dropped once it returns
+        #[cfg(feature = "jit")]
+        if let Some(jit) = &mut self.jit {
+            jit.reset();
+        }
+        self.exit_block = true;
+    }
+
+    // Lets tick run common pairs of instructions, like lui then addi, as a single operation. A fused
+    // pair retires both instructions in one call to tick, with the same result and traps as running
+    // them one at a time. It needs the decode cache, and tick_block does not fuse anything.
+    pub fn set_macro_op_fusion(&mut self, enabled: bool) {
+        self.flush_decode_cache();
+        self.fusion = enabled;
+    }
+
+    pub fn fusion_counts(&self) -> FusionCounts {
+        self.fusion_counts
+    }
+
+    // Blocks run by tick_block, and so by run, are compiled to native code once they have run threshold
+    // times, or never with None. Compiled blocks behave exactly like interpreted ones, only faster.
+    // tick always interprets, since it has to stop after a single instruction and compiled code
+    // runs the whole block, so callers that want the jit have to go through run or tick_block.
+    #[cfg(feature = "jit")]
+    pub fn set_jit_threshold(&mut self, threshold: Option<u32>) {
+        self.flush_decode_cache();
+        self.jit = threshold.map(Jit::new);
+    }
+
+    #[inline]
+    fn invalidate_code(&mut self, address: usize, size: usize) {
+        if let Some(cache) = &mut self.decode_cache {
+            cache.invalidate(address, size);
+        }
+        if let Some(cache) = &mut self.block_cache {
+            self.exit_block |= cache.invalidate(address, size);
+        }
+        // the running block may have just been overwritten, cached or not
+        let (start, end) = self.running_block;
+        self.exit_block |= address < end && address.saturating_add(size) > start;
+    }
+
+    // Watches start..start + length for the given kind of access. The instruction that touches it
+    // still completes, then tick returns a Watchpoint trap carrying the returned id.
+    pub fn add_watchpoint(&mut self, start: usize, length: usize, kind: WatchKind) -> usize {
+        let id = self.next_watchpoint_id;
+        self.next_watchpoint_id += 1;
+        self.watchpoints.push(Watchpoint { id, start, end: start.saturating_add(length), kind });
+
+        id
+    }
 
-        let word = self.fetch(memory)?;
-        if let Some(instruction) = Cpu::decode(word) {
-            let result = (instruction.operation)(self, memory, word, instruction_address);
-            self.x[0] = 0; // make sure x0 is still zero!
+    pub fn remove_watchpoint(&mut self, id: usize) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|watchpoint| watchpoint.id != id);
 
-            result
+        count != self.watchpoints.len()
+    }
+
+    #[inline]
+    fn check_watchpoints(&mut self, address: usize, size: usize, read: bool, write: bool) {
+        if self.watchpoint_hit.is_some() {
+            return;
+        }
+
+        let end = address.saturating_add(size);
+        self.watchpoint_hit = self.watchpoints.iter()
+            .find(|watchpoint| {
+                let kind = match watchpoint.kind {
+                    WatchKind::Read => read,
+                    WatchKind::Write => write,
+                    WatchKind::Access => true
+                };
+                kind && address < watchpoint.end && watchpoint.start < end
+            })
+            .map(|watchpoint| watchpoint.id);
+    }
+
+    #[inline]
+    fn check_stack_guard(&self, address: usize, size: usize) -> Result<(), Trap> {
+        match self.stack_guard {
+            Some((start, end)) if address < end && start < address.saturating_add(size) => Err(Trap {
+                trap_type: TrapType::StackOverflow {
+                    stack_pointer: self.x[Register::SP as usize] as u64,
+                    pc: self.instruction_address as u64
+                },
+                value: address as u64
+            }),
+            _ => Ok(())
+        }
+    }
+
+    #[inline]
+    fn check_alignment(&self, address: usize, size: usize, atomic: bool, trap_type: TrapType) -> Result<(), Trap> {
+        let enforce = match self.misaligned_access {
+            MisalignedAccess::Emulate => false,
+            MisalignedAccess::TrapAtomics => atomic,
+            MisalignedAccess::Trap => true
+        };
+
+        if enforce && address & (size - 1) != 0 {
+            Err(Trap { trap_type, value: address as u64 })
         } else {
-            Err(Trap { trap_type: TrapType::IllegalInstruction, value: word as u64 })
+            Ok(())
         }
     }
 
+    // every data access an instruction makes is checked by one of these before it reaches memory
+    #[inline]
+    pub(crate) fn check_load(&mut self, address: usize, size: usize) -> Result<(), Trap> {
+        self.check_alignment(address, size, false, TrapType::LoadAddressMisaligned)?;
+        self.check_stack_guard(address, size)?;
+        self.check_watchpoints(address, size, true, false);
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) fn check_store(&mut self, address: usize, size: usize) -> Result<(), Trap> {
+        self.check_alignment(address, size, false, TrapType::StoreAddressMisaligned)?;
+        self.check_stack_guard(address, size)?;
+        self.check_watchpoints(address, size, false, true);
+        self.invalidate_code(address, size);
+        Ok(())
+    }
+
+    pub(crate) fn check_lr(&mut self, address: usize, size: usize) -> Result<(), Trap> {
+        self.check_alignment(address, size, true, TrapType::LoadAddressMisaligned)?;
+        self.check_stack_guard(address, size)?;
+        self.check_watchpoints(address, size, true, false);
+        Ok(())
+    }
+
+    // used by SC as well as the AMOs
+    pub(crate) fn check_amo(&mut self, address: usize, size: usize) -> Result<(), Trap> {
+        self.check_alignment(address, size, true, TrapType::StoreAddressMisaligned)?;
+        self.check_stack_guard(address, size)?;
+        self.check_watchpoints(address, size, true, true);
+        self.invalidate_code(address, size);
+        Ok(())
+    }
+
+    // every control transfer goes through here so misaligned targets are caught before anything changes
+    #[inline]
+    pub(crate) fn jump(&mut self, target: usize) -> Result<(), Trap> {
+        if !self.compressed_instructions && target & 3 != 0 {
+            return Err(Trap { trap_type: TrapType::InstructionAddressMisaligned, value: target as u64 });
+        }
+
+        self.pc = target;
+        Ok(())
+    }
+
+    // Takes a concrete Memory type as well as dyn Memory. A concrete type gets its own copy of the
+    // fetch path here and of the whole block loop in tick_block, with the memory accesses inlined.
+    // tick never runs compiled code, see set_jit_threshold.
+    pub fn tick<M: AsMemory + ?Sized>(&mut self, memory: &mut M) -> Result<(), Trap> {
+        self.tick_fused(memory, true)
+    }
+
+    // tick, but with fuse false only the first instruction of a fused pair runs
+    #[inline]
+    fn tick_fused<M: AsMemory + ?Sized>(&mut self, memory: &mut M, fuse: bool) -> Result<(), Trap> {
+        let instruction_address = self.pc;
+        self.instruction_address = instruction_address;
+        self.time = self.time.wrapping_add(1);
+
+        let decoded = match self.decode_cache.as_ref().and_then(|cache| cache.lookup(instruction_address)) {
+            Some(decoded) => {
+                self.pc = instruction_address + decoded.length;
+                decoded
+            },
+            None => {
+                let word = self.fetch(memory)?;
+                let mut decoded = match Cpu::decode(word) {
+                    Some(instruction) => DecodedInstruction { pc: instruction_address, word, length: self.pc - instruction_address, instruction, fusion: None },
+                    None => return Err(Trap { trap_type: TrapType::IllegalInstruction, value: word as u64 })
+                };
+                if self.fusion && self.decode_cache.is_some() {
+                    if let Some((fusion, end)) = self.fuse(memory, &decoded) {
+                        decoded.fusion = Some((fusion, self.pc));
+                        decoded.length = end - instruction_address;
+                        self.pc = end;
+                    }
+                }
+                if let Some(cache) = &mut self.decode_cache {
+                    cache.insert(decoded);
+                }
+                decoded
+            }
+        };
+
+        let result = match (decoded.fusion, fuse) {
+            (Some((fusion, second)), true) => fusion.execute(self, memory.as_memory(), second),
+            (Some((_, second)), false) => {
+                self.pc = second;
+                (decoded.instruction.operation)(self, memory.as_memory(), decoded.word, instruction_address)
+            },
+            (None, _) => (decoded.instruction.operation)(self, memory.as_memory(), decoded.word, instruction_address)
+        };
+        self.x[0] = 0; // make sure x0 is still zero!
+
+        // a fault in the instruction itself takes priority over any watchpoint it hit
+        match (result, self.watchpoint_hit.take()) {
+            (Ok(()), Some(id)) => Err(Trap { trap_type: TrapType::Watchpoint, value: id as u64 }),
+            (result, _) => result
+        }
+    }
+
+    // the pair starting with first if the instruction after it fuses with it, along with where the pair ends
+    fn fuse<M: Memory + ?Sized>(&self, memory: &M, first: &DecodedInstruction) -> Option<(Fusion, usize)> {
+        let second = first.pc + first.length;
+        let (word, length) = self.fetch_at(memory, second).ok()?;
+        let fusion = Fusion::detect(self, first.instruction, first.word, Cpu::decode(word)?, word, first.pc)?;
+        Some((fusion, second + length))
+    }
+
+    // Runs the guest up to the end of the current basic block and returns the number of instructions
+    // retired. Blocks are translated once into pre-decoded operations, so this is much faster than
+    // calling tick in a loop, but time only advances a block at a time. Traps and watchpoints are
+    // reported exactly as tick would report them and leave the pc in the same place.
+    pub fn tick_block<M: AsMemory + ?Sized>(&mut self, memory: &mut M) -> Result<usize, Trap> {
+        #[cfg_attr(not(feature = "jit"), allow(unused_mut))]
+        let mut block = match self.block_cache.as_mut().and_then(|cache| cache.take(self.pc)) {
+            Some(block) => block,
+            None => {
+                let block = Block::translate(self, memory, self.pc);
+                if block.is_empty() {
+                    // let tick report whatever is wrong with the first instruction
+                    return self.tick(memory).map(|_| 1);
+                }
+
+                if let Some(cache) = &mut self.block_cache {
+                    cache.register(&block);
+                }
+                Box::new(block)
+            }
+        };
+
+        #[cfg(feature = "jit")]
+        if let Some(jit) = &mut self.jit {
+            jit.prepare(&mut block, self.compressed_instructions);
+        }
+
+        let result = block.execute(self, memory);
+        // a block that wrote to code or ran FENCE.I is translated again next time
+        if let Some(cache) = &mut self.block_cache {
+            if !self.exit_block {
+                cache.restore(block);
+            }
+        }
+
+        result
+    }
+
+    // Runs the guest until it exits, traps or has retired budget instructions, and returns why it
+    // stopped along with the number of instructions retired, which never goes over the budget. Blocks
+    // are only used while they cannot overshoot it, and a fused pair is split when one instruction
+    // is left. An instruction that faults is not retired, the pc and time are put back to it.
+    pub fn run<M: AsMemory + ?Sized>(&mut self, memory: &mut M, budget: u64) -> (ExitReason, u64) {
+        let start = self.time;
+        let reason = loop {
+            if self.stop.take() {
+                break ExitReason::Stopped;
+            }
+            let left = budget.saturating_sub(self.time.wrapping_sub(start));
+            if left == 0 {
+                break ExitReason::BudgetExhausted;
+            }
+
+            let result = match self.block_cache.is_some() && left >= MAX_BLOCK_LENGTH as u64 {
+                true => self.tick_block(memory).map(|_| ()),
+                false => self.tick_fused(memory, left > 1)
+            };
+            if let Err(trap) = result {
+                break match trap.trap_type {
+                    TrapType::Stop => ExitReason::Exit(trap.value),
+                    TrapType::Breakpoint => ExitReason::Breakpoint { pc: self.instruction_address },
+                    // a watchpoint fires once the access is done, so that instruction did complete
+                    TrapType::Watchpoint => ExitReason::Trap { trap, pc: self.instruction_address },
+                    _ => {
+                        self.pc = self.instruction_address;
+                        self.time = self.time.wrapping_sub(1);
+                        ExitReason::Trap { trap, pc: self.pc }
+                    }
+                };
+            }
+        };
+
+        (reason, self.time.wrapping_sub(start))
+    }
+
+    pub fn stop_handle(&self) -> StopHandle {
+        self.stop.clone()
+    }
+
     pub fn get_f32(&mut self, reg: usize) -> f32 {
         // only consider the bottom 32 bits of the register
         f32::from_bits(self.f[reg].to_bits() as u32)
@@ -1173,43 +1629,41 @@ impl Cpu {
         match address {
             // @TODO: Mask should consider of 32-bit mode
             CSR_FFLAGS_ADDRESS => self.read_fflags(),
-            CSR_FRM_ADDRESS => (self.csr[CSR_FCSR_ADDRESS as usize] >> 5) & 0x7,
-            CSR_SSTATUS_ADDRESS => self.csr[CSR_MSTATUS_ADDRESS as usize] & 0x80000003000de162,
-            CSR_SIE_ADDRESS => self.csr[CSR_MIE_ADDRESS as usize] & 0x222,
-            CSR_SIP_ADDRESS => self.csr[CSR_MIP_ADDRESS as usize] & 0x222,
-            CSR_FCSR_ADDRESS => self.csr[CSR_FCSR_ADDRESS as usize] & 0xff,
-            _ => self.csr[address as usize]
+            CSR_FRM_ADDRESS => (self.csr.get(CSR_FCSR_ADDRESS) >> 5) & 0x7,
+            CSR_SSTATUS_ADDRESS => self.csr.get(CSR_MSTATUS_ADDRESS) & 0x80000003000de162,
+            CSR_SIE_ADDRESS => self.csr.get(CSR_MIE_ADDRESS) & 0x222,
+            CSR_SIP_ADDRESS => self.csr.get(CSR_MIP_ADDRESS) & 0x222,
+            CSR_FCSR_ADDRESS => self.csr.get(CSR_FCSR_ADDRESS) & 0xff,
+            CSR_TIME_ADDRESS => self.time,
+            _ => self.csr.get(address)
         }
     }
 
     pub fn write_csr(&mut self, address: u16, value: u64) {
         match address {
             CSR_FFLAGS_ADDRESS => self.write_fflags(value),
-            CSR_FRM_ADDRESS => {
-                self.csr[CSR_FCSR_ADDRESS as usize] &= !0xe0;
-                self.csr[CSR_FCSR_ADDRESS as usize] |= (value << 5) & 0xe0;
-            },
-            CSR_SSTATUS_ADDRESS => {
-                self.csr[CSR_MSTATUS_ADDRESS as usize] &= !0x80000003000de162;
-                self.csr[CSR_MSTATUS_ADDRESS as usize] |= value & 0x80000003000de162;
-            },
-            CSR_SIE_ADDRESS => {
-                self.csr[CSR_MIE_ADDRESS as usize] &= !0x222;
-                self.csr[CSR_MIE_ADDRESS as usize] |= value & 0x222;
-            },
-            CSR_SIP_ADDRESS => {
-                self.csr[CSR_MIP_ADDRESS as usize] &= !0x222;
-                self.csr[CSR_MIP_ADDRESS as usize] |= value & 0x222;
-            },
+            CSR_FRM_ADDRESS => self.write_masked(CSR_FCSR_ADDRESS, 0xe0, value << 5),
+            CSR_SSTATUS_ADDRESS => self.write_masked(CSR_MSTATUS_ADDRESS, 0x80000003000de162, value),
+            CSR_SIE_ADDRESS => self.write_masked(CSR_MIE_ADDRESS, 0x222, value),
+            CSR_SIP_ADDRESS => self.write_masked(CSR_MIP_ADDRESS, 0x222, value),
             CSR_MIDELEG_ADDRESS => {
-                self.csr[address as usize] = value & 0x666; // from qemu
+                self.csr.set(address, value & 0x666); // from qemu
+            },
+            CSR_TIME_ADDRESS => {
+                self.time = value;
             },
             _ => {
-                self.csr[address as usize] = value;
+                self.csr.set(address, value);
             }
         };
     }
 
+    // the bits in mask come from value, the rest keep what the CSR already held
+    fn write_masked(&mut self, address: u16, mask: u64, value: u64) {
+        let kept = self.csr.get(address) & !mask;
+        self.csr.set(address, kept | value & mask);
+    }
+
     pub fn set_fcsr_nx(&mut self) {
         let flags = self.read_fflags();
         self.write_fflags(flags | 1);
@@ -1255,7 +1709,7 @@ impl Cpu {
             _ => 0
         };
 
-        let flags = self.csr[CSR_FCSR_ADDRESS as usize] & !0x1f;
+        let flags = self.csr.get(CSR_FCSR_ADDRESS) & !0x1f;
 
         // println!("read_fflags: {:#x} nx={:?}", flags, inexact);
         flags | inexact | underflow | overflow | div_by_zero | invalid_op
@@ -1263,7 +1717,7 @@ impl Cpu {
 
     #[cfg(not(target_arch = "x86_64"))]
     fn read_fflags(&self) -> u64 {
-        self.csr[CSR_FCSR_ADDRESS as usize] & 0x1f
+        self.csr.get(CSR_FCSR_ADDRESS) & 0x1f
     }
 
     #[cfg(target_arch = "x86_64")]
@@ -1294,8 +1748,8 @@ impl Cpu {
 
     #[cfg(not(target_arch = "x86_64"))]
     fn write_fflags(&mut self, value: u64) {
-        self.csr[CSR_FCSR_ADDRESS as usize] &= !0x1f;
-        self.csr[CSR_FCSR_ADDRESS as usize] |= value & 0x1f;
+        let fcsr = self.csr.get(CSR_FCSR_ADDRESS) & !0x1f;
+        self.csr.set(CSR_FCSR_ADDRESS, fcsr | value & 0x1f);
     }
 }
 
@@ -1331,6 +1785,7 @@ const MRET: Instruction = Instruction {
 #[cfg(test)]
 mod test_cpu {
     use super::*;
+    use crate::memory::AtomicOp;
 
     #[test]
     fn babys_first_instruction() {
@@ -1368,6 +1823,618 @@ mod test_cpu {
         assert_eq!(4, pc2 - pc1);
     }
 
+    #[test]
+    fn fetch_outside_memory_faults() {
+        let mut cpu = Cpu::new();
+        let mut memory: Vec<u8> = vec![0x05, 0x05];
+        cpu.update_pc(0x100);
+
+        let e = cpu.tick(&mut memory).unwrap_err();
+        assert!(matches!(e.trap_type, TrapType::InstructionAccessFault));
+        assert_eq!(e.value, 0x100);
+    }
+
+    #[test]
+    fn misaligned_loads_and_stores_follow_the_policy() {
+        let mut memory: Vec<u8> = vec![
+            0x83, 0x32, 0x90, 0x00, // ld t0, 9(zero)
+            0x23, 0x25, 0x50, 0x00, // sw t0, 10(zero)
+            0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07,
+            0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f
+        ];
+
+        let mut cpu = Cpu::new();
+        cpu.tick(&mut memory).expect("cpu failure");
+        assert_eq!(cpu.x[5], 0x0807060504030201);
+        cpu.tick(&mut memory).expect("cpu failure");
+
+        cpu.set_misaligned_access(MisalignedAccess::Trap);
+        cpu.update_pc(0);
+        let e = cpu.tick(&mut memory).unwrap_err();
+        assert!(matches!(e.trap_type, TrapType::LoadAddressMisaligned));
+        assert_eq!(e.value, 9);
+        let e = cpu.tick(&mut memory).unwrap_err();
+        assert!(matches!(e.trap_type, TrapType::StoreAddressMisaligned));
+        assert_eq!(e.value, 10);
+    }
+
+    #[test]
+    fn misaligned_atomics_trap_by_default() {
+        let mut memory: Vec<u8> = vec![
+            0xaf, 0x22, 0x65, 0x00, // amoadd.w t0, t1, 0(a0)
+            0xaf, 0x32, 0x05, 0x10, // lr.d t0, 0(a0)
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
+        ];
+
+        let mut cpu = Cpu::new();
+        cpu.x[10] = 9;
+        let e = cpu.tick(&mut memory).unwrap_err();
+        assert!(matches!(e.trap_type, TrapType::StoreAddressMisaligned));
+        let e = cpu.tick(&mut memory).unwrap_err();
+        assert!(matches!(e.trap_type, TrapType::LoadAddressMisaligned));
+        assert_eq!(e.value, 9);
+
+        cpu.set_misaligned_access(MisalignedAccess::Emulate);
+        cpu.update_pc(0);
+        cpu.x[6] = 0x100;
+        cpu.tick(&mut memory).expect("cpu failure");
+        assert_eq!(memory[10], 0x01);
+    }
+
+    #[test]
+    fn store_conditional_fails_if_memory_changed() {
+        let mut memory: Vec<u8> = vec![
+            0xaf, 0x22, 0x05, 0x10, // lr.w t0, 0(a0)
+            0x2f, 0x23, 0x75, 0x18, // sc.w t1, t2, 0(a0)
+            0xaf, 0x22, 0x05, 0x10, // lr.w t0, 0(a0)
+            0x2f, 0x23, 0x75, 0x18, // sc.w t1, t2, 0(a0)
+            0xfe, 0xff, 0xff, 0xff
+        ];
+
+        let mut cpu = Cpu::new();
+        cpu.x[10] = 16;
+        cpu.x[7] = 5;
+        cpu.tick(&mut memory).expect("cpu failure");
+        assert_eq!(cpu.x[5], -2);
+
+        // something else stores to the reserved word behind the cpu's back
+        memory.write_u32(16, 3).unwrap();
+        cpu.tick(&mut memory).expect("cpu failure");
+        assert_eq!(cpu.x[6], 1);
+        assert_eq!(memory.read_u32(16).unwrap(), 3);
+
+        cpu.tick(&mut memory).expect("cpu failure");
+        cpu.tick(&mut memory).expect("cpu failure");
+        assert_eq!(cpu.x[6], 0);
+        assert_eq!(memory.read_u32(16).unwrap(), 5);
+    }
+
+    #[test]
+    fn atomic_ops_default_to_read_then_write() {
+        let mut memory: Vec<u8> = vec![0; 8];
+
+        assert_eq!(memory.fetch_op_u64(0, AtomicOp::Add, 3).unwrap(), 0);
+        assert_eq!(memory.fetch_op_u32(0, AtomicOp::Max, -1i32 as u32).unwrap(), 3);
+        assert_eq!(memory.fetch_op_u32(0, AtomicOp::MaxU, -1i32 as u32).unwrap(), 3);
+        assert_eq!(memory.compare_exchange_u64(0, 3, 7).unwrap(), 0xffff_ffff);
+        assert_eq!(memory.compare_exchange_u64(0, 0xffff_ffff, 7).unwrap(), 0xffff_ffff);
+        assert_eq!(memory.read_u64(0).unwrap(), 7);
+    }
+
+    #[test]
+    fn watchpoints_fire_after_the_access() {
+        let mut memory: Vec<u8> = vec![
+            0x83, 0x32, 0x05, 0x00, // ld t0, 0(a0)
+            0x23, 0x34, 0x55, 0x00, // sd t0, 8(a0)
+            0x03, 0x23, 0xc5, 0x00, // lw t1, 12(a0)
+            0xaf, 0xa3, 0x55, 0x00, // amoadd.w t2, t0, 0(a1)
+            0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
+        ];
+
+        let mut cpu = Cpu::new();
+        cpu.x[10] = 16;
+        cpu.x[11] = 24;
+        let stores = cpu.add_watchpoint(27, 1, WatchKind::Write);
+        let reads = cpu.add_watchpoint(16, 4, WatchKind::Read);
+
+        let e = cpu.tick(&mut memory).unwrap_err();
+        assert!(matches!(e.trap_type, TrapType::Watchpoint));
+        assert_eq!(e.value, reads as u64);
+        assert_eq!(cpu.x[5], 7);
+        assert_eq!(cpu.get_pc(), 4);
+
+        let e = cpu.tick(&mut memory).unwrap_err();
+        assert!(matches!(e.trap_type, TrapType::Watchpoint));
+        assert_eq!(e.value, stores as u64);
+        assert_eq!(memory[24], 7);
+
+        // reads of a write watchpoint go unnoticed
+        cpu.tick(&mut memory).expect("cpu failure");
+        assert!(cpu.remove_watchpoint(stores));
+        assert!(!cpu.remove_watchpoint(stores));
+        cpu.tick(&mut memory).expect("cpu failure");
+        assert_eq!(memory[24], 14);
+    }
+
+    #[test]
+    fn runaway_recursion_hits_the_stack_guard() {
+        let mut memory: Vec<u8> = vec![
+            0x13, 0x01, 0x01, 0xff, // addi sp, sp, -16
+            0x23, 0x34, 0x11, 0x00, // sd ra, 8(sp)
+            0xef, 0xf0, 0x9f, 0xff // jal ra, -8
+        ];
+        memory.resize(0x2000, 0);
+
+        let mut cpu = Cpu::new();
+        cpu.set_stack(0x2000, 0x800, 0x100);
+        assert_eq!(cpu.get_register(Register::SP), 0x2000);
+
+        let e = loop {
+            if let Err(e) = cpu.tick(&mut memory) {
+                break e;
+            }
+        };
+        match e.trap_type {
+            TrapType::StackOverflow { stack_pointer, pc } => {
+                assert_eq!(stack_pointer, 0x17f0);
+                assert_eq!(pc, 4);
+            },
+            trap_type => panic!("unexpected trap {:?}", trap_type)
+        }
+        assert_eq!(e.value, 0x17f8);
+        assert_eq!(memory.read_u64(0x1808).unwrap(), 12);
+        assert!(memory[0x1700..0x1800].iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn guest_stores_invalidate_cached_code() {
+        let mut memory: Vec<u8> = vec![
+            0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+            0x23, 0x20, 0x60, 0x00, // sw t1, 0(zero)
+            0x6f, 0xf0, 0x9f, 0xff // jal zero, -8
+        ];
+
+        let mut cpu = Cpu::new();
+        cpu.set_decode_cache(true);
+        cpu.x[6] = 0x01050513; // addi a0, a0, 16
+        for _ in 0..4 {
+            cpu.tick(&mut memory).expect("cpu failure");
+        }
+        assert_eq!(cpu.x[10], 17);
+    }
+
+    #[test]
+    fn fence_i_picks_up_host_code_changes() {
+        let mut memory: Vec<u8> = vec![
+            0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+            0x0f, 0x10, 0x00, 0x00, // fence.i
+            0x6f, 0xf0, 0x9f, 0xff // jal zero, -8
+        ];
+
+        let mut cpu = Cpu::new();
+        cpu.set_decode_cache(true);
+        cpu.tick(&mut memory).expect("cpu failure");
+
+        // the host writes straight to memory, which the cpu cannot see
+        memory.write_u32(0, 0x01050513).unwrap(); // addi a0, a0, 16
+        cpu.update_pc(0);
+        cpu.tick(&mut memory).expect("cpu failure");
+        assert_eq!(cpu.x[10], 2);
+
+        for _ in 0..3 {
+            cpu.tick(&mut memory).expect("cpu failure");
+        }
+        assert_eq!(cpu.x[10], 18);
+
+        cpu.set_decode_cache(false);
+        memory.write_u32(0, 0x00150513).unwrap(); // addi a0, a0, 1
+        cpu.update_pc(0);
+        cpu.tick(&mut memory).expect("cpu failure");
+        assert_eq!(cpu.x[10], 19);
+    }
+
+    #[test]
+    fn blocks_stop_after_rewriting_their_own_code() {
+        let code: Vec<u8> = vec![
+            0x23, 0x24, 0x60, 0x00, // sw t1, 8(zero)
+            0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+            0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+            0x73, 0x00, 0x00, 0x00 // ecall
+        ];
+
+        for cached in [false, true] {
+            let mut memory = code.clone();
+            let mut cpu = Cpu::new();
+            cpu.set_decode_cache(cached);
+            cpu.x[6] = 0x01050513; // addi a0, a0, 16
+            assert_eq!(cpu.tick_block(&mut memory).expect("cpu failure"), 1);
+            assert_eq!(cpu.get_pc(), 4);
+            assert_eq!(cpu.tick_block(&mut memory).expect("cpu failure"), 3);
+            assert_eq!(cpu.x[10], 17);
+            assert_eq!(cpu.time, 4);
+        }
+    }
+
+    #[test]
+    fn blocks_cut_short_leave_the_pc_at_their_end() {
+        let mut memory: Vec<u8> = vec![
+            0x23, 0x30, 0x00, 0x10, // sd zero, 256(zero)
+            0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+            0x13, 0x05, 0x15, 0x00 // addi a0, a0, 1
+        ];
+        memory.resize(0x200, 0);
+
+        // the block stops before the illegal instruction after the second addi
+        let mut cpu = Cpu::new();
+        assert_eq!(cpu.tick_block(&mut memory).expect("cpu failure"), 3);
+        assert_eq!(cpu.get_pc(), 12);
+        assert_eq!(cpu.x[10], 2);
+    }
+
+    #[test]
+    fn dyn_memory_runs_like_a_concrete_type() {
+        let code: Vec<u8> = vec![
+            0x13, 0x05, 0x50, 0x00, // addi a0, zero, 5
+            0x23, 0x30, 0xa0, 0x10, // sd a0, 256(zero)
+            0x83, 0x35, 0x00, 0x10, // ld a1, 256(zero)
+            0x03, 0x36, 0x00, 0x20 // ld a2, 512(zero)
+        ];
+
+        for blocks in [false, true] {
+            let mut concrete = code.clone();
+            concrete.resize(0x200, 0);
+            let mut boxed: Box<dyn Memory> = Box::new(concrete.clone());
+
+            let mut cpus = [Cpu::new(), Cpu::new()];
+            let errors = [
+                run_until_trap(&mut cpus[0], &mut concrete, blocks),
+                run_until_trap(&mut cpus[1], boxed.as_mut(), blocks)
+            ];
+            for (cpu, e) in cpus.iter().zip(errors) {
+                assert!(matches!(e, Trap { trap_type: TrapType::LoadAccessFault, value: 0x200 }));
+                assert_eq!(cpu.x[11], 5);
+                assert_eq!(cpu.get_pc(), 16);
+            }
+        }
+    }
+
+    fn run_until_trap<M: AsMemory + ?Sized>(cpu: &mut Cpu, memory: &mut M, blocks: bool) -> Trap {
+        loop {
+            let result = match blocks {
+                true => cpu.tick_block(memory).map(|_| ()),
+                false => cpu.tick(memory)
+            };
+            if let Err(e) = result {
+                return e;
+            }
+        }
+    }
+
+    #[test]
+    fn blocks_trap_exactly_like_tick() {
+        let code: Vec<u8> = vec![
+            0x13, 0x05, 0x50, 0x00, // addi a0, zero, 5
+            0xb7, 0x05, 0x01, 0x00, // lui a1, 16
+            0x23, 0x30, 0xa0, 0x10, // sd a0, 256(zero)
+            0x03, 0xb6, 0x05, 0x00, // ld a2, 0(a1)
+            0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+            0x73, 0x00, 0x00, 0x00 // ecall
+        ];
+
+        for blocks in [false, true] {
+            let mut memory = code.clone();
+            memory.resize(0x200, 0);
+            let mut cpu = Cpu::new();
+            cpu.set_decode_cache(true);
+            let id = cpu.add_watchpoint(0x100, 8, WatchKind::Write);
+
+            let mut traps = Vec::new();
+            while traps.len() < 2 {
+                let result = match blocks {
+                    true => cpu.tick_block(&mut memory).map(|_| ()),
+                    false => cpu.tick(&mut memory)
+                };
+                if let Err(e) = result {
+                    traps.push((e, cpu.get_pc(), cpu.time));
+                }
+            }
+
+            assert!(matches!(traps[0], (Trap { trap_type: TrapType::Watchpoint, value }, 12, 3) if value == id as u64));
+            assert!(matches!(traps[1], (Trap { trap_type: TrapType::LoadAccessFault, value: 0x10000 }, 16, 4)));
+            assert_eq!(cpu.x[10], 5);
+        }
+    }
+
+    #[test]
+    fn fused_pairs_run_like_separate_instructions() {
+        let code: Vec<u8> = vec![
+            0x37, 0x55, 0x34, 0x12, // lui a0, 0x12345
+            0x13, 0x05, 0xf5, 0xff, // addi a0, a0, -1
+            0x93, 0x15, 0x05, 0x03, // slli a1, a0, 48
+            0x93, 0xd5, 0x05, 0x03, // srli a1, a1, 48
+            0x97, 0x02, 0x00, 0x00, // auipc t0, 0
+            0xe7, 0x80, 0xc2, 0x00, // jalr ra, 12(t0)
+            0x73, 0x00, 0x00, 0x00, // ecall
+            0x73, 0x00, 0x00, 0x00, // ecall
+            0x97, 0x16, 0x00, 0x00, // auipc a3, 1
+            0x03, 0xb7, 0x06, 0x00 // ld a4, 0(a3)
+        ];
+
+        let mut states = Vec::new();
+        for fusion in [false, true] {
+            let mut memory = code.clone();
+            let mut cpu = Cpu::new();
+            cpu.set_decode_cache(true);
+            cpu.set_macro_op_fusion(fusion);
+            let e = run_until_trap(&mut cpu, &mut memory, false);
+            // the fused load still traps as the ld, after the auipc has retired
+            assert!(matches!(e, Trap { trap_type: TrapType::LoadAccessFault, value: 0x1020 }));
+            states.push((cpu.get_pc(), cpu.time, cpu.x));
+
+            let counts = cpu.fusion_counts();
+            match fusion {
+                true => assert_eq!(counts, FusionCounts { lui_addi: 1, auipc_jalr: 1, auipc_ld: 1, slli_srli: 1 }),
+                false => assert_eq!(counts, FusionCounts::default())
+            }
+        }
+
+        assert_eq!(states[0].0, 40);
+        assert_eq!(states[0].1, 9);
+        assert_eq!(states[0].2[10], 0x12344fff);
+        assert_eq!(states[0].2[11], 0x4fff);
+        assert_eq!(states[0].2[1], 24);
+        assert_eq!(states[0], states[1]);
+    }
+
+    #[cfg(feature = "jit")]
+    #[test]
+    fn compiled_blocks_match_tick() {
+        let code: Vec<u8> = vec![
+            0x13, 0x05, 0x00, 0x00, // addi a0, zero, 0
+            0x93, 0x05, 0x40, 0x06, // addi a1, zero, 100
+            0x33, 0x86, 0xb5, 0x02, // mul a2, a1, a1
+            0x33, 0x05, 0xc5, 0x00, // add a0, a0, a2
+            0x93, 0x85, 0xf5, 0xff, // addi a1, a1, -1
+            0xe3, 0x9a, 0x05, 0xfe, // bne a1, zero, -12
+            0x73, 0x00, 0x00, 0x00 // ecall
+        ];
+
+        let mut states = Vec::new();
+        for jit in [None, Some(2)] {
+            let mut memory = code.clone();
+            let mut cpu = Cpu::new();
+            cpu.set_decode_cache(true);
+            cpu.set_jit_threshold(jit);
+            let e = loop {
+                let result = match jit {
+                    Some(_) => cpu.tick_block(&mut memory).map(|_| ()),
+                    None => cpu.tick(&mut memory)
+                };
+                if let Err(e) = result {
+                    break e;
+                }
+            };
+            // ecall without a handler does nothing, so both run off the end of memory
+            assert!(matches!(e.trap_type, TrapType::InstructionAccessFault));
+            states.push((e.value, cpu.get_pc(), cpu.time, cpu.x));
+        }
+
+        assert_eq!(states[0].3[10], 338350);
+        assert_eq!(states[0], states[1]);
+    }
+
+    #[cfg(feature = "jit")]
+    #[test]
+    fn compiled_blocks_stop_early_like_interpreted_ones() {
+        let rewrite: Vec<u8> = vec![
+            0x23, 0x26, 0x60, 0x00, // sw t1, 12(zero)
+            0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+            0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+            0x13, 0x05, 0x45, 0x06, // addi a0, a0, 100
+            0x73, 0x00, 0x00, 0x00 // ecall
+        ];
+        let watch: Vec<u8> = vec![
+            0x13, 0x05, 0x50, 0x00, // addi a0, zero, 5
+            0x23, 0x30, 0xa0, 0x10, // sd a0, 256(zero)
+            0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+            0x73, 0x00, 0x00, 0x00 // ecall
+        ];
+
+        let mut states = Vec::new();
+        for jit in [None, Some(0)] {
+            let mut cpu = Cpu::new();
+            cpu.set_decode_cache(true);
+            cpu.set_jit_threshold(jit);
+            cpu.set_ecall_handler(Some(Instruction {
+                name: "ECALL",
+                operation: |cpu, _memory, _word, _address| {
+                    Err(Trap { trap_type: TrapType::Stop, value: cpu.x[10] as u64 })
+                }
+            }));
+
+            // the store replaces the addi a0, a0, 100 further down the same block
+            let mut memory = rewrite.clone();
+            cpu.x[6] = 0x01000593; // addi a1, zero, 16
+            assert!(matches!(cpu.run(&mut memory, 1000), (ExitReason::Exit(2), 5)));
+            assert_eq!(cpu.x[11], 16);
+            let rewritten = (cpu.get_pc(), cpu.time, cpu.x);
+
+            cpu.reset();
+            let mut memory = watch.clone();
+            memory.resize(0x200, 0);
+            cpu.add_watchpoint(0x100, 8, WatchKind::Write);
+            assert!(matches!(cpu.run(&mut memory, 1000), (ExitReason::Trap { trap: Trap { trap_type: TrapType::Watchpoint, .. }, pc: 4 }, 2)));
+            assert_eq!(cpu.get_pc(), 8);
+            assert!(matches!(cpu.run(&mut memory, 1000), (ExitReason::Exit(6), 2)));
+            states.push((rewritten, cpu.get_pc(), cpu.time, cpu.x));
+        }
+
+        assert_eq!(states[0], states[1]);
+    }
+
+    #[cfg(feature = "jit")]
+    #[test]
+    fn ecall_handlers_can_replace_the_jit_under_compiled_code() {
+        let mut memory: Vec<u8> = vec![
+            0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+            0x73, 0x00, 0x00, 0x00, // ecall
+            0x6f, 0xf0, 0x9f, 0xff // jal zero, -8
+        ];
+
+        let mut cpu = Cpu::new();
+        cpu.set_decode_cache(true);
+        cpu.set_jit_threshold(Some(2));
+        cpu.set_ecall_handler(Some(Instruction {
+            name: "ECALL",
+            operation: |cpu, _memory, _word, _address| {
+                match cpu.x[10] {
+                    100 => Err(Trap { trap_type: TrapType::Stop, value: 0 }),
+                    count if count % 20 == 0 => {
+                        cpu.set_jit_threshold(Some(2));
+                        Ok(())
+                    },
+                    _ => Ok(())
+                }
+            }
+        }));
+
+        let e = loop {
+            if let Err(e) = cpu.tick_block(&mut memory) {
+                break e;
+            }
+        };
+        assert!(matches!(e.trap_type, TrapType::Stop));
+        assert_eq!(cpu.x[10], 100);
+    }
+
+    #[test]
+    fn misaligned_jumps_trap_without_compressed_instructions() {
+        let mut memory: Vec<u8> = vec![
+            0xe7, 0x00, 0x50, 0x00, // jalr ra, 5(zero)
+            0xe7, 0x00, 0x60, 0x00, // jalr ra, 6(zero)
+            0x63, 0x03, 0x00, 0x00, // beq zero, zero, 6
+            0x05, 0x05, // addi a0,a0,1
+            0x00, 0x00
+        ];
+
+        let mut cpu = Cpu::new();
+        cpu.tick(&mut memory).expect("cpu failure");
+        assert_eq!(cpu.get_pc(), 4);
+        assert_eq!(cpu.x[1], 4);
+
+        cpu.set_compressed_instructions(false);
+        cpu.x[1] = 0;
+        let e = cpu.tick(&mut memory).unwrap_err();
+        assert!(matches!(e.trap_type, TrapType::InstructionAddressMisaligned));
+        assert_eq!(e.value, 6);
+        assert_eq!(cpu.x[1], 0);
+
+        cpu.update_pc(8);
+        let e = cpu.tick(&mut memory).unwrap_err();
+        assert!(matches!(e.trap_type, TrapType::InstructionAddressMisaligned));
+        assert_eq!(e.value, 14);
+
+        cpu.update_pc(12);
+        let e = cpu.tick(&mut memory).unwrap_err();
+        assert!(matches!(e.trap_type, TrapType::IllegalInstruction));
+    }
+
+    #[test]
+    fn run_reports_why_it_stopped_and_resumes() {
+        let mut memory: Vec<u8> = vec![
+            0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+            0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+            0x73, 0x00, 0x10, 0x00, // ebreak
+            0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+            0x73, 0x00, 0x00, 0x00 // ecall
+        ];
+
+        let mut cpu = Cpu::new();
+        cpu.set_decode_cache(true);
+        cpu.set_ecall_handler(Some(Instruction {
+            name: "ECALL",
+            operation: |cpu, _memory, _word, _address| {
+                Err(Trap { trap_type: TrapType::Stop, value: cpu.x[10] as u64 })
+            }
+        }));
+
+        assert!(matches!(cpu.run(&mut memory, 1), (ExitReason::BudgetExhausted, 1)));
+        assert_eq!(cpu.get_pc(), 4);
+        assert!(matches!(cpu.run(&mut memory, 100), (ExitReason::Breakpoint { pc: 8 }, 2)));
+        assert_eq!(cpu.x[10], 2);
+
+        cpu.stop_handle().stop();
+        assert!(matches!(cpu.run(&mut memory, 100), (ExitReason::Stopped, 0)));
+        assert!(matches!(cpu.run(&mut memory, 100), (ExitReason::Exit(3), 2)));
+
+        // a fault is not retired and leaves the pc on the instruction, so resuming tries it again
+        let (reason, retired) = cpu.run(&mut memory, 100);
+        assert!(matches!(reason, ExitReason::Trap { trap: Trap { trap_type: TrapType::InstructionAccessFault, value: 20 }, pc: 20 }));
+        assert_eq!(retired, 0);
+        assert_eq!(cpu.time, 5);
+
+        memory.extend([0x6f, 0x00, 0x00, 0x00]); // jal zero, 0
+        assert!(matches!(cpu.run(&mut memory, 100), (ExitReason::BudgetExhausted, 100)));
+        assert_eq!(cpu.get_pc(), 20);
+    }
+
+    #[test]
+    fn run_splits_a_fused_pair_to_stay_within_the_budget() {
+        let mut memory: Vec<u8> = vec![
+            0x37, 0x55, 0x34, 0x12, // lui a0, 0x12345
+            0x13, 0x05, 0xf5, 0xff, // addi a0, a0, -1
+            0x37, 0x55, 0x34, 0x12, // lui a0, 0x12345
+            0x13, 0x05, 0xf5, 0xff // addi a0, a0, -1
+        ];
+
+        let mut cpu = Cpu::new();
+        cpu.set_decode_cache(true);
+        cpu.set_macro_op_fusion(true);
+        assert!(matches!(cpu.run(&mut memory, 3), (ExitReason::BudgetExhausted, 3)));
+        assert_eq!(cpu.get_pc(), 12);
+        assert_eq!(cpu.x[10], 0x12345000);
+        assert_eq!(cpu.fusion_counts().lui_addi, 1);
+
+        assert!(matches!(cpu.run(&mut memory, 1), (ExitReason::BudgetExhausted, 1)));
+        assert_eq!(cpu.x[10], 0x12344fff);
+    }
+
+    #[test]
+    fn clone_and_reset_keep_the_configuration() {
+        let mut memory: Vec<u8> = vec![
+            0x05, 0x05, // addi a0,a0,1
+            0x05, 0x05, // addi a0,a0,1
+            0x05, 0x05, // addi a0,a0,1
+            0x00, 0x00
+        ];
+        assert!(std::mem::size_of::<Cpu>() < 2048);
+
+        let mut cpu = Cpu::new();
+        cpu.tick(&mut memory).expect("cpu failure");
+        cpu.write_csr(CSR_MSCRATCH_ADDRESS, 7);
+        cpu.write_csr(0x7c0, 9);
+
+        let mut clone = cpu.clone();
+        clone.tick(&mut memory).expect("cpu failure");
+        assert_eq!(clone.x[10], 2);
+        assert_eq!(clone.read_csr(CSR_TIME_ADDRESS), 2);
+        assert_eq!(clone.read_csr(CSR_MSCRATCH_ADDRESS), 7);
+        assert_eq!(clone.read_csr(0x7c0), 9);
+        assert_eq!(cpu.x[10], 1);
+        assert_eq!(cpu.read_csr(CSR_TIME_ADDRESS), 1);
+
+        cpu.set_compressed_instructions(false);
+        cpu.reset();
+        assert_eq!(cpu.get_pc(), 0);
+        assert_eq!(cpu.x[10], 0);
+        assert_eq!(cpu.read_csr(CSR_TIME_ADDRESS), 0);
+        assert_eq!(cpu.read_csr(CSR_MSCRATCH_ADDRESS), 0);
+        assert_eq!(cpu.read_csr(0x7c0), 0);
+        let e = cpu.tick(&mut memory).unwrap_err();
+        assert!(matches!(e.trap_type, TrapType::IllegalInstruction));
+    }
+
     #[test]
     fn decode_fld_compressed_instruction() {
         let opcode = Cpu::uncompress(0x3022);
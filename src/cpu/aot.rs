@@ -0,0 +1,574 @@
+use crate::cpu::block::ends_block;
+use crate::cpu::instruction::{self, Instruction};
+use crate::cpu::{Cpu, Trap, TrapType};
+use crate::memory::{AsMemory, Memory};
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryInto;
+
+const EM_RISCV: u16 = 0xf3;
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+
+// A loadable segment of an executable. data is what the file holds, the rest of size is zero filled.
+pub struct Segment {
+    pub address: usize,
+    pub data: Vec<u8>,
+    pub size: usize,
+    pub executable: bool
+}
+
+pub struct Image {
+    pub entry: usize,
+    pub segments: Vec<Segment>
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(offset..offset.checked_add(2)?)?.try_into().ok()?))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(offset..offset.checked_add(4)?)?.try_into().ok()?))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(offset..offset.checked_add(8)?)?.try_into().ok()?))
+}
+
+impl Image {
+    // Reads the loadable segments of a little endian 64 bit RISC-V executable, None for anything else
+    pub fn from_elf(elf: &[u8]) -> Option<Image> {
+        if elf.get(0..6)? != [0x7f, b'E', b'L', b'F', 2, 1] || read_u16(elf, 18)? != EM_RISCV {
+            return None;
+        }
+
+        let headers = read_u64(elf, 32)? as usize;
+        let header_size = read_u16(elf, 54)? as usize;
+        let mut segments = Vec::new();
+        for index in 0..read_u16(elf, 56)? as usize {
+            let header = headers.checked_add(index * header_size)?;
+            if read_u32(elf, header)? != PT_LOAD {
+                continue;
+            }
+
+            let offset = read_u64(elf, header + 8)? as usize;
+            let file_size = read_u64(elf, header + 32)? as usize;
+            segments.push(Segment {
+                address: read_u64(elf, header + 16)? as usize,
+                data: elf.get(offset..offset.checked_add(file_size)?)?.to_vec(),
+                size: read_u64(elf, header + 40)? as usize,
+                executable: read_u32(elf, header + 4)? & PF_X != 0
+            });
+        }
+
+        Some(Image { entry: read_u64(elf, 24)? as usize, segments })
+    }
+
+    // moves the whole image so its lowest segment starts at base
+    pub fn relocate(&mut self, base: usize) {
+        let lowest = self.segments.iter().map(|segment| segment.address).min().unwrap_or(base);
+        for segment in self.segments.iter_mut() {
+            segment.address = segment.address.wrapping_sub(lowest).wrapping_add(base);
+        }
+        self.entry = self.entry.wrapping_sub(lowest).wrapping_add(base);
+    }
+
+    pub fn load<M: Memory + ?Sized>(&self, memory: &mut M) -> Result<(), Trap> {
+        for segment in self.segments.iter() {
+            memory.write_bytes(segment.address, &segment.data)?;
+            memory.fill(segment.address.wrapping_add(segment.data.len()), segment.size.saturating_sub(segment.data.len()), 0)?;
+        }
+
+        Ok(())
+    }
+}
+
+struct Decoded {
+    word: u32, // already expanded if it was a compressed instruction
+    length: usize,
+    instruction: &'static Instruction
+}
+
+// A straight sweep through the executable segments, which assumes there is no data mixed in with the
+// code. Anything that does not decode is left out and reported by tick if it ever runs.
+fn disassemble(image: &Image) -> BTreeMap<usize, Decoded> {
+    let mut code = BTreeMap::new();
+    for segment in image.segments.iter().filter(|segment| segment.executable) {
+        let mut offset = 0;
+        while let Some(halfword) = read_u16(&segment.data, offset) {
+            let (word, length) = match halfword & 3 {
+                3 => match read_u32(&segment.data, offset) {
+                    Some(word) => (word, 4),
+                    None => break
+                },
+                _ => (Cpu::uncompress(halfword as u32), 2)
+            };
+            if let Some(instruction) = Cpu::decode(word) {
+                code.insert(segment.address.wrapping_add(offset), Decoded { word, length, instruction });
+            }
+            offset += length;
+        }
+    }
+
+    code
+}
+
+// Every address a block has to start at that can be found from the code itself: the entry point, branch
+// and jump targets, and whatever follows an instruction that ends a block, which covers return addresses.
+// Indirect jumps anywhere else still work, they just run through tick until they reach a block.
+fn block_starts(image: &Image, code: &BTreeMap<usize, Decoded>) -> BTreeSet<usize> {
+    let mut starts = BTreeSet::new();
+    starts.insert(image.entry);
+    for (&pc, decoded) in code.iter() {
+        match decoded.instruction.name {
+            "JAL" => {
+                starts.insert(pc.wrapping_add(instruction::parse_format_j(decoded.word).imm as usize));
+            },
+            "BEQ" | "BNE" | "BLT" | "BGE" | "BLTU" | "BGEU" => {
+                starts.insert(pc.wrapping_add(instruction::parse_format_b(decoded.word).imm as usize));
+            },
+            _ => {}
+        }
+        if ends_block(decoded.word) {
+            starts.insert(pc.wrapping_add(decoded.length));
+        }
+    }
+
+    starts.retain(|pc| code.contains_key(pc));
+    starts
+}
+
+// rd = this for the instructions that only read registers and write an integer register
+fn integer_expression(name: &str, word: u32, pc: usize) -> Option<String> {
+    let r = instruction::parse_format_r(word);
+    let imm = instruction::parse_format_i(word).imm;
+    let shamt = (word >> 20) & 0x3f;
+    let shamt_w = (word >> 20) & 0x1f;
+    let a = format!("cpu.x[{}]", r.rs1);
+    let b = format!("cpu.x[{}]", r.rs2);
+
+    Some(match name {
+        "LUI" => format!("{}", instruction::parse_format_u(word).imm as i64),
+        "AUIPC" => format!("{}", pc.wrapping_add(instruction::parse_format_u(word).imm as usize) as i64),
+        "ADDI" if r.rs1 == 0 => format!("{}", imm),
+        "ADDI" => format!("{}.wrapping_add({})", a, imm),
+        "SLTI" => format!("({} < {}) as i64", a, imm),
+        "SLTIU" if imm == 0 => String::from("0"),
+        "SLTIU" => format!("(({} as u64) < {}) as i64", a, imm as u64),
+        "XORI" => format!("{} ^ {}", a, imm),
+        "ORI" => format!("{} | {}", a, imm),
+        "ANDI" => format!("{} & {}", a, imm),
+        "SLLI" => format!("{} << {}", a, shamt),
+        "SRLI" => format!("(({} as u64) >> {}) as i64", a, shamt),
+        "SRAI" => format!("{} >> {}", a, shamt),
+        "ADDIW" => format!("{}.wrapping_add({}) as i32 as i64", a, imm),
+        "SLLIW" => format!("(({} as u32) << {}) as i32 as i64", a, shamt_w),
+        "SRLIW" => format!("(({} as u32) >> {}) as i32 as i64", a, shamt_w),
+        "SRAIW" => format!("(({} as i32) >> {}) as i64", a, shamt_w),
+        "ADD" => format!("{}.wrapping_add({})", a, b),
+        "SUB" => format!("{}.wrapping_sub({})", a, b),
+        "SLL" => format!("{}.wrapping_shl({} as u32)", a, b),
+        "SLT" => format!("({} < {}) as i64", a, b),
+        "SLTU" => format!("(({} as u64) < ({} as u64)) as i64", a, b),
+        "XOR" => format!("{} ^ {}", a, b),
+        "SRL" => format!("({} as u64).wrapping_shr({} as u32) as i64", a, b),
+        "SRA" => format!("{}.wrapping_shr({} as u32)", a, b),
+        "OR" => format!("{} | {}", a, b),
+        "AND" => format!("{} & {}", a, b),
+        "ADDW" => format!("{}.wrapping_add({}) as i32 as i64", a, b),
+        "SUBW" => format!("{}.wrapping_sub({}) as i32 as i64", a, b),
+        "SLLW" => format!("({} as u32).wrapping_shl({} as u32) as i32 as i64", a, b),
+        "SRLW" => format!("({} as u32).wrapping_shr({} as u32) as i32 as i64", a, b),
+        "SRAW" => format!("({} as i32).wrapping_shr({} as u32) as i64", a, b),
+        "MUL" => format!("{}.wrapping_mul({})", a, b),
+        "MULH" => format!("(({} as i128 * {} as i128) >> 64) as i64", a, b),
+        "MULHU" => format!("(({} as u64 as u128).wrapping_mul({} as u64 as u128) >> 64) as i64", a, b),
+        "MULHSU" => format!("(({} as u128).wrapping_mul({} as u64 as u128) >> 64) as i64", a, b),
+        "MULW" => format!("({} as i32).wrapping_mul({} as i32) as i64", a, b),
+        "DIV" => format!("match {} {{ 0 => -1, divisor => {}.wrapping_div(divisor) }}", b, a),
+        "DIVU" => format!("match {} as u64 {{ 0 => -1, divisor => (({} as u64) / divisor) as i64 }}", b, a),
+        "REM" => format!("match {} {{ 0 => {a}, divisor => {a}.wrapping_rem(divisor) }}", b, a = a),
+        "REMU" => format!("match {} as u64 {{ 0 => {a}, divisor => (({a} as u64) % divisor) as i64 }}", b, a = a),
+        "DIVW" => format!("match {} as i32 {{ 0 => -1, divisor => ({} as i32).wrapping_div(divisor) as i64 }}", b, a),
+        "DIVUW" => format!("match {} as u32 {{ 0 => -1, divisor => (({} as u32) / divisor) as i32 as i64 }}", b, a),
+        "REMW" => format!("match {} as i32 {{ 0 => {a} as i32 as i64, divisor => ({a} as i32).wrapping_rem(divisor) as i64 }}", b, a = a),
+        "REMUW" => format!("match {} as u32 {{ 0 => {a} as u32 as i32 as i64, divisor => (({a} as u32) % divisor) as i32 as i64 }}", b, a = a),
+        "FMV.X.D" => format!("cpu.f[{}].to_bits() as i64", r.rs1),
+        _ => return None
+    })
+}
+
+// f[rd] = this for the floating point instructions that never touch the fcsr
+fn float_expression(name: &str, word: u32) -> Option<String> {
+    let r = instruction::parse_format_r2(word);
+
+    Some(match name {
+        "FADD.D" => format!("cpu.f[{}] + cpu.f[{}]", r.rs1, r.rs2),
+        "FMUL.D" => format!("cpu.f[{}] * cpu.f[{}]", r.rs1, r.rs2),
+        "FMADD.D" => format!("cpu.f[{}] * cpu.f[{}] + cpu.f[{}]", r.rs1, r.rs2, r.rs3),
+        "FMV.D.X" => format!("f64::from_bits(cpu.x[{}] as u64)", r.rs1),
+        "FCVT.D.L" => format!("cpu.x[{}] as f64", r.rs1),
+        "FCVT.D.W" => format!("cpu.x[{}] as i32 as f64", r.rs1),
+        _ => return None
+    })
+}
+
+fn load_function(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "LB" => "load_i8",
+        "LBU" => "load_u8",
+        "LH" => "load_i16",
+        "LHU" => "load_u16",
+        "LW" => "load_i32",
+        "LWU" => "load_u32",
+        "LD" => "load_i64",
+        "FLD" => "load_f64",
+        _ => return None
+    })
+}
+
+// the store function and how to turn rs2 into what it stores
+fn store_function(name: &str) -> Option<(&'static str, &'static str)> {
+    Some(match name {
+        "SB" => ("store_u8", "x[{}] as u8"),
+        "SH" => ("store_u16", "x[{}] as u16"),
+        "SW" => ("store_u32", "x[{}] as u32"),
+        "SD" => ("store_u64", "x[{}] as u64"),
+        "FSD" => ("store_u64", "f[{}].to_bits()"),
+        _ => return None
+    })
+}
+
+fn condition(name: &str, a: &str, b: &str) -> Option<String> {
+    Some(match name {
+        "BEQ" => format!("{} == {}", a, b),
+        "BNE" => format!("{} != {}", a, b),
+        "BLT" => format!("{} < {}", a, b),
+        "BGE" => format!("{} >= {}", a, b),
+        "BLTU" => format!("({} as u64) < ({} as u64)", a, b),
+        "BGEU" => format!("({} as u64) >= ({} as u64)", a, b),
+        _ => return None
+    })
+}
+
+// Appends the Rust for one instruction and returns true if that code always leaves the block
+fn translate_instruction(body: &mut String, uses_memory: &mut bool, decoded: &Decoded, pc: usize, retired: usize) -> bool {
+    let name = decoded.instruction.name;
+    let word = decoded.word;
+    let next = pc.wrapping_add(decoded.length);
+    let r = instruction::parse_format_r(word);
+    let position = format!("cpu, time, {}, {:#x}, {:#x}", retired, pc, next);
+
+    *body += &format!("    // {:#x}: {}\n", pc, name);
+    if let Some(expression) = integer_expression(name, word, pc) {
+        if r.rd != 0 {
+            *body += &format!("    cpu.x[{}] = {};\n", r.rd, expression);
+        }
+    } else if let Some(expression) = float_expression(name, word) {
+        *body += &format!("    cpu.f[{}] = {};\n", r.rd, expression);
+    } else if let Some(function) = load_function(name) {
+        *uses_memory = true;
+        *body += &format!("    enter({});\n", position);
+        *body += &format!("    let address = cpu.x[{}].wrapping_add({}) as usize;\n", r.rs1, instruction::parse_format_i(word).imm);
+        *body += &format!("    {}(cpu, memory, {}, address)?;\n", function, r.rd);
+    } else if let Some((function, value)) = store_function(name) {
+        *uses_memory = true;
+        *body += &format!("    enter({});\n", position);
+        *body += &format!("    let address = cpu.x[{}].wrapping_add({}) as usize;\n", r.rs1, instruction::parse_format_s(word).imm);
+        *body += &format!("    let value = cpu.{};\n", value.replace("{}", &r.rs2.to_string()));
+        *body += &format!("    {}(cpu, memory, address, value)?;\n", function);
+    } else if let Some(condition) = condition(name, &format!("cpu.x[{}]", r.rs1), &format!("cpu.x[{}]", r.rs2)) {
+        let target = pc.wrapping_add(instruction::parse_format_b(word).imm as usize);
+        *body += &format!("    if {} {{\n        return jump({}, {:#x});\n    }}\n", condition, position, target);
+    } else if name == "JAL" {
+        let target = pc.wrapping_add(instruction::parse_format_j(word).imm as usize);
+        *body += &format!("    jump_and_link({}, {:#x}, {})\n", position, target, r.rd);
+        return true;
+    } else if name == "JALR" {
+        let imm = instruction::parse_format_i(word).imm;
+        *body += &format!("    let target = (cpu.x[{}] as u64).wrapping_add({}) as usize & !1;\n", r.rs1, imm as u64);
+        *body += &format!("    jump_and_link({}, target, {})\n", position, r.rd);
+        return true;
+    } else {
+        // everything else, including the system instructions, runs through the interpreter
+        *uses_memory = true;
+        let call = format!("interpret(cpu, memory, time, {}, {:#x})", retired, pc);
+        if ends_block(word) {
+            *body += &format!("    {}\n", call);
+            return true;
+        }
+        *body += &format!("    {}?;\n", call);
+    }
+
+    false
+}
+
+fn translate_block(code: &BTreeMap<usize, Decoded>, starts: &BTreeSet<usize>, start: usize) -> String {
+    let mut body = String::new();
+    let mut uses_memory = false;
+    let mut pc = start;
+    let mut retired = 0;
+
+    // a block runs up to an instruction that ends it, into the next block, or up to something that did not decode
+    while let Some(decoded) = code.get(&pc) {
+        retired += 1;
+        if translate_instruction(&mut body, &mut uses_memory, decoded, pc, retired) {
+            break;
+        }
+
+        pc = pc.wrapping_add(decoded.length);
+        if ends_block(decoded.word) || starts.contains(&pc) || !code.contains_key(&pc) {
+            body += &format!("    finish(cpu, time, {}, {:#x})\n", retired, pc);
+            break;
+        }
+    }
+
+    let memory = match uses_memory {
+        true => "memory",
+        false => "_memory"
+    };
+    format!(
+        "fn block_{:x}<M: AsMemory + ?Sized>(cpu: &mut Cpu, {}: &mut M) -> Result<usize, Trap> {{\n    let time = current_time(cpu);\n{}}}\n",
+        start, memory, body
+    )
+}
+
+// Translates the executable segments of an image into the source of a Rust module for the crate that
+// depends on this one. Each basic block becomes a function and the module's tick_block runs the one
+// at the pc, finding it in a table of every block start. It behaves like Cpu::tick_block, traps and
+// watchpoints included, as long as the code is not changed at run time and compressed instructions
+// are left enabled. Instructions that are not translated natively run through Cpu::tick, as does any
+// code the translation did not find.
+pub fn translate(image: &Image) -> String {
+    let code = disassemble(image);
+    let starts = block_starts(image, &code);
+
+    let mut source = String::from(
+        "// Generated by user_mode_riscv::cpu::aot::translate, do not edit\n\
+         \n\
+         use user_mode_riscv::cpu::aot::*;\n\
+         use user_mode_riscv::cpu::{Cpu, Trap};\n\
+         use user_mode_riscv::memory::AsMemory;\n\
+         \n\
+         pub fn tick_block<M: AsMemory + ?Sized>(cpu: &mut Cpu, memory: &mut M) -> Result<usize, Trap> {\n    \
+             match lookup::<M>(cpu.pc) {\n        \
+                 Some(block) => block(cpu, memory),\n        \
+                 None => cpu.tick(memory).map(|_| 1)\n    \
+             }\n\
+         }\n\
+         \n\
+         fn lookup<M: AsMemory + ?Sized>(pc: usize) -> Option<Block<M>> {\n    \
+             match pc {\n"
+    );
+    for start in starts.iter() {
+        source += &format!("        {:#x} => Some(block_{:x}),\n", start, start);
+    }
+    source += "        _ => None\n    }\n}\n";
+
+    for start in starts.iter() {
+        source += "\n";
+        source += &translate_block(&code, &starts, *start);
+    }
+
+    source
+}
+
+// What the translated code calls. retired counts the instructions the block has run so far including
+// the current one, which is what tick would have added to the time by the end of it.
+pub type Block<M> = fn(&mut Cpu, &mut M) -> Result<usize, Trap>;
+
+#[inline]
+pub fn current_time(cpu: &Cpu) -> u64 {
+    cpu.time
+}
+
+// leaves the cpu as tick would while running the instruction at pc, for anything that can trap
+#[inline]
+pub fn enter(cpu: &mut Cpu, time: u64, retired: usize, pc: usize, next: usize) {
+    cpu.instruction_address = pc;
+    cpu.pc = next;
+    cpu.time = time.wrapping_add(retired as u64);
+}
+
+#[inline]
+pub fn finish(cpu: &mut Cpu, time: u64, retired: usize, next: usize) -> Result<usize, Trap> {
+    cpu.pc = next;
+    cpu.time = time.wrapping_add(retired as u64);
+    Ok(retired)
+}
+
+#[inline]
+pub fn jump(cpu: &mut Cpu, time: u64, retired: usize, pc: usize, next: usize, target: usize) -> Result<usize, Trap> {
+    enter(cpu, time, retired, pc, next);
+    cpu.jump(target)?;
+    Ok(retired)
+}
+
+#[inline]
+pub fn jump_and_link(cpu: &mut Cpu, time: u64, retired: usize, pc: usize, next: usize, target: usize, rd: usize) -> Result<usize, Trap> {
+    enter(cpu, time, retired, pc, next);
+    cpu.jump(target)?;
+    if rd != 0 {
+        cpu.x[rd] = next as i64;
+    }
+    Ok(retired)
+}
+
+pub fn interpret<M: AsMemory + ?Sized>(cpu: &mut Cpu, memory: &mut M, time: u64, retired: usize, pc: usize) -> Result<usize, Trap> {
+    cpu.pc = pc;
+    cpu.time = time.wrapping_add(retired as u64 - 1);
+    cpu.tick(memory)?;
+    Ok(retired)
+}
+
+// The instruction completes before a watchpoint it hit is reported, but a fault drops the hit like tick does
+#[inline]
+fn accessed<T>(cpu: &mut Cpu, result: Result<T, Trap>) -> Result<T, Trap> {
+    if result.is_err() {
+        cpu.watchpoint_hit = None;
+    }
+    result
+}
+
+#[inline]
+fn completed(cpu: &mut Cpu) -> Result<(), Trap> {
+    match cpu.watchpoint_hit.take() {
+        Some(id) => Err(Trap { trap_type: TrapType::Watchpoint, value: id as u64 }),
+        None => Ok(())
+    }
+}
+
+#[inline]
+fn loaded(cpu: &mut Cpu, rd: usize, value: i64) -> Result<(), Trap> {
+    if rd != 0 {
+        cpu.x[rd] = value;
+    }
+    completed(cpu)
+}
+
+#[inline]
+pub fn load_i8<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, rd: usize, address: usize) -> Result<(), Trap> {
+    cpu.check_load(address, 1)?;
+    let value = accessed(cpu, memory.read_i8(address))?;
+    loaded(cpu, rd, value as i64)
+}
+
+#[inline]
+pub fn load_u8<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, rd: usize, address: usize) -> Result<(), Trap> {
+    cpu.check_load(address, 1)?;
+    let value = accessed(cpu, memory.read_u8(address))?;
+    loaded(cpu, rd, value as i64)
+}
+
+#[inline]
+pub fn load_i16<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, rd: usize, address: usize) -> Result<(), Trap> {
+    cpu.check_load(address, 2)?;
+    let value = accessed(cpu, memory.read_i16(address))?;
+    loaded(cpu, rd, value as i64)
+}
+
+#[inline]
+pub fn load_u16<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, rd: usize, address: usize) -> Result<(), Trap> {
+    cpu.check_load(address, 2)?;
+    let value = accessed(cpu, memory.read_u16(address))?;
+    loaded(cpu, rd, value as i64)
+}
+
+#[inline]
+pub fn load_i32<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, rd: usize, address: usize) -> Result<(), Trap> {
+    cpu.check_load(address, 4)?;
+    let value = accessed(cpu, memory.read_i32(address))?;
+    loaded(cpu, rd, value as i64)
+}
+
+#[inline]
+pub fn load_u32<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, rd: usize, address: usize) -> Result<(), Trap> {
+    cpu.check_load(address, 4)?;
+    let value = accessed(cpu, memory.read_u32(address))?;
+    loaded(cpu, rd, value as i64)
+}
+
+#[inline]
+pub fn load_i64<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, rd: usize, address: usize) -> Result<(), Trap> {
+    cpu.check_load(address, 8)?;
+    let value = accessed(cpu, memory.read_i64(address))?;
+    loaded(cpu, rd, value)
+}
+
+#[inline]
+pub fn load_f64<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, rd: usize, address: usize) -> Result<(), Trap> {
+    cpu.check_load(address, 8)?;
+    let value = accessed(cpu, memory.read_u64(address))?;
+    cpu.f[rd] = f64::from_bits(value);
+    completed(cpu)
+}
+
+#[inline]
+pub fn store_u8<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, address: usize, value: u8) -> Result<(), Trap> {
+    cpu.check_store(address, 1)?;
+    accessed(cpu, memory.write_u8(address, value))?;
+    completed(cpu)
+}
+
+#[inline]
+pub fn store_u16<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, address: usize, value: u16) -> Result<(), Trap> {
+    cpu.check_store(address, 2)?;
+    accessed(cpu, memory.write_u16(address, value))?;
+    completed(cpu)
+}
+
+#[inline]
+pub fn store_u32<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, address: usize, value: u32) -> Result<(), Trap> {
+    cpu.check_store(address, 4)?;
+    accessed(cpu, memory.write_u32(address, value))?;
+    completed(cpu)
+}
+
+#[inline]
+pub fn store_u64<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, address: usize, value: u64) -> Result<(), Trap> {
+    cpu.check_store(address, 8)?;
+    accessed(cpu, memory.write_u64(address, value))?;
+    completed(cpu)
+}
+
+#[cfg(test)]
+mod test_aot {
+    use super::*;
+
+    static SIMPLE: &[u8] = include_bytes!("../../test/rv64ui-p-simple");
+
+    #[test]
+    fn reads_the_loadable_segments() {
+        let mut image = Image::from_elf(SIMPLE).expect("not an ELF file");
+        assert_eq!(image.entry, 0x80000000);
+        assert_eq!(image.segments.len(), 2);
+        assert!(image.segments[0].executable);
+        assert_eq!(image.segments[0].data.len(), 0x1bc);
+        assert!(!image.segments[1].executable);
+        assert_eq!(image.segments[1].address, 0x80001000);
+
+        image.relocate(0);
+        assert_eq!(image.entry, 0);
+        assert_eq!(image.segments[1].address, 0x1000);
+
+        assert!(Image::from_elf(include_bytes!("../../test/rv64ui-p-simple.dump")).is_none());
+    }
+
+    #[test]
+    fn every_block_can_be_looked_up() {
+        let mut image = Image::from_elf(SIMPLE).expect("not an ELF file");
+        image.relocate(0);
+        let source = translate(&image);
+
+        // j reset_vector
+        assert!(source.contains("fn block_0<M: AsMemory + ?Sized>(cpu: &mut Cpu, _memory: &mut M)"));
+        assert!(source.contains("    jump_and_link(cpu, time, 1, 0x0, 0x4, 0x48, 0)\n"));
+        assert!(source.contains("    interpret(cpu, memory, time, 4, 0x144)\n"));
+
+        let blocks: Vec<&str> = source.lines().filter_map(|line| line.strip_prefix("fn block_")).collect();
+        assert!(blocks.len() > 1);
+        for block in blocks {
+            let start = block.split('<').next().unwrap();
+            assert!(source.contains(&format!("        0x{} => Some(block_{}),\n", start, start)));
+        }
+    }
+}
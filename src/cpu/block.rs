@@ -0,0 +1,468 @@
+use crate::cpu::instruction::{self, Instruction};
+use crate::cpu::{Cpu, Trap, TrapType};
+use crate::memory::{AsMemory, Memory, PAGE_SIZE};
+use std::collections::{HashMap, HashSet};
+
+#[cfg(feature = "jit")]
+mod jit;
+
+// the jit writes x86-64 code into memory mapped with mmap, anywhere else it would jump into garbage
+#[cfg(all(feature = "jit", not(all(target_arch = "x86_64", unix))))]
+compile_error!("the jit feature is only supported on x86-64 unix hosts");
+
+#[cfg(feature = "jit")]
+pub(crate) use jit::Jit;
+
+// long straight runs of code are split so tick_block still hands control back now and then
+pub(crate) const MAX_BLOCK_LENGTH: usize = 64;
+const CACHE_ENTRIES: usize = 4096;
+
+#[derive(Clone, Copy)]
+enum Alu {
+    Add,
+    Sub,
+    And,
+    Or,
+    Xor,
+    Sll,
+    Srl,
+    Sra,
+    Slt,
+    Sltu,
+    AddW,
+    SubW
+}
+
+#[derive(Clone, Copy)]
+enum Condition {
+    Eq,
+    Ne,
+    Lt,
+    Ge,
+    Ltu,
+    Geu
+}
+
+#[derive(Clone, Copy)]
+enum Load {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    I64
+}
+
+// the operands are pulled out of the instruction word when the block is translated
+#[derive(Clone, Copy)]
+enum Operation {
+    // rd = rs1 op imm, the shift immediates hold the shift amount
+    Immediate(Alu, i64),
+    // rd = rs1 op rs2
+    Register(Alu),
+    // lui and auipc, which are both constant once the pc is known
+    Constant(i64),
+    Load(Load, i64),
+    Store(usize, i64),
+    Branch(Condition, usize),
+    Jal(usize),
+    Jalr(i64),
+    // everything else runs the interpreter's own implementation
+    Interpreted(&'static Instruction, u32)
+}
+
+#[derive(Clone, Copy)]
+struct Op {
+    operation: Operation,
+    rd: usize,
+    rs1: usize,
+    rs2: usize,
+    pc: usize,
+    next: usize
+}
+
+// A straight line run of guest code that can only be left through its last instruction or a trap
+pub(crate) struct Block {
+    ops: Vec<Op>,
+    start: usize,
+    end: usize, // just past the last instruction
+    #[cfg(feature = "jit")]
+    runs: u32,
+    #[cfg(feature = "jit")]
+    code: Option<jit::Code>
+}
+
+fn translate_op(instruction: &'static Instruction, word: u32, pc: usize, next: usize) -> Op {
+    let r = instruction::parse_format_r(word);
+    let i = instruction::parse_format_i(word);
+    let shamt = ((word >> 20) & 0x3f) as i64;
+    let b_target = pc.wrapping_add(instruction::parse_format_b(word).imm as usize);
+    let s_imm = instruction::parse_format_s(word).imm;
+
+    let operation = match instruction.name {
+        "ADDI" => Operation::Immediate(Alu::Add, i.imm),
+        "ANDI" => Operation::Immediate(Alu::And, i.imm),
+        "ORI" => Operation::Immediate(Alu::Or, i.imm),
+        "XORI" => Operation::Immediate(Alu::Xor, i.imm),
+        "SLTI" => Operation::Immediate(Alu::Slt, i.imm),
+        "SLTIU" => Operation::Immediate(Alu::Sltu, i.imm),
+        "SLLI" => Operation::Immediate(Alu::Sll, shamt),
+        "SRLI" => Operation::Immediate(Alu::Srl, shamt),
+        "SRAI" => Operation::Immediate(Alu::Sra, shamt),
+        "ADDIW" => Operation::Immediate(Alu::AddW, i.imm),
+        "ADD" => Operation::Register(Alu::Add),
+        "SUB" => Operation::Register(Alu::Sub),
+        "AND" => Operation::Register(Alu::And),
+        "OR" => Operation::Register(Alu::Or),
+        "XOR" => Operation::Register(Alu::Xor),
+        "SLL" => Operation::Register(Alu::Sll),
+        "SRL" => Operation::Register(Alu::Srl),
+        "SRA" => Operation::Register(Alu::Sra),
+        "SLT" => Operation::Register(Alu::Slt),
+        "SLTU" => Operation::Register(Alu::Sltu),
+        "ADDW" => Operation::Register(Alu::AddW),
+        "SUBW" => Operation::Register(Alu::SubW),
+        "LUI" => Operation::Constant(instruction::parse_format_u(word).imm as i64),
+        "AUIPC" => Operation::Constant(pc.wrapping_add(instruction::parse_format_u(word).imm as usize) as i64),
+        "LB" => Operation::Load(Load::I8, i.imm),
+        "LBU" => Operation::Load(Load::U8, i.imm),
+        "LH" => Operation::Load(Load::I16, i.imm),
+        "LHU" => Operation::Load(Load::U16, i.imm),
+        "LW" => Operation::Load(Load::I32, i.imm),
+        "LWU" => Operation::Load(Load::U32, i.imm),
+        "LD" => Operation::Load(Load::I64, i.imm),
+        "SB" => Operation::Store(1, s_imm),
+        "SH" => Operation::Store(2, s_imm),
+        "SW" => Operation::Store(4, s_imm),
+        "SD" => Operation::Store(8, s_imm),
+        "BEQ" => Operation::Branch(Condition::Eq, b_target),
+        "BNE" => Operation::Branch(Condition::Ne, b_target),
+        "BLT" => Operation::Branch(Condition::Lt, b_target),
+        "BGE" => Operation::Branch(Condition::Ge, b_target),
+        "BLTU" => Operation::Branch(Condition::Ltu, b_target),
+        "BGEU" => Operation::Branch(Condition::Geu, b_target),
+        "JAL" => Operation::Jal(pc.wrapping_add(instruction::parse_format_j(word).imm as usize)),
+        "JALR" => Operation::Jalr(i.imm),
+        _ => Operation::Interpreted(instruction, word)
+    };
+
+    Op { operation, rd: r.rd, rs1: r.rs1, rs2: r.rs2, pc, next }
+}
+
+// control transfers, system instructions and fences can all change what runs next
+pub(crate) fn ends_block(word: u32) -> bool {
+    matches!(word & 0x7f, 0b1100011 | 0b1101111 | 0b1100111 | 0b1110011 | 0b0001111)
+}
+
+impl Block {
+    // Translates from pc up to and including the next instruction that ends a block. An instruction
+    // that cannot be fetched or decoded ends the block early and is left for tick to report, so the
+    // block is empty if the very first one fails.
+    pub fn translate<M: Memory + ?Sized>(cpu: &Cpu, memory: &M, start: usize) -> Block {
+        let mut ops = Vec::new();
+        let mut pc = start;
+
+        while ops.len() < MAX_BLOCK_LENGTH {
+            let (word, length) = match cpu.fetch_at(memory, pc) {
+                Ok(fetched) => fetched,
+                Err(_) => break
+            };
+            let instruction = match Cpu::decode(word) {
+                Some(instruction) => instruction,
+                None => break
+            };
+
+            let next = pc.wrapping_add(length);
+            ops.push(translate_op(instruction, word, pc, next));
+            pc = next;
+            if ends_block(word) {
+                break;
+            }
+        }
+
+        Block {
+            ops,
+            start,
+            end: pc,
+            #[cfg(feature = "jit")]
+            runs: 0,
+            #[cfg(feature = "jit")]
+            code: None
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    fn pages(&self) -> impl Iterator<Item = usize> {
+        self.start / PAGE_SIZE..=(self.end - 1) / PAGE_SIZE
+    }
+
+    // Runs the block and returns how many instructions it retired. Each instruction leaves the cpu
+    // exactly as tick would have, so the block can stop early after a store into code or a watchpoint.
+    // Only the interpreted instructions can see the time, so it is brought up to date just for them.
+    pub fn execute<M: AsMemory + ?Sized>(&self, cpu: &mut Cpu, memory: &mut M) -> Result<usize, Trap> {
+        let time = cpu.time;
+        cpu.exit_block = false;
+        cpu.running_block = (self.start, self.end);
+
+        #[cfg(feature = "jit")]
+        if let Some(code) = &self.code {
+            let (retired, result) = code.run(self, cpu, memory.as_memory(), time);
+            return Block::finish(cpu, time, retired, result);
+        }
+
+        // register only instructions cannot trap or see the pc, so they skip all the bookkeeping
+        cpu.pc = self.end;
+        let mut retired = 0;
+        let mut result = Ok(());
+        for op in self.ops.iter() {
+            retired += 1;
+            if let Some(value) = op.register_only(cpu) {
+                cpu.x[op.rd] = value;
+                cpu.x[0] = 0;
+                continue;
+            }
+
+            result = Block::step(op, cpu, memory, time, retired);
+            if result.is_err() || cpu.exit_block || cpu.watchpoint_hit.is_some() {
+                break;
+            }
+            // the register only instructions after it leave the pc alone, it has to be the end again
+            if retired < self.ops.len() {
+                cpu.pc = self.end;
+            }
+        }
+
+        Block::finish(cpu, time, retired, result)
+    }
+
+    // runs an instruction that may trap or look at the pc, retired includes the instruction itself
+    fn step<M: AsMemory + ?Sized>(op: &Op, cpu: &mut Cpu, memory: &mut M, time: u64, retired: usize) -> Result<(), Trap> {
+        cpu.instruction_address = op.pc;
+        cpu.pc = op.next;
+        if let Operation::Interpreted(..) = op.operation {
+            cpu.time = time.wrapping_add(retired as u64);
+        }
+
+        let result = op.execute(cpu, memory);
+        cpu.x[0] = 0;
+        result
+    }
+
+    fn finish(cpu: &mut Cpu, time: u64, retired: usize, result: Result<(), Trap>) -> Result<usize, Trap> {
+        cpu.time = time.wrapping_add(retired as u64);
+        cpu.running_block = (0, 0);
+
+        // a fault in the instruction itself takes priority over any watchpoint it hit
+        match (result, cpu.watchpoint_hit.take()) {
+            (Ok(()), Some(id)) => Err(Trap { trap_type: TrapType::Watchpoint, value: id as u64 }),
+            (result, _) => result.map(|_| retired)
+        }
+    }
+}
+
+impl Op {
+    #[inline]
+    fn alu(cpu: &Cpu, alu: Alu, a: i64, b: i64) -> i64 {
+        match alu {
+            Alu::Add => cpu.sign_extend(a.wrapping_add(b)),
+            Alu::Sub => cpu.sign_extend(a.wrapping_sub(b)),
+            Alu::And => cpu.sign_extend(a & b),
+            Alu::Or => cpu.sign_extend(a | b),
+            Alu::Xor => cpu.sign_extend(a ^ b),
+            Alu::Sll => cpu.sign_extend(a.wrapping_shl(b as u32)),
+            Alu::Srl => cpu.sign_extend(cpu.unsigned_data(a).wrapping_shr(b as u32) as i64),
+            Alu::Sra => cpu.sign_extend(a.wrapping_shr(b as u32)),
+            Alu::Slt => (a < b) as i64,
+            Alu::Sltu => (cpu.unsigned_data(a) < cpu.unsigned_data(b)) as i64,
+            Alu::AddW => a.wrapping_add(b) as i32 as i64,
+            Alu::SubW => a.wrapping_sub(b) as i32 as i64
+        }
+    }
+
+    #[inline]
+    fn register_only(&self, cpu: &Cpu) -> Option<i64> {
+        match self.operation {
+            Operation::Immediate(alu, imm) => Some(Op::alu(cpu, alu, cpu.x[self.rs1], imm)),
+            Operation::Register(alu) => Some(Op::alu(cpu, alu, cpu.x[self.rs1], cpu.x[self.rs2])),
+            Operation::Constant(value) => Some(cpu.sign_extend(value)),
+            _ => None
+        }
+    }
+
+    fn execute<M: AsMemory + ?Sized>(&self, cpu: &mut Cpu, memory: &mut M) -> Result<(), Trap> {
+        match self.operation {
+            Operation::Immediate(..) | Operation::Register(..) | Operation::Constant(..) => {
+                if let Some(value) = self.register_only(cpu) {
+                    cpu.x[self.rd] = value;
+                }
+            },
+            Operation::Load(load, imm) => {
+                let address = cpu.x[self.rs1].wrapping_add(imm) as usize;
+                cpu.x[self.rd] = match load {
+                    Load::I8 => {
+                        cpu.check_load(address, 1)?;
+                        memory.read_i8(address)? as i64
+                    },
+                    Load::U8 => {
+                        cpu.check_load(address, 1)?;
+                        memory.read_u8(address)? as i64
+                    },
+                    Load::I16 => {
+                        cpu.check_load(address, 2)?;
+                        memory.read_i16(address)? as i64
+                    },
+                    Load::U16 => {
+                        cpu.check_load(address, 2)?;
+                        memory.read_u16(address)? as i64
+                    },
+                    Load::I32 => {
+                        cpu.check_load(address, 4)?;
+                        memory.read_i32(address)? as i64
+                    },
+                    Load::U32 => {
+                        cpu.check_load(address, 4)?;
+                        memory.read_u32(address)? as i64
+                    },
+                    Load::I64 => {
+                        cpu.check_load(address, 8)?;
+                        memory.read_i64(address)?
+                    }
+                };
+            },
+            Operation::Store(size, imm) => {
+                let address = cpu.x[self.rs1].wrapping_add(imm) as usize;
+                let value = cpu.x[self.rs2];
+                cpu.check_store(address, size)?;
+                match size {
+                    1 => memory.write_u8(address, value as u8)?,
+                    2 => memory.write_u16(address, value as u16)?,
+                    4 => memory.write_u32(address, value as u32)?,
+                    _ => memory.write_u64(address, value as u64)?
+                }
+            },
+            Operation::Branch(condition, target) => {
+                let a = cpu.x[self.rs1];
+                let b = cpu.x[self.rs2];
+                let taken = match condition {
+                    Condition::Eq => cpu.sign_extend(a) == cpu.sign_extend(b),
+                    Condition::Ne => cpu.sign_extend(a) != cpu.sign_extend(b),
+                    Condition::Lt => cpu.sign_extend(a) < cpu.sign_extend(b),
+                    Condition::Ge => cpu.sign_extend(a) >= cpu.sign_extend(b),
+                    Condition::Ltu => cpu.unsigned_data(a) < cpu.unsigned_data(b),
+                    Condition::Geu => cpu.unsigned_data(a) >= cpu.unsigned_data(b)
+                };
+                if taken {
+                    cpu.jump(target)?;
+                }
+            },
+            Operation::Jal(target) => {
+                cpu.jump(target)?;
+                cpu.x[self.rd] = cpu.sign_extend(self.next as i64);
+            },
+            Operation::Jalr(imm) => {
+                cpu.jump((cpu.x[self.rs1] as u64).wrapping_add(imm as u64) as usize & !1)?;
+                cpu.x[self.rd] = cpu.sign_extend(self.next as i64);
+            },
+            Operation::Interpreted(instruction, word) => {
+                (instruction.operation)(cpu, memory.as_memory(), word, self.pc)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Direct mapped cache of translated blocks by start address, kept coherent with guest stores the same
+// way as the DecodeCache. A block is taken out while it runs and only put back if nothing could have
+// changed its code in the meantime. Like the DecodeCache its entries are allocated on first use.
+pub(crate) struct BlockCache {
+    entries: Box<[Option<Box<Block>>]>,
+    code_pages: HashMap<usize, HashSet<usize>>, // the slots that may hold a block on each page
+    code_start: usize,
+    code_end: usize
+}
+
+impl BlockCache {
+    pub fn new() -> Self {
+        BlockCache {
+            entries: Box::new([]),
+            code_pages: HashMap::new(),
+            code_start: usize::MAX,
+            code_end: 0
+        }
+    }
+
+    fn index(pc: usize) -> usize {
+        (pc >> 1) % CACHE_ENTRIES
+    }
+
+    pub fn take(&mut self, pc: usize) -> Option<Box<Block>> {
+        let entry = self.entries.get_mut(BlockCache::index(pc))?;
+        match entry.as_ref().is_some_and(|block| block.start == pc) {
+            true => entry.take(),
+            false => None
+        }
+    }
+
+    // a newly translated block has to be registered before it runs so stores into it are noticed
+    pub fn register(&mut self, block: &Block) {
+        let slot = BlockCache::index(block.start);
+        for page in block.pages() {
+            self.code_pages.entry(page).or_default().insert(slot);
+        }
+        self.code_start = self.code_start.min(block.start);
+        self.code_end = self.code_end.max(block.end - 1);
+    }
+
+    pub fn restore(&mut self, block: Box<Block>) {
+        if self.entries.is_empty() {
+            self.entries = (0..CACHE_ENTRIES).map(|_| None).collect();
+        }
+        let index = BlockCache::index(block.start);
+        self.entries[index] = Some(block);
+    }
+
+    pub fn clear(&mut self) {
+        if self.code_pages.is_empty() {
+            return;
+        }
+
+        for entry in self.entries.iter_mut() {
+            *entry = None;
+        }
+        self.code_pages.clear();
+        self.code_start = usize::MAX;
+        self.code_end = 0;
+    }
+
+    // drops every block on the pages address..address + size touches, returns true if any of them
+    // held code so the running block knows to stop
+    pub fn invalidate(&mut self, address: usize, size: usize) -> bool {
+        let last = address.saturating_add(size - 1);
+        if last < self.code_start || address > self.code_end {
+            return false;
+        }
+
+        let mut dropped = false;
+        for page in [address / PAGE_SIZE, last / PAGE_SIZE] {
+            if let Some(slots) = self.code_pages.remove(&page) {
+                // the block registered in a slot may be running, or replaced by one from another page
+                for slot in slots {
+                    let entry = self.entries.get_mut(slot).filter(|entry| {
+                        entry.as_ref().is_some_and(|block| block.pages().any(|block_page| block_page == page))
+                    });
+                    if let Some(entry) = entry {
+                        *entry = None;
+                    }
+                }
+                dropped = true;
+            }
+        }
+
+        dropped
+    }
+}
@@ -0,0 +1,508 @@
+use super::{Alu, Block, Condition, Op, Operation};
+use crate::cpu::{Cpu, Trap};
+use crate::memory::Memory;
+use std::any::Any;
+use std::mem::offset_of;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::sync::Arc;
+
+// how many times a block runs through the interpreter before it is compiled
+const DEFAULT_THRESHOLD: u32 = 16;
+
+// compiled code is never freed on its own, the arena is only emptied when every block is flushed
+const ARENA_SIZE: usize = 8 << 20;
+
+const RAX: u8 = 0;
+const RCX: u8 = 1;
+const RBX: u8 = 3;
+const RBP: u8 = 5;
+const R14: u8 = 14;
+
+// x86 condition codes
+const CC_B: u8 = 0x2;
+const CC_AE: u8 = 0x3;
+const CC_E: u8 = 0x4;
+const CC_NE: u8 = 0x5;
+const CC_L: u8 = 0xc;
+const CC_GE: u8 = 0xd;
+
+// What the generated code is called with. The code itself only uses the first three fields, the
+// rest are for step, which runs anything the code does not do itself.
+#[repr(C)]
+struct Context<'a> {
+    x: *mut i64,
+    f: *mut f64,
+    pc: *mut usize,
+    cpu: *mut Cpu,
+    memory: *mut (dyn Memory + 'a),
+    block: *const Block,
+    time: u64,
+    result: Result<(), Trap>,
+    panic: Option<Box<dyn Any + Send>>
+}
+
+// Called by the generated code to run op index of the block through the interpreter. Anything but
+// zero means the block has to stop, either because of a trap or because step told it to.
+extern "C" fn step(context: *mut Context, index: usize) -> u32 {
+    let context = unsafe { &mut *context };
+    // a panic must not unwind through the generated code, so it is carried over to Code::run
+    let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+        let cpu = unsafe { &mut *context.cpu };
+        let block = unsafe { &*context.block };
+        let result = Block::step(&block.ops[index], cpu, unsafe { &mut *context.memory }, context.time, index + 1);
+        let stop = result.is_err() || cpu.exit_block || cpu.watchpoint_hit.is_some();
+        // Only the last op can move pc anywhere but the end of the block, which the code relies on.
+        // A block that stops early leaves the pc after the op, just like the interpreter.
+        if !stop && index + 1 < block.ops.len() {
+            cpu.pc = block.end;
+        }
+        (result, stop)
+    }));
+
+    match outcome {
+        Ok((Ok(()), exit)) => exit as u32,
+        Ok((Err(trap), _)) => {
+            context.result = Err(trap);
+            1
+        },
+        Err(payload) => {
+            context.panic = Some(payload);
+            1
+        }
+    }
+}
+
+// Holds on to the mapping the code lives in, so a block that is still running stays mapped even if
+// the jit is reset or dropped underneath it, say by an ecall handler
+pub(crate) struct Code {
+    entry: unsafe extern "C" fn(*mut Context) -> usize,
+    _mapping: Arc<Mapping>
+}
+
+impl Code {
+    // returns the number of instructions retired and how the last of them went
+    pub fn run(&self, block: &Block, cpu: &mut Cpu, memory: &mut dyn Memory, time: u64) -> (usize, Result<(), Trap>) {
+        // from here on the cpu is only touched through raw pointers, by the code and by step
+        let cpu: *mut Cpu = cpu;
+        let mut context = Context {
+            x: unsafe { ptr::addr_of_mut!((*cpu).x) } as *mut i64,
+            f: unsafe { ptr::addr_of_mut!((*cpu).f) } as *mut f64,
+            pc: unsafe { ptr::addr_of_mut!((*cpu).pc) },
+            cpu,
+            memory,
+            block,
+            time,
+            result: Ok(()),
+            panic: None
+        };
+
+        let retired = unsafe { (self.entry)(&mut context) };
+        if let Some(payload) = context.panic {
+            panic::resume_unwind(payload);
+        }
+
+        (retired, context.result)
+    }
+}
+
+// A fixed size mapping that is writable while code is copied in and executable the rest of the time
+struct Mapping {
+    base: *mut u8
+}
+
+// nothing is ever written through a shared mapping, only by the arena that has it to itself
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.base as *mut libc::c_void, ARENA_SIZE) };
+    }
+}
+
+struct Arena {
+    mapping: Arc<Mapping>,
+    used: usize
+}
+
+impl Arena {
+    fn new() -> Option<Arena> {
+        let base = unsafe {
+            libc::mmap(ptr::null_mut(), ARENA_SIZE, libc::PROT_READ | libc::PROT_EXEC, libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0)
+        };
+
+        match base == libc::MAP_FAILED {
+            true => None,
+            false => Some(Arena { mapping: Arc::new(Mapping { base: base as *mut u8 }), used: 0 })
+        }
+    }
+
+    fn protect(&self, protection: libc::c_int) -> bool {
+        unsafe { libc::mprotect(self.mapping.base as *mut libc::c_void, ARENA_SIZE, protection) == 0 }
+    }
+
+    fn install(&mut self, code: &[u8]) -> Option<Code> {
+        // keep every entry point 16 byte aligned
+        let start = (self.used + 15) & !15;
+        if start + code.len() > ARENA_SIZE || !self.protect(libc::PROT_READ | libc::PROT_WRITE) {
+            return None;
+        }
+
+        let entry = unsafe {
+            let entry = self.mapping.base.add(start);
+            ptr::copy_nonoverlapping(code.as_ptr(), entry, code.len());
+            entry
+        };
+        self.used = start + code.len();
+
+        match self.protect(libc::PROT_READ | libc::PROT_EXEC) {
+            true => Some(Code {
+                entry: unsafe { std::mem::transmute::<*mut u8, unsafe extern "C" fn(*mut Context) -> usize>(entry) },
+                _mapping: self.mapping.clone()
+            }),
+            false => None
+        }
+    }
+}
+
+// Compiles blocks to x86-64 once they have run threshold times. Register only instructions, jumps,
+// branches, MUL and the simple double precision arithmetic become native code. Everything else,
+// including every memory access, calls back into the interpreter so traps are reported exactly as
+// tick_block would report them.
+pub(crate) struct Jit {
+    arena: Option<Arena>,
+    threshold: u32,
+    unavailable: bool // executable memory could not be mapped
+}
+
+impl Default for Jit {
+    fn default() -> Self {
+        Jit::new(DEFAULT_THRESHOLD)
+    }
+}
+
+impl Jit {
+    pub fn new(threshold: u32) -> Self {
+        Jit {
+            arena: None,
+            threshold,
+            unavailable: false
+        }
+    }
+
+    pub fn prepare(&mut self, block: &mut Block, compressed_instructions: bool) {
+        if block.code.is_some() || self.unavailable {
+            return;
+        }
+        block.runs = block.runs.saturating_add(1);
+        if block.runs <= self.threshold {
+            return;
+        }
+
+        if self.arena.is_none() {
+            self.arena = Arena::new();
+            self.unavailable = self.arena.is_none();
+        }
+        if let Some(arena) = &mut self.arena {
+            block.code = arena.install(&compile(block, compressed_instructions));
+        }
+    }
+
+    pub fn threshold(&self) -> u32 {
+        self.threshold
+    }
+
+    // Only called once no cached block holds compiled code. A block that is still running does, and
+    // then the arena is left to it and a new one is mapped when the next block is compiled.
+    pub fn reset(&mut self) {
+        match self.arena.as_mut().filter(|arena| Arc::strong_count(&arena.mapping) == 1) {
+            Some(arena) => arena.used = 0,
+            None => self.arena = None
+        }
+    }
+}
+
+struct Assembler {
+    code: Vec<u8>
+}
+
+impl Assembler {
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn rex(&mut self, wide: bool, reg: u8, base: u8) {
+        let rex = 0x40 | (wide as u8) << 3 | (reg >> 3) << 2 | base >> 3;
+        if rex != 0x40 {
+            self.code.push(rex);
+        }
+    }
+
+    // opcode reg, [base + displacement], base must not be rsp or r12
+    fn memory(&mut self, wide: bool, opcode: &[u8], reg: u8, base: u8, displacement: usize) {
+        self.rex(wide, reg, base);
+        self.emit(opcode);
+        self.code.push(0x80 | (reg & 7) << 3 | base & 7);
+        self.emit(&(displacement as i32).to_le_bytes());
+    }
+
+    fn load(&mut self, reg: u8, base: u8, displacement: usize) {
+        self.memory(true, &[0x8b], reg, base, displacement);
+    }
+
+    fn store(&mut self, base: u8, displacement: usize, reg: u8) {
+        self.memory(true, &[0x89], reg, base, displacement);
+    }
+
+    fn load_x(&mut self, reg: u8, x: usize) {
+        self.load(reg, RBX, x * 8);
+    }
+
+    fn store_x(&mut self, x: usize, reg: u8) {
+        self.store(RBX, x * 8, reg);
+    }
+
+    fn move_immediate(&mut self, reg: u8, value: u64) {
+        self.rex(true, 0, reg);
+        self.code.push(0xb8 + (reg & 7));
+        self.emit(&value.to_le_bytes());
+    }
+
+    // group 1 operation on rax with a sign extended 32 bit immediate
+    fn immediate(&mut self, wide: bool, extension: u8, value: i64) {
+        self.rex(wide, 0, RAX);
+        self.code.push(0x81);
+        self.code.push(0xc0 | extension << 3);
+        self.emit(&(value as i32).to_le_bytes());
+    }
+
+    fn shift_immediate(&mut self, extension: u8, amount: i64) {
+        self.emit(&[0x48, 0xc1, 0xc0 | extension << 3, amount as u8]);
+    }
+
+    fn shift_cl(&mut self, extension: u8) {
+        self.emit(&[0x48, 0xd3, 0xc0 | extension << 3]);
+    }
+
+    // rax = rax < operand as 0 or 1, after the compare has been emitted
+    fn set(&mut self, condition: u8) {
+        self.emit(&[0x0f, 0x90 | condition, 0xc0, 0x0f, 0xb6, 0xc0]);
+    }
+
+    fn sign_extend_eax(&mut self) {
+        self.emit(&[0x48, 0x63, 0xc0]);
+    }
+
+    fn set_pc(&mut self, reg: u8) {
+        self.load(RCX, RBP, offset_of!(Context, pc));
+        self.store(RCX, 0, reg);
+    }
+
+    // returns where the 32 bit displacement goes so it can be patched later
+    fn jump(&mut self, condition: Option<u8>) -> usize {
+        match condition {
+            Some(condition) => self.emit(&[0x0f, 0x80 | condition]),
+            None => self.code.push(0xe9)
+        }
+        self.emit(&[0; 4]);
+        self.code.len() - 4
+    }
+
+    fn patch(&mut self, at: usize) {
+        let offset = (self.code.len() - (at + 4)) as i32;
+        self.code[at..at + 4].copy_from_slice(&offset.to_le_bytes());
+    }
+
+    fn double(&mut self, opcode: u8, xmm: u8, f: usize) {
+        self.code.push(0xf2);
+        self.memory(false, &[0x0f, opcode], xmm, R14, f * 8);
+    }
+}
+
+fn alu_operand(assembler: &mut Assembler, alu: Alu, rs2: usize) -> bool {
+    let opcode = match alu {
+        Alu::Add | Alu::AddW => 0x03,
+        Alu::Sub | Alu::SubW => 0x2b,
+        Alu::And => 0x23,
+        Alu::Or => 0x0b,
+        Alu::Xor => 0x33,
+        Alu::Slt | Alu::Sltu => 0x3b,
+        Alu::Sll | Alu::Srl | Alu::Sra => {
+            assembler.load_x(RCX, rs2);
+            assembler.shift_cl(shift_extension(alu));
+            return true;
+        }
+    };
+
+    let wide = !matches!(alu, Alu::AddW | Alu::SubW);
+    assembler.memory(wide, &[opcode], RAX, RBX, rs2 * 8);
+    finish_alu(assembler, alu);
+    true
+}
+
+fn alu_immediate(assembler: &mut Assembler, alu: Alu, value: i64) -> bool {
+    let extension = match alu {
+        Alu::Add | Alu::AddW => 0,
+        Alu::Or => 1,
+        Alu::And => 4,
+        Alu::Xor => 6,
+        Alu::Slt | Alu::Sltu => 7,
+        Alu::Sll | Alu::Srl | Alu::Sra => {
+            assembler.shift_immediate(shift_extension(alu), value);
+            return true;
+        },
+        Alu::Sub | Alu::SubW => return false
+    };
+
+    assembler.immediate(!matches!(alu, Alu::AddW), extension, value);
+    finish_alu(assembler, alu);
+    true
+}
+
+fn shift_extension(alu: Alu) -> u8 {
+    match alu {
+        Alu::Sll => 4,
+        Alu::Srl => 5,
+        _ => 7
+    }
+}
+
+fn finish_alu(assembler: &mut Assembler, alu: Alu) {
+    match alu {
+        Alu::Slt => assembler.set(CC_L),
+        Alu::Sltu => assembler.set(CC_B),
+        Alu::AddW | Alu::SubW => assembler.sign_extend_eax(),
+        _ => {}
+    }
+}
+
+// emits op as native code, returns false if it has to go through step instead
+fn compile_op(assembler: &mut Assembler, op: &Op, compressed_instructions: bool) -> bool {
+    // without the C extension a jump to a target that is not 4 byte aligned traps
+    let reachable = |target: usize| compressed_instructions || target & 3 == 0;
+
+    match op.operation {
+        Operation::Immediate(alu, value) => {
+            if op.rd == 0 {
+                return true;
+            }
+            assembler.load_x(RAX, op.rs1);
+            if !alu_immediate(assembler, alu, value) {
+                return false;
+            }
+            assembler.store_x(op.rd, RAX);
+        },
+        Operation::Register(alu) => {
+            if op.rd == 0 {
+                return true;
+            }
+            assembler.load_x(RAX, op.rs1);
+            alu_operand(assembler, alu, op.rs2);
+            assembler.store_x(op.rd, RAX);
+        },
+        Operation::Constant(value) => {
+            if op.rd != 0 {
+                assembler.move_immediate(RAX, value as u64);
+                assembler.store_x(op.rd, RAX);
+            }
+        },
+        Operation::Branch(condition, target) if reachable(target) => {
+            // jump over the pc update when the branch is not taken
+            let not_taken = match condition {
+                Condition::Eq => CC_NE,
+                Condition::Ne => CC_E,
+                Condition::Lt => CC_GE,
+                Condition::Ge => CC_L,
+                Condition::Ltu => CC_AE,
+                Condition::Geu => CC_B
+            };
+            assembler.load_x(RAX, op.rs1);
+            assembler.memory(true, &[0x3b], RAX, RBX, op.rs2 * 8);
+            let skip = assembler.jump(Some(not_taken));
+            assembler.move_immediate(RAX, target as u64);
+            assembler.set_pc(RAX);
+            assembler.patch(skip);
+        },
+        Operation::Jal(target) if reachable(target) => {
+            if op.rd != 0 {
+                assembler.move_immediate(RAX, op.next as u64);
+                assembler.store_x(op.rd, RAX);
+            }
+            assembler.move_immediate(RAX, target as u64);
+            assembler.set_pc(RAX);
+        },
+        Operation::Jalr(offset) if compressed_instructions => {
+            assembler.load_x(RAX, op.rs1);
+            assembler.immediate(true, 0, offset);
+            assembler.immediate(true, 4, -2);
+            assembler.set_pc(RAX);
+            if op.rd != 0 {
+                assembler.move_immediate(RAX, op.next as u64);
+                assembler.store_x(op.rd, RAX);
+            }
+        },
+        Operation::Interpreted(instruction, _) => match instruction.name {
+            "MUL" | "MULW" => {
+                if op.rd != 0 {
+                    assembler.load_x(RAX, op.rs1);
+                    assembler.memory(instruction.name == "MUL", &[0x0f, 0xaf], RAX, RBX, op.rs2 * 8);
+                    if instruction.name == "MULW" {
+                        assembler.sign_extend_eax();
+                    }
+                    assembler.store_x(op.rd, RAX);
+                }
+            },
+            "FADD.D" | "FMUL.D" => {
+                assembler.double(0x10, 0, op.rs1);
+                assembler.double(if instruction.name == "FADD.D" { 0x58 } else { 0x59 }, 0, op.rs2);
+                assembler.double(0x11, 0, op.rd);
+            },
+            _ => return false
+        },
+        _ => return false
+    }
+
+    true
+}
+
+fn compile(block: &Block, compressed_instructions: bool) -> Vec<u8> {
+    let mut assembler = Assembler { code: Vec::new() };
+
+    // push rbx, push rbp, push r14, mov rbp, rdi
+    assembler.emit(&[0x53, 0x55, 0x41, 0x56, 0x48, 0x89, 0xfd]);
+    assembler.load(RBX, RBP, offset_of!(Context, x));
+    assembler.load(R14, RBP, offset_of!(Context, f));
+    assembler.move_immediate(RAX, block.end as u64);
+    assembler.set_pc(RAX);
+
+    let mut exits = Vec::new();
+    for (index, op) in block.ops.iter().enumerate() {
+        let start = assembler.code.len();
+        if compile_op(&mut assembler, op, compressed_instructions) {
+            continue;
+        }
+        assembler.code.truncate(start);
+
+        // mov rdi, rbp, mov esi, index, then call step and leave if it says so
+        assembler.emit(&[0x48, 0x89, 0xef, 0xbe]);
+        assembler.emit(&(index as u32).to_le_bytes());
+        assembler.move_immediate(RAX, step as *const () as u64);
+        assembler.emit(&[0xff, 0xd0, 0x85, 0xc0]);
+        let next = assembler.jump(Some(CC_E));
+        assembler.code.push(0xb8);
+        assembler.emit(&(index as u32 + 1).to_le_bytes());
+        exits.push(assembler.jump(None));
+        assembler.patch(next);
+    }
+
+    assembler.code.push(0xb8);
+    assembler.emit(&(block.ops.len() as u32).to_le_bytes());
+    for exit in exits {
+        assembler.patch(exit);
+    }
+    // pop r14, pop rbp, pop rbx, ret
+    assembler.emit(&[0x41, 0x5e, 0x5d, 0x5b, 0xc3]);
+
+    assembler.code
+}
@@ -0,0 +1,124 @@
+use crate::cpu::fusion::Fusion;
+use crate::cpu::instruction::Instruction;
+use crate::memory::PAGE_SIZE;
+use std::collections::{HashMap, HashSet};
+
+const CACHE_ENTRIES: usize = 4096;
+// pc is always at least 2 byte aligned so this can never match a real entry
+const EMPTY: usize = usize::MAX;
+
+#[derive(Clone, Copy)]
+pub(crate) struct DecodedInstruction {
+    pub pc: usize,
+    pub word: u32, // already expanded if it was a compressed instruction
+    pub length: usize, // covers both instructions of a fused pair
+    pub instruction: &'static Instruction,
+    pub fusion: Option<(Fusion, usize)> // the pair and the address of its second instruction
+}
+
+// Direct mapped cache of decoded instructions indexed by pc. It only knows about the code it has
+// seen fetched, so it has to be told about stores into that code to stay coherent. The entries are
+// only allocated once the first instruction is cached, a Cpu that never runs never pays for them.
+pub(crate) struct DecodeCache {
+    entries: Box<[Option<DecodedInstruction>]>,
+    // the slots that may hold code from each page, so a store only looks at those
+    code_pages: HashMap<usize, HashSet<usize>>,
+    // every cached instruction lies within these bounds, which keeps the store check cheap
+    code_start: usize,
+    code_end: usize
+}
+
+impl DecodeCache {
+    pub fn new() -> Self {
+        DecodeCache {
+            entries: Box::new([]),
+            code_pages: HashMap::new(),
+            code_start: EMPTY,
+            code_end: 0
+        }
+    }
+
+    #[inline]
+    fn index(pc: usize) -> usize {
+        (pc >> 1) % CACHE_ENTRIES
+    }
+
+    #[inline]
+    pub fn lookup(&self, pc: usize) -> Option<DecodedInstruction> {
+        self.entries.get(DecodeCache::index(pc)).and_then(|entry| entry.filter(|entry| entry.pc == pc))
+    }
+
+    pub fn insert(&mut self, entry: DecodedInstruction) {
+        let last = entry.pc.wrapping_add(entry.length - 1);
+        let slot = DecodeCache::index(entry.pc);
+        self.code_pages.entry(entry.pc / PAGE_SIZE).or_default().insert(slot);
+        self.code_pages.entry(last / PAGE_SIZE).or_default().insert(slot);
+        self.code_start = self.code_start.min(entry.pc);
+        self.code_end = self.code_end.max(last);
+        if self.entries.is_empty() {
+            self.entries = vec![None; CACHE_ENTRIES].into_boxed_slice();
+        }
+        self.entries[slot] = Some(entry);
+    }
+
+    pub fn clear(&mut self) {
+        if self.code_pages.is_empty() {
+            return;
+        }
+
+        self.entries.fill(None);
+        self.code_pages.clear();
+        self.code_start = EMPTY;
+        self.code_end = 0;
+    }
+
+    // drops every cached instruction on the pages address..address + size touches
+    pub fn invalidate(&mut self, address: usize, size: usize) {
+        let last = address.saturating_add(size - 1);
+        if last < self.code_start || address > self.code_end {
+            return;
+        }
+
+        for page in [address / PAGE_SIZE, last / PAGE_SIZE] {
+            // a slot may have been taken over by code from another page since it was recorded
+            for slot in self.code_pages.remove(&page).unwrap_or_default() {
+                let entry = &mut self.entries[slot];
+                let stale = entry.is_some_and(|entry| {
+                    entry.pc / PAGE_SIZE == page || entry.pc.wrapping_add(entry.length - 1) / PAGE_SIZE == page
+                });
+                if stale {
+                    *entry = None;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_cache {
+    use super::*;
+    use crate::cpu::Cpu;
+
+    fn entry(pc: usize) -> DecodedInstruction {
+        let word = 0x00150513; // addi a0, a0, 1
+        DecodedInstruction { pc, word, length: 4, instruction: Cpu::decode(word).unwrap(), fusion: None }
+    }
+
+    #[test]
+    fn invalidate_only_drops_the_pages_written_to() {
+        let mut cache = DecodeCache::new();
+        cache.insert(entry(0x1000));
+        cache.insert(entry(0x3000)); // takes over the slot of 0x1000
+        cache.insert(entry(0x2ffe)); // spans two pages
+        cache.insert(entry(0x4008));
+
+        cache.invalidate(0x1004, 4);
+        assert!(cache.lookup(0x3000).is_some());
+        assert!(!cache.code_pages.contains_key(&0x1));
+
+        cache.invalidate(0x3000, 8);
+        assert!(cache.lookup(0x3000).is_none());
+        assert!(cache.lookup(0x2ffe).is_none());
+        assert!(cache.lookup(0x4008).is_some());
+    }
+}
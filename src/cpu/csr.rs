@@ -0,0 +1,106 @@
+use crate::cpu::*;
+
+// Every CSR with storage of its own, sorted by address. fflags, frm, sstatus, sie and sip are views
+// onto other CSRs, and the time is counted by the cpu itself.
+const STORED: [u16; 34] = [
+    CSR_USTATUS_ADDRESS,
+    CSR_FCSR_ADDRESS,
+    CSR_UIE_ADDRESS,
+    CSR_UTVEC_ADDRESS,
+    CSR_USCRATCH_ADDRESS,
+    CSR_UEPC_ADDRESS,
+    CSR_UCAUSE_ADDRESS,
+    CSR_UTVAL_ADDRESS,
+    CSR_UIP_ADDRESS,
+    CSR_SEDELEG_ADDRESS,
+    CSR_SIDELEG_ADDRESS,
+    CSR_STVEC_ADDRESS,
+    CSR_SSCRATCH_ADDRESS,
+    CSR_SEPC_ADDRESS,
+    CSR_SCAUSE_ADDRESS,
+    CSR_STVAL_ADDRESS,
+    CSR_SATP_ADDRESS,
+    CSR_MSTATUS_ADDRESS,
+    CSR_MISA_ADDRESS,
+    CSR_MEDELEG_ADDRESS,
+    CSR_MIDELEG_ADDRESS,
+    CSR_MIE_ADDRESS,
+    CSR_MTVEC_ADDRESS,
+    CSR_MSCRATCH_ADDRESS,
+    CSR_MEPC_ADDRESS,
+    CSR_MCAUSE_ADDRESS,
+    CSR_MTVAL_ADDRESS,
+    CSR_MIP_ADDRESS,
+    CSR_PMPCFG0_ADDRESS,
+    CSR_PMPADDR0_ADDRESS,
+    CSR_MCYCLE_ADDRESS,
+    CSR_CYCLE_ADDRESS,
+    CSR_INSTRET_ADDRESS,
+    CSR_MHARTID_ADDRESS
+];
+
+// Raw CSR storage, small enough that creating, cloning and resetting a Cpu stays cheap. Any other
+// address still reads back what was last written to it, those are kept in a list that stays empty
+// unless the guest goes looking for CSRs that do not exist.
+#[derive(Clone)]
+pub(crate) struct Csrs {
+    values: [u64; STORED.len()],
+    other: Vec<(u16, u64)>
+}
+
+impl Csrs {
+    pub fn new() -> Self {
+        Csrs {
+            values: [0; STORED.len()],
+            other: Vec::new()
+        }
+    }
+
+    pub fn get(&self, address: u16) -> u64 {
+        match STORED.binary_search(&address) {
+            Ok(index) => self.values[index],
+            Err(_) => self.other.iter().find(|(other, _)| *other == address).map_or(0, |(_, value)| *value)
+        }
+    }
+
+    pub fn set(&mut self, address: u16, value: u64) {
+        match STORED.binary_search(&address) {
+            Ok(index) => self.values[index] = value,
+            Err(_) => match self.other.iter_mut().find(|(other, _)| *other == address) {
+                Some(entry) => entry.1 = value,
+                None => self.other.push((address, value))
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.values = [0; STORED.len()];
+        self.other.clear();
+    }
+}
+
+#[cfg(test)]
+mod test_csr {
+    use super::*;
+
+    #[test]
+    fn stored_addresses_are_sorted() {
+        assert!(STORED.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn unknown_addresses_read_back() {
+        let mut csrs = Csrs::new();
+        csrs.set(CSR_MEPC_ADDRESS, 0x1234);
+        csrs.set(0x7c0, 5);
+        csrs.set(0x7c0, 6);
+        assert_eq!(csrs.get(CSR_MEPC_ADDRESS), 0x1234);
+        assert_eq!(csrs.get(0x7c0), 6);
+        assert_eq!(csrs.get(0x7c1), 0);
+        assert_eq!(csrs.other.len(), 1);
+
+        csrs.clear();
+        assert_eq!(csrs.get(CSR_MEPC_ADDRESS), 0);
+        assert_eq!(csrs.get(0x7c0), 0);
+    }
+}
@@ -0,0 +1,104 @@
+use crate::cpu::instruction::{self, Instruction};
+use crate::cpu::{Cpu, Trap};
+use crate::memory::Memory;
+
+// How many times each kind of pair has run fused since the cpu was created
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FusionCounts {
+    pub lui_addi: u64,
+    pub auipc_jalr: u64,
+    pub auipc_ld: u64,
+    pub slli_srli: u64
+}
+
+// A pair of instructions that runs as one. Everything that only depends on the pc and the
+// instruction words is worked out when the pair is decoded.
+#[derive(Clone, Copy)]
+pub(crate) enum Fusion {
+    // lui rd, imm then addi or addiw rd, rd, imm, which is a constant
+    LuiAddi { rd: usize, value: i64 },
+    // auipc rd, imm then jalr link, offset(rd)
+    AuipcJalr { rd: usize, base: i64, link: usize, target: usize },
+    // auipc rd, imm then ld dest, offset(rd)
+    AuipcLd { rd: usize, base: i64, dest: usize, address: usize },
+    // slli rd, rs, n then srli rd, rd, n, which zero extends the low bits of rs
+    SlliSrli { rd: usize, rs: usize, shift: u32 }
+}
+
+impl Fusion {
+    // the pair first at pc followed by second, if it is one that fuses
+    pub fn detect(cpu: &Cpu, first: &Instruction, first_word: u32, second: &Instruction, second_word: u32, pc: usize) -> Option<Fusion> {
+        let u = instruction::parse_format_u(first_word);
+        let i = instruction::parse_format_i(second_word);
+        // every pair feeds the first result straight into the second, and writing x0 fuses nothing
+        if u.rd == 0 || i.rs1 != u.rd {
+            return None;
+        }
+
+        match (first.name, second.name) {
+            ("LUI", "ADDI") if i.rd == u.rd => Some(Fusion::LuiAddi {
+                rd: u.rd,
+                value: cpu.sign_extend((u.imm as i64).wrapping_add(i.imm))
+            }),
+            ("LUI", "ADDIW") if i.rd == u.rd => Some(Fusion::LuiAddi {
+                rd: u.rd,
+                value: (u.imm as i64).wrapping_add(i.imm) as i32 as i64
+            }),
+            ("AUIPC", "JALR") => {
+                let base = cpu.sign_extend(pc.wrapping_add(u.imm as usize) as i64);
+                let target = (base as u64).wrapping_add(i.imm as u64) as usize & !1;
+                Some(Fusion::AuipcJalr { rd: u.rd, base, link: i.rd, target })
+            },
+            ("AUIPC", "LD") => {
+                let base = cpu.sign_extend(pc.wrapping_add(u.imm as usize) as i64);
+                let address = base.wrapping_add(i.imm) as usize;
+                Some(Fusion::AuipcLd { rd: u.rd, base, dest: i.rd, address })
+            },
+            ("SLLI", "SRLI") if i.rd == u.rd && (first_word >> 20) & 0x3f == (second_word >> 20) & 0x3f => {
+                let r = instruction::parse_format_r(first_word);
+                Some(Fusion::SlliSrli { rd: r.rd, rs: r.rs1, shift: (first_word >> 20) & 0x3f })
+            },
+            _ => None
+        }
+    }
+
+    // Runs the pair as tick would run the two instructions one after the other. tick has already
+    // set the pc past both of them and counted the first one in the time.
+    pub fn execute(&self, cpu: &mut Cpu, memory: &mut dyn Memory, second_address: usize) -> Result<(), Trap> {
+        match *self {
+            Fusion::LuiAddi { rd, value } => {
+                cpu.fusion_counts.lui_addi += 1;
+                Fusion::start_second(cpu, second_address);
+                cpu.x[rd] = value;
+            },
+            Fusion::AuipcJalr { rd, base, link, target } => {
+                cpu.fusion_counts.auipc_jalr += 1;
+                cpu.x[rd] = base;
+                Fusion::start_second(cpu, second_address);
+                let next = cpu.sign_extend(cpu.pc as i64);
+                cpu.jump(target)?;
+                cpu.x[link] = next;
+            },
+            Fusion::AuipcLd { rd, base, dest, address } => {
+                cpu.fusion_counts.auipc_ld += 1;
+                cpu.x[rd] = base;
+                Fusion::start_second(cpu, second_address);
+                cpu.check_load(address, 8)?;
+                cpu.x[dest] = memory.read_i64(address)?;
+            },
+            Fusion::SlliSrli { rd, rs, shift } => {
+                cpu.fusion_counts.slli_srli += 1;
+                Fusion::start_second(cpu, second_address);
+                cpu.x[rd] = cpu.sign_extend((cpu.unsigned_data(cpu.x[rs] << shift) >> shift) as i64);
+            }
+        }
+
+        Ok(())
+    }
+
+    // from here on a trap is reported against the second instruction, with the first one retired
+    fn start_second(cpu: &mut Cpu, second_address: usize) {
+        cpu.instruction_address = second_address;
+        cpu.time = cpu.time.wrapping_add(1);
+    }
+}
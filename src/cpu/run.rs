@@ -0,0 +1,46 @@
+use crate::cpu::Trap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Why Cpu::run handed control back. The pc is always where the guest continues, so calling run
+// again resumes it. After an exit, a breakpoint or a watchpoint that is the next instruction, after
+// any other trap it is the instruction that raised it, which runs again once the host has dealt with
+// the cause.
+#[derive(Debug)]
+pub enum ExitReason {
+    // the whole budget was spent
+    BudgetExhausted,
+    // the ecall handler returned TrapType::Stop, the code is its value
+    Exit(u64),
+    // the guest ran EBREAK at pc
+    Breakpoint { pc: usize },
+    // any other trap, pc is the instruction that raised it
+    Trap { trap: Trap, pc: usize },
+    // the host asked the cpu to stop through a StopHandle
+    Stopped
+}
+
+// Lets the host stop a running cpu from another thread or from inside an ecall handler. run notices
+// the request before the next instruction or block.
+#[derive(Clone, Debug, Default)]
+pub struct StopHandle {
+    requested: Arc<AtomicBool>
+}
+
+impl StopHandle {
+    pub fn stop(&self) {
+        self.requested.store(true, Ordering::Relaxed);
+    }
+
+    // true once per request, so the next run goes ahead
+    pub(crate) fn take(&self) -> bool {
+        match self.requested.load(Ordering::Relaxed) {
+            true => self.requested.swap(false, Ordering::Relaxed),
+            false => false
+        }
+    }
+
+    pub(crate) fn clear(&self) {
+        self.requested.store(false, Ordering::Relaxed);
+    }
+}
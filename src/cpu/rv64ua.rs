@@ -1,14 +1,14 @@
 use crate::cpu::instruction;
 use crate::cpu::instruction::Instruction;
+use crate::memory::AtomicOp;
 
 pub const AMOADD_D: Instruction = Instruction {
     name: "AMOADD.D",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_r(word);
-
-        let tmp = memory.read_i64(cpu.x[f.rs1] as usize)?;
-        memory.write_u64(cpu.x[f.rs1] as usize, cpu.x[f.rs2].wrapping_add(tmp) as u64)?;
-        cpu.x[f.rd] = tmp;
+        let address = cpu.x[f.rs1] as usize;
+        cpu.check_amo(address, 8)?;
+        cpu.x[f.rd] = memory.fetch_op_u64(address, AtomicOp::Add, cpu.x[f.rs2] as u64)? as i64;
         Ok(())
     }
 };
@@ -17,9 +17,9 @@ pub const AMOADD_W: Instruction = Instruction {
     name: "AMOADD.W",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_r(word);
-        let tmp = memory.read_i32(cpu.x[f.rs1] as usize)? as i64;
-        memory.write_u32(cpu.x[f.rs1] as usize, cpu.x[f.rs2].wrapping_add(tmp) as u32)?;
-        cpu.x[f.rd] = tmp;
+        let address = cpu.x[f.rs1] as usize;
+        cpu.check_amo(address, 4)?;
+        cpu.x[f.rd] = memory.fetch_op_u32(address, AtomicOp::Add, cpu.x[f.rs2] as u32)? as i32 as i64;
         Ok(())
     }
 };
@@ -28,9 +28,9 @@ pub const AMOAND_D: Instruction = Instruction {
     name: "AMOAND.D",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_r(word);
-        let tmp = memory.read_i64(cpu.x[f.rs1] as usize)?;
-        memory.write_u64(cpu.x[f.rs1] as usize, (cpu.x[f.rs2] & tmp) as u64)?;
-        cpu.x[f.rd] = tmp;
+        let address = cpu.x[f.rs1] as usize;
+        cpu.check_amo(address, 8)?;
+        cpu.x[f.rd] = memory.fetch_op_u64(address, AtomicOp::And, cpu.x[f.rs2] as u64)? as i64;
         Ok(())
     }
 };
@@ -39,9 +39,9 @@ pub const AMOAND_W: Instruction = Instruction {
     name: "AMOAND.W",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_r(word);
-        let tmp = memory.read_i32(cpu.x[f.rs1] as usize)? as i64;
-        memory.write_u32(cpu.x[f.rs1] as usize, (cpu.x[f.rs2] & tmp) as u32)?;
-        cpu.x[f.rd] = tmp;
+        let address = cpu.x[f.rs1] as usize;
+        cpu.check_amo(address, 4)?;
+        cpu.x[f.rd] = memory.fetch_op_u32(address, AtomicOp::And, cpu.x[f.rs2] as u32)? as i32 as i64;
         Ok(())
     }
 };
@@ -50,15 +50,9 @@ pub const AMOMAX_D: Instruction = Instruction {
     name: "AMOMAX.D",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_r(word);
-
-        let tmp = memory.read_i64(cpu.x[f.rs1] as usize)?;
-        let max = match cpu.x[f.rs2] >=tmp {
-            true => cpu.x[f.rs2],
-            false => tmp as i64
-        };
-        memory.write_u64(cpu.x[f.rs1] as usize, max as u64)?;
-        cpu.x[f.rd] = tmp;
-
+        let address = cpu.x[f.rs1] as usize;
+        cpu.check_amo(address, 8)?;
+        cpu.x[f.rd] = memory.fetch_op_u64(address, AtomicOp::Max, cpu.x[f.rs2] as u64)? as i64;
         Ok(())
     }
 };
@@ -67,13 +61,9 @@ pub const AMOMAX_W: Instruction = Instruction {
     name: "AMOMAX.W",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_r(word);
-        let tmp = memory.read_i32(cpu.x[f.rs1] as usize)?;
-        let max = match (cpu.x[f.rs2] as i32) >=tmp {
-            true => cpu.x[f.rs2] as i32,
-            false => tmp as i32
-        };
-        memory.write_u32(cpu.x[f.rs1] as usize, max as u32)?;
-        cpu.x[f.rd] = tmp as i64;
+        let address = cpu.x[f.rs1] as usize;
+        cpu.check_amo(address, 4)?;
+        cpu.x[f.rd] = memory.fetch_op_u32(address, AtomicOp::Max, cpu.x[f.rs2] as u32)? as i32 as i64;
         Ok(())
     }
 };
@@ -82,13 +72,9 @@ pub const AMOMAXU_D: Instruction = Instruction {
     name: "AMOMAXU.D",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_r(word);
-        let tmp = memory.read_u64(cpu.x[f.rs1] as usize)?;
-        let max = match (cpu.x[f.rs2] as u64) >=tmp {
-            true => cpu.x[f.rs2] as u64,
-            false => tmp as u64
-        };
-        memory.write_u64(cpu.x[f.rs1] as usize, max)?;
-        cpu.x[f.rd] = tmp as i64;
+        let address = cpu.x[f.rs1] as usize;
+        cpu.check_amo(address, 8)?;
+        cpu.x[f.rd] = memory.fetch_op_u64(address, AtomicOp::MaxU, cpu.x[f.rs2] as u64)? as i64;
         Ok(())
     }
 };
@@ -97,13 +83,9 @@ pub const AMOMAXU_W: Instruction = Instruction {
     name: "AMOMAXU.W",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_r(word);
-        let tmp = memory.read_u32(cpu.x[f.rs1] as usize)?;
-        let max = match (cpu.x[f.rs2] as u32) >= tmp {
-            true => cpu.x[f.rs2] as u32,
-            false => tmp as u32
-        };
-        memory.write_u32(cpu.x[f.rs1] as usize, max)?;
-        cpu.x[f.rd] = tmp as i32 as i64;
+        let address = cpu.x[f.rs1] as usize;
+        cpu.check_amo(address, 4)?;
+        cpu.x[f.rd] = memory.fetch_op_u32(address, AtomicOp::MaxU, cpu.x[f.rs2] as u32)? as i32 as i64;
         Ok(())
     }
 };
@@ -112,14 +94,9 @@ pub const AMOMIN_D: Instruction = Instruction {
     name: "AMOMIN.D",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_r(word);
-
-        let tmp = memory.read_i64(cpu.x[f.rs1] as usize)?;
-        let min = match cpu.x[f.rs2] <=tmp {
-            true => cpu.x[f.rs2],
-            false => tmp as i64
-        };
-        memory.write_u64(cpu.x[f.rs1] as usize, min as u64)?;
-        cpu.x[f.rd] = tmp as i64;
+        let address = cpu.x[f.rs1] as usize;
+        cpu.check_amo(address, 8)?;
+        cpu.x[f.rd] = memory.fetch_op_u64(address, AtomicOp::Min, cpu.x[f.rs2] as u64)? as i64;
         Ok(())
     }
 };
@@ -128,13 +105,9 @@ pub const AMOMIN_W: Instruction = Instruction {
     name: "AMOMIN.W",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_r(word);
-        let tmp = memory.read_i32(cpu.x[f.rs1] as usize)?;
-        let min = match (cpu.x[f.rs2] as i32) <= tmp {
-            true => cpu.x[f.rs2] as i32,
-            false => tmp as i32
-        };
-        memory.write_u32(cpu.x[f.rs1] as usize, min as u32)?;
-        cpu.x[f.rd] = tmp as i64;
+        let address = cpu.x[f.rs1] as usize;
+        cpu.check_amo(address, 4)?;
+        cpu.x[f.rd] = memory.fetch_op_u32(address, AtomicOp::Min, cpu.x[f.rs2] as u32)? as i32 as i64;
         Ok(())
     }
 };
@@ -143,13 +116,9 @@ pub const AMOMINU_D: Instruction = Instruction {
     name: "AMOMINU.D",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_r(word);
-        let tmp = memory.read_u64(cpu.x[f.rs1] as usize)?;
-        let min = match (cpu.x[f.rs2] as u64) <= tmp {
-            true => cpu.x[f.rs2] as u64,
-            false => tmp as u64
-        };
-        memory.write_u64(cpu.x[f.rs1] as usize, min)?;
-        cpu.x[f.rd] = tmp as i64;
+        let address = cpu.x[f.rs1] as usize;
+        cpu.check_amo(address, 8)?;
+        cpu.x[f.rd] = memory.fetch_op_u64(address, AtomicOp::MinU, cpu.x[f.rs2] as u64)? as i64;
         Ok(())
     }
 };
@@ -158,13 +127,9 @@ pub const AMOMINU_W: Instruction = Instruction {
     name: "AMOMINU.W",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_r(word);
-        let tmp = memory.read_u32(cpu.x[f.rs1] as usize)?;
-        let min = match (cpu.x[f.rs2] as u32) <= tmp {
-            true => cpu.x[f.rs2] as u32,
-            false => tmp as u32
-        };
-        memory.write_u32(cpu.x[f.rs1] as usize, min)?;
-        cpu.x[f.rd] = tmp as i32 as i64;
+        let address = cpu.x[f.rs1] as usize;
+        cpu.check_amo(address, 4)?;
+        cpu.x[f.rd] = memory.fetch_op_u32(address, AtomicOp::MinU, cpu.x[f.rs2] as u32)? as i32 as i64;
         Ok(())
     }
 };
@@ -173,9 +138,9 @@ pub const AMOOR_D: Instruction = Instruction {
     name: "AMOOR.D",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_r(word);
-        let tmp = memory.read_u64(cpu.x[f.rs1] as usize)?;
-        memory.write_u64(cpu.x[f.rs1] as usize, (cpu.x[f.rs2] as u64) | tmp)?;
-        cpu.x[f.rd] = tmp as i64;
+        let address = cpu.x[f.rs1] as usize;
+        cpu.check_amo(address, 8)?;
+        cpu.x[f.rd] = memory.fetch_op_u64(address, AtomicOp::Or, cpu.x[f.rs2] as u64)? as i64;
         Ok(())
     }
 };
@@ -184,9 +149,9 @@ pub const AMOOR_W: Instruction = Instruction {
     name: "AMOOR.W",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_r(word);
-        let tmp = memory.read_u32(cpu.x[f.rs1] as usize)?;
-        memory.write_u32(cpu.x[f.rs1] as usize, (cpu.x[f.rs2] as u32) | tmp)?;
-        cpu.x[f.rd] = tmp as i32 as i64;
+        let address = cpu.x[f.rs1] as usize;
+        cpu.check_amo(address, 4)?;
+        cpu.x[f.rd] = memory.fetch_op_u32(address, AtomicOp::Or, cpu.x[f.rs2] as u32)? as i32 as i64;
         Ok(())
     }
 };
@@ -195,9 +160,9 @@ pub const AMOSWAP_D: Instruction = Instruction {
     name: "AMOSWAP.D",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_r(word);
-        let tmp = memory.read_u64(cpu.x[f.rs1] as usize)?;
-        memory.write_u64(cpu.x[f.rs1] as usize, cpu.x[f.rs2] as u64)?;
-        cpu.x[f.rd] = tmp as i64;
+        let address = cpu.x[f.rs1] as usize;
+        cpu.check_amo(address, 8)?;
+        cpu.x[f.rd] = memory.fetch_op_u64(address, AtomicOp::Swap, cpu.x[f.rs2] as u64)? as i64;
         Ok(())
     }
 };
@@ -206,9 +171,9 @@ pub const AMOSWAP_W: Instruction = Instruction {
     name: "AMOSWAP.W",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_r(word);
-        let tmp = memory.read_u32(cpu.x[f.rs1] as usize)?;
-        memory.write_u32(cpu.x[f.rs1] as usize, cpu.x[f.rs2] as u32)?;
-        cpu.x[f.rd] = tmp as i32 as i64;
+        let address = cpu.x[f.rs1] as usize;
+        cpu.check_amo(address, 4)?;
+        cpu.x[f.rd] = memory.fetch_op_u32(address, AtomicOp::Swap, cpu.x[f.rs2] as u32)? as i32 as i64;
         Ok(())
     }
 };
@@ -217,9 +182,9 @@ pub const AMOXOR_D: Instruction = Instruction {
     name: "AMOXOR.D",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_r(word);
-        let tmp = memory.read_u64(cpu.x[f.rs1] as usize)?;
-        memory.write_u64(cpu.x[f.rs1] as usize, cpu.x[f.rs2] as u64 ^ tmp)?;
-        cpu.x[f.rd] = tmp as i64;
+        let address = cpu.x[f.rs1] as usize;
+        cpu.check_amo(address, 8)?;
+        cpu.x[f.rd] = memory.fetch_op_u64(address, AtomicOp::Xor, cpu.x[f.rs2] as u64)? as i64;
         Ok(())
     }
 };
@@ -228,9 +193,9 @@ pub const AMOXOR_W: Instruction = Instruction {
     name: "AMOXOR.W",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_r(word);
-        let tmp = memory.read_u32(cpu.x[f.rs1] as usize)?;
-        memory.write_u32(cpu.x[f.rs1] as usize, cpu.x[f.rs2] as u32 ^ tmp)?;
-        cpu.x[f.rd] = tmp as i32 as i64;
+        let address = cpu.x[f.rs1] as usize;
+        cpu.check_amo(address, 4)?;
+        cpu.x[f.rd] = memory.fetch_op_u32(address, AtomicOp::Xor, cpu.x[f.rs2] as u32)? as i32 as i64;
         Ok(())
     }
 };
@@ -239,10 +204,12 @@ pub const LR_D: Instruction = Instruction {
     name: "LR.D",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_r(word);
-        // @TODO: Implement properly
-        cpu.x[f.rd] = memory.read_i64(cpu.x[f.rs1] as usize)?;
+        let address = cpu.x[f.rs1] as usize;
+        cpu.check_lr(address, 8)?;
+        cpu.x[f.rd] = memory.read_i64(address)?;
         cpu.is_reservation_set = true;
-        cpu.reservation = cpu.x[f.rs1] as u64; // Is virtual address ok?
+        cpu.reservation = address as u64; // Is virtual address ok?
+        cpu.reservation_value = cpu.x[f.rd] as u64;
         Ok(())
     }
 };
@@ -251,27 +218,33 @@ pub const LR_W: Instruction = Instruction {
     name: "LR.W",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_r(word);
-        // @TODO: Implement properly
-        cpu.x[f.rd] = memory.read_u32(cpu.x[f.rs1] as usize)? as i64;
+        let address = cpu.x[f.rs1] as usize;
+        cpu.check_lr(address, 4)?;
+        let value = memory.read_u32(address)?;
+        cpu.x[f.rd] = value as i32 as i64;
         cpu.is_reservation_set = true;
-        cpu.reservation = cpu.x[f.rs1] as u64; // Is virtual address ok?
+        cpu.reservation = address as u64; // Is virtual address ok?
+        cpu.reservation_value = value as u64;
         Ok(())
     }
 };
 
+// SC is a compare and exchange against the value LR saw, so it fails if anything with access to the
+// memory has changed that value in the meantime
 pub const SC_D: Instruction = Instruction {
     name: "SC.D",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_r(word);
-        // @TODO: Implement properly
-        cpu.x[f.rd] = match cpu.is_reservation_set && cpu.reservation == (cpu.x[f.rs1] as u64) {
+        let address = cpu.x[f.rs1] as usize;
+        cpu.check_amo(address, 8)?;
+        cpu.x[f.rd] = match cpu.is_reservation_set && cpu.reservation == (address as u64) {
             true => {
-                memory.write_u64(cpu.x[f.rs1] as usize, cpu.x[f.rs2] as u64)?;
-                cpu.is_reservation_set = false;
-                0
+                let current = memory.compare_exchange_u64(address, cpu.reservation_value, cpu.x[f.rs2] as u64)?;
+                (current != cpu.reservation_value) as i64
             },
             false => 1
         };
+        cpu.is_reservation_set = false;
         Ok(())
     }
 };
@@ -280,15 +253,17 @@ pub const SC_W: Instruction = Instruction {
     name: "SC.W",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_r(word);
-        // @TODO: Implement properly
-        cpu.x[f.rd] = match cpu.is_reservation_set && cpu.reservation == (cpu.x[f.rs1] as u64) {
+        let address = cpu.x[f.rs1] as usize;
+        cpu.check_amo(address, 4)?;
+        cpu.x[f.rd] = match cpu.is_reservation_set && cpu.reservation == (address as u64) {
             true => {
-                memory.write_u32(cpu.x[f.rs1] as usize, cpu.x[f.rs2] as u32)?;
-                cpu.is_reservation_set = false;
-                0
+                let expected = cpu.reservation_value as u32;
+                let current = memory.compare_exchange_u32(address, expected, cpu.x[f.rs2] as u32)?;
+                (current != expected) as i64
             },
             false => 1
         };
+        cpu.is_reservation_set = false;
         Ok(())
     }
 };
@@ -235,7 +235,9 @@ pub const FSD: Instruction = Instruction {
     name: "FSD",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_s(word);
-        memory.write_u64(cpu.x[f.rs1].wrapping_add(f.imm) as usize, cpu.f[f.rs2].to_bits())
+        let address = cpu.x[f.rs1].wrapping_add(f.imm) as usize;
+        cpu.check_store(address, 8)?;
+        memory.write_u64(address, cpu.f[f.rs2].to_bits())
     }
 };
 
@@ -243,7 +245,9 @@ pub const FLD: Instruction = Instruction {
     name: "FLD",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_i(word);
-        cpu.f[f.rd] = f64::from_bits(memory.read_u64(cpu.x[f.rs1].wrapping_add(f.imm) as usize)?);
+        let address = cpu.x[f.rs1].wrapping_add(f.imm) as usize;
+        cpu.check_load(address, 8)?;
+        cpu.f[f.rd] = f64::from_bits(memory.read_u64(address)?);
         Ok(())
     }
 };
@@ -68,7 +68,9 @@ pub const FLW: Instruction = Instruction {
     name: "FLW",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_i(word);
-        let value = f32::from_bits(memory.read_u32(cpu.x[f.rs1].wrapping_add(f.imm) as usize)?);
+        let address = cpu.x[f.rs1].wrapping_add(f.imm) as usize;
+        cpu.check_load(address, 4)?;
+        let value = f32::from_bits(memory.read_u32(address)?);
         cpu.set_f32(f.rd, value);
         Ok(())
     }
@@ -114,7 +116,9 @@ pub const FSW: Instruction = Instruction {
     name: "FSW",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_s(word);
-        memory.write_u32(cpu.x[f.rs1].wrapping_add(f.imm) as usize, cpu.f[f.rs2].to_bits() as u32)
+        let address = cpu.x[f.rs1].wrapping_add(f.imm) as usize;
+        cpu.check_store(address, 4)?;
+        memory.write_u32(address, cpu.f[f.rs2].to_bits() as u32)
     }
 };
 
@@ -470,4 +474,4 @@ pub const FNMSUB_S: Instruction = Instruction {
         cpu.set_f32(f.rd, v);
         Ok(())
     }
-};
\ No newline at end of file
+};
\ No newline at end of file
@@ -1,4 +1,4 @@
-use crate::cpu::{instruction, Xlen};
+use crate::cpu::{instruction, Trap, TrapType, Xlen};
 use crate::cpu::instruction::Instruction;
 
 pub const ADD: Instruction = Instruction {
@@ -69,7 +69,7 @@ pub const BEQ: Instruction = Instruction {
     operation: |cpu, _memory, word, address| {
         let f = instruction::parse_format_b(word);
         if cpu.sign_extend(cpu.x[f.rs1]) == cpu.sign_extend(cpu.x[f.rs2]) {
-            cpu.pc = address.wrapping_add(f.imm as usize);
+            cpu.jump(address.wrapping_add(f.imm as usize))?;
         }
         Ok(())
     }
@@ -80,7 +80,7 @@ pub const BGE: Instruction = Instruction {
     operation: |cpu, _memory, word, address| {
         let f = instruction::parse_format_b(word);
         if cpu.sign_extend(cpu.x[f.rs1]) >= cpu.sign_extend(cpu.x[f.rs2]) {
-            cpu.pc = address.wrapping_add(f.imm as usize);
+            cpu.jump(address.wrapping_add(f.imm as usize))?;
         }
         Ok(())
     }
@@ -91,7 +91,7 @@ pub const BGEU: Instruction = Instruction {
     operation: |cpu, _memory, word, address| {
         let f = instruction::parse_format_b(word);
         if cpu.unsigned_data(cpu.x[f.rs1]) >= cpu.unsigned_data(cpu.x[f.rs2]) {
-            cpu.pc = address.wrapping_add(f.imm as usize);
+            cpu.jump(address.wrapping_add(f.imm as usize))?;
         }
         Ok(())
     }
@@ -102,7 +102,7 @@ pub const BLT: Instruction = Instruction {
     operation: |cpu, _memory, word, address| {
         let f = instruction::parse_format_b(word);
         if cpu.sign_extend(cpu.x[f.rs1]) < cpu.sign_extend(cpu.x[f.rs2]) {
-            cpu.pc = address.wrapping_add(f.imm as usize);
+            cpu.jump(address.wrapping_add(f.imm as usize))?;
         }
         Ok(())
     }
@@ -113,7 +113,7 @@ pub const BLTU: Instruction = Instruction {
     operation: |cpu, _memory, word, address| {
         let f = instruction::parse_format_b(word);
         if cpu.unsigned_data(cpu.x[f.rs1]) < cpu.unsigned_data(cpu.x[f.rs2]) {
-            cpu.pc = address.wrapping_add(f.imm as usize);
+            cpu.jump(address.wrapping_add(f.imm as usize))?;
         }
         Ok(())
     }
@@ -124,7 +124,7 @@ pub const BNE: Instruction = Instruction {
     operation: |cpu, _memory, word, address| {
         let f = instruction::parse_format_b(word);
         if cpu.sign_extend(cpu.x[f.rs1]) != cpu.sign_extend(cpu.x[f.rs2]) {
-            cpu.pc = address.wrapping_add(f.imm as usize);
+            cpu.jump(address.wrapping_add(f.imm as usize))?;
         }
         Ok(())
     }
@@ -143,8 +143,9 @@ pub const JAL: Instruction = Instruction {
     name: "JAL",
     operation: |cpu, _memory, word, address| {
         let f = instruction::parse_format_j(word);
-        cpu.x[f.rd] = cpu.sign_extend(cpu.pc as i64);
-        cpu.pc = address.wrapping_add(f.imm as usize);
+        let tmp = cpu.sign_extend(cpu.pc as i64);
+        cpu.jump(address.wrapping_add(f.imm as usize))?;
+        cpu.x[f.rd] = tmp;
         Ok(())
     }
 };
@@ -154,7 +155,7 @@ pub const JALR: Instruction = Instruction {
     operation: |cpu, _memory, word, _address| {
         let f = instruction::parse_format_i(word);
         let tmp = cpu.sign_extend(cpu.pc as i64);
-        cpu.pc = (cpu.x[f.rs1] as u64).wrapping_add(f.imm as u64) as usize;
+        cpu.jump((cpu.x[f.rs1] as u64).wrapping_add(f.imm as u64) as usize & !1)?;
         cpu.x[f.rd] = tmp;
         Ok(())
     }
@@ -164,7 +165,9 @@ pub const LB: Instruction = Instruction {
     name: "LB",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_i(word);
-        cpu.x[f.rd] = memory.read_i8(cpu.x[f.rs1].wrapping_add(f.imm) as usize)? as i64;
+        let address = cpu.x[f.rs1].wrapping_add(f.imm) as usize;
+        cpu.check_load(address, 1)?;
+        cpu.x[f.rd] = memory.read_i8(address)? as i64;
         Ok(())
     }
 };
@@ -173,7 +176,9 @@ pub const LBU: Instruction = Instruction {
     name: "LBU",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_i(word);
-        cpu.x[f.rd] = memory.read_u8(cpu.x[f.rs1].wrapping_add(f.imm) as usize)? as i64;
+        let address = cpu.x[f.rs1].wrapping_add(f.imm) as usize;
+        cpu.check_load(address, 1)?;
+        cpu.x[f.rd] = memory.read_u8(address)? as i64;
         Ok(())
     }
 };
@@ -182,7 +187,9 @@ pub const LD: Instruction = Instruction {
     name: "LD",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_i(word);
-        cpu.x[f.rd] = memory.read_i64(cpu.x[f.rs1].wrapping_add(f.imm) as usize)?;
+        let address = cpu.x[f.rs1].wrapping_add(f.imm) as usize;
+        cpu.check_load(address, 8)?;
+        cpu.x[f.rd] = memory.read_i64(address)?;
         Ok(())
     }
 };
@@ -191,7 +198,9 @@ pub const LH: Instruction = Instruction {
     name: "LH",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_i(word);
-        cpu.x[f.rd] = memory.read_i16(cpu.x[f.rs1].wrapping_add(f.imm) as usize)? as i64;
+        let address = cpu.x[f.rs1].wrapping_add(f.imm) as usize;
+        cpu.check_load(address, 2)?;
+        cpu.x[f.rd] = memory.read_i16(address)? as i64;
         Ok(())
     }
 };
@@ -200,7 +209,9 @@ pub const LHU: Instruction = Instruction {
     name: "LHU",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_i(word);
-        cpu.x[f.rd] = memory.read_u16(cpu.x[f.rs1].wrapping_add(f.imm) as usize)? as i64;
+        let address = cpu.x[f.rs1].wrapping_add(f.imm) as usize;
+        cpu.check_load(address, 2)?;
+        cpu.x[f.rd] = memory.read_u16(address)? as i64;
         Ok(())
     }
 };
@@ -209,7 +220,9 @@ pub const LW: Instruction = Instruction {
     name: "LW",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_i(word);
-        cpu.x[f.rd] = memory.read_i32(cpu.x[f.rs1].wrapping_add(f.imm) as usize)? as i64;
+        let address = cpu.x[f.rs1].wrapping_add(f.imm) as usize;
+        cpu.check_load(address, 4)?;
+        cpu.x[f.rd] = memory.read_i32(address)? as i64;
         Ok(())
     }
 };
@@ -218,7 +231,9 @@ pub const LWU: Instruction = Instruction {
     name: "LWU",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_i(word);
-        cpu.x[f.rd] = memory.read_u32(cpu.x[f.rs1].wrapping_add(f.imm) as usize)? as i64;
+        let address = cpu.x[f.rs1].wrapping_add(f.imm) as usize;
+        cpu.check_load(address, 4)?;
+        cpu.x[f.rd] = memory.read_u32(address)? as i64;
         Ok(())
     }
 };
@@ -254,7 +269,9 @@ pub const SB: Instruction = Instruction {
     name: "SB",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_s(word);
-        memory.write_u8(cpu.x[f.rs1].wrapping_add(f.imm) as usize, cpu.x[f.rs2] as u8)
+        let address = cpu.x[f.rs1].wrapping_add(f.imm) as usize;
+        cpu.check_store(address, 1)?;
+        memory.write_u8(address, cpu.x[f.rs2] as u8)
     }
 };
 
@@ -262,7 +279,9 @@ pub const SD: Instruction = Instruction {
     name: "SD",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_s(word);
-        memory.write_u64(cpu.x[f.rs1].wrapping_add(f.imm) as usize, cpu.x[f.rs2] as u64)
+        let address = cpu.x[f.rs1].wrapping_add(f.imm) as usize;
+        cpu.check_store(address, 8)?;
+        memory.write_u64(address, cpu.x[f.rs2] as u64)
     }
 };
 
@@ -270,7 +289,9 @@ pub const SH: Instruction = Instruction {
     name: "SH",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_s(word);
-        memory.write_u16(cpu.x[f.rs1].wrapping_add(f.imm) as usize, cpu.x[f.rs2] as u16)
+        let address = cpu.x[f.rs1].wrapping_add(f.imm) as usize;
+        cpu.check_store(address, 2)?;
+        memory.write_u16(address, cpu.x[f.rs2] as u16)
     }
 };
 
@@ -475,7 +496,9 @@ pub const SW: Instruction = Instruction {
     name: "SW",
     operation: |cpu, memory, word, _address| {
         let f = instruction::parse_format_s(word);
-        memory.write_u32(cpu.x[f.rs1].wrapping_add(f.imm) as usize, cpu.x[f.rs2] as u32)
+        let address = cpu.x[f.rs1].wrapping_add(f.imm) as usize;
+        cpu.check_store(address, 4)?;
+        memory.write_u32(address, cpu.x[f.rs2] as u32)
     }
 };
 
@@ -569,9 +592,8 @@ pub const CSRRWI: Instruction = Instruction {
 
 pub const EBREAK: Instruction = Instruction {
     name: "EBREAK",
-    operation: |_cpu, _memory, _word, _address| {
-        // TODO: implement debugger?
-        Ok(())
+    operation: |_cpu, _memory, _word, address| {
+        Err(Trap { trap_type: TrapType::Breakpoint, value: address as u64 })
     }
 };
 
@@ -588,8 +610,8 @@ pub const ECALL: Instruction = Instruction {
 
 pub const FENCE_I: Instruction = Instruction {
     name: "FENCE.I",
-    operation: |_cpu, _memory, _word, _address| {
-        // Do nothing
+    operation: |cpu, _memory, _word, _address| {
+        cpu.flush_decode_cache();
         Ok(())
     }
 };
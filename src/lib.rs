@@ -7,6 +7,7 @@ mod test {
 
     use super::cpu::*;
     use super::cpu::instruction::Instruction;
+    use super::memory::*;
 
     use elfloader::*;
     use std::io::Write;
@@ -16,13 +17,16 @@ mod test {
 
     struct RVTestElfLoader<'a> {
         pub img_base: u64,
-        target: &'a mut [u8]
+        relocate: bool,
+        target: &'a mut dyn Memory
     }
 
     impl <'a> RVTestElfLoader<'a> {
-        pub fn new(target: &'a mut [u8]) -> Self {
+        // a relocating loader moves the image down to address 0
+        pub fn new(target: &'a mut dyn Memory, relocate: bool) -> Self {
             RVTestElfLoader {
                 img_base: u64::MAX,
+                relocate,
                 target
             }
         }
@@ -40,13 +44,16 @@ mod test {
         }
 
         fn load(&mut self, _flags: Flags, base: VAddr, region: &[u8]) -> Result<(), ElfLoaderErr> {
-            let start = base - self.img_base;
+            let start = match self.relocate {
+                true => base - self.img_base,
+                false => base
+            };
             let end = start + region.len() as u64;
 
             //println!("Loading region from {:#x} into {:?} with {:?} bytes", base, start, region.len());
-            if end < MAX_SIZE as u64 {
-                for i in 0..region.len() {
-                    self.target[start as usize + i] = region[i];
+            if !self.relocate || end < MAX_SIZE as u64 {
+                for (i, byte) in region.iter().enumerate() {
+                    self.target.write_u8(start as usize + i, *byte).map_err(|_| ElfLoaderErr::OutOfMemory)?;
                 }
 
                 Ok(())
@@ -79,14 +86,33 @@ mod test {
 
     }
 
+    #[derive(Clone, Copy, PartialEq)]
+    enum Mode {
+        Tick,
+        // tick with macro-op fusion turned on
+        Fused,
+        Blocks,
+        // blocks compiled to native code the first time they run
+        #[cfg_attr(not(feature = "jit"), allow(dead_code))]
+        Jit
+    }
+
     fn run_test(binary_blob: &[u8]) {
         let mut target: Vec<u8> = Vec::new();
         target.resize(MAX_SIZE + STACK_SIZE, 0);
 
+        run_test_in(binary_blob, &mut target, true, MAX_SIZE + STACK_SIZE - 1, Mode::Tick);
+    }
+
+    // generic so the tests run the same monomorphized code a host with a concrete Memory type would
+    fn run_test_in<M: AsMemory + ?Sized>(binary_blob: &[u8], target: &mut M, relocate: bool, stack_pointer: usize, mode: Mode) -> Cpu {
         let binary = ElfBinary::new(binary_blob).expect("Got proper ELF file");
-        let mut loader = RVTestElfLoader::new(&mut target);
+        let mut loader = RVTestElfLoader::new(target.as_memory(), relocate);
         binary.load(&mut loader).expect("Can't load the binary?");
-        let img_base = loader.img_base;
+        let img_base = match relocate {
+            true => loader.img_base,
+            false => 0
+        };
 
         let entry_point_offset = binary.entry_point() - img_base;
 
@@ -103,19 +129,35 @@ mod test {
         }));
 
         cpu.update_pc(entry_point_offset as usize);
-        cpu.update_stack_pointer(MAX_SIZE + STACK_SIZE - 1);
-        let mut fuel = 1_000_000_000;
+        cpu.set_stack(stack_pointer, STACK_SIZE, PAGE_SIZE);
+        // plain tick runs uncached, the way a Cpu does out of the box
+        cpu.set_decode_cache(mode != Mode::Tick);
+        cpu.set_macro_op_fusion(mode == Mode::Fused);
+        #[cfg(feature = "jit")]
+        cpu.set_jit_threshold(match mode {
+            Mode::Jit => Some(0),
+            _ => None
+        });
+        let mut fuel: u64 = 1_000_000_000;
 
         let dump_instructions = std::env::var("DUMP_INSTRUCTIONS").is_ok();
         let mut old_x = cpu.x.clone();
         let mut old_f = cpu.f.clone();
 
+        // run only uses tick for budgets shorter than a block, and splits a fused pair that would go
+        // past the budget, so fusion needs at least two at a time
+        let step = match (mode, dump_instructions) {
+            (Mode::Blocks | Mode::Jit, false) => fuel,
+            (Mode::Fused, false) => 2,
+            _ => 1
+        };
+
         loop {
             let pc = cpu.get_pc();
 
             if dump_instructions {
                 let saved = cpu.pc;
-                let op = cpu.fetch(&target).expect("instruction fetch failed");
+                let op = cpu.fetch(target).expect("instruction fetch failed");
                 let inst = Cpu::decode(op);
                 cpu.pc = saved;
 
@@ -150,34 +192,34 @@ mod test {
                 std::io::stdout().flush().expect("flush");
             }
 
-            match cpu.tick(&mut target) {
-                Ok(_) => {
-                    fuel = fuel - 1;
+            let (reason, retired) = cpu.run(target, step);
+            fuel = fuel.saturating_sub(retired);
+            match reason {
+                ExitReason::BudgetExhausted => {
                     if fuel == 0 {
                         panic!("out of fuel");
                     }
                 },
-                Err(e) => {
-                    match e.trap_type {
-                        TrapType::Stop => {
-                            if e.value != 0 {
-                                panic!("CPU test {:?} failed a0={:#x} a1={:#x} a2={:#x} a3={:#x} a4={:#x} t2={:#x}", e.value >> 1, cpu.get_register(Register::A0), cpu.get_register(Register::A1), cpu.get_register(Register::A2), cpu.get_register(Register::A3), cpu.get_register(Register::A4), cpu.get_register(Register::T2));
-                            } else {
-                                break;
-                            }
-                        },
-                        _ => panic!("CPU failure: pc = {:#x} - {:?}", pc, e)
-                    }
-                }
+                ExitReason::Exit(0) => break,
+                ExitReason::Exit(code) => {
+                    panic!("CPU test {:?} failed a0={:#x} a1={:#x} a2={:#x} a3={:#x} a4={:#x} t2={:#x}", code >> 1, cpu.get_register(Register::A0), cpu.get_register(Register::A1), cpu.get_register(Register::A2), cpu.get_register(Register::A3), cpu.get_register(Register::A4), cpu.get_register(Register::T2));
+                },
+                reason => panic!("CPU failure: {:?}", reason)
             }
         }
+
+        cpu
     }
 
+    // elfloader requires the ELF headers to be suitably aligned, which include_bytes! does not promise
+    #[repr(C, align(8))]
+    struct AlignedBlob<B: ?Sized>(B);
+
     macro_rules! rv_test {
         ( $bytes:literal ) => {
-            let binary_blob = include_bytes!($bytes);
+            static BINARY_BLOB: &AlignedBlob<[u8]> = &AlignedBlob(*include_bytes!($bytes));
 
-            run_test(binary_blob);
+            run_test(&BINARY_BLOB.0);
         }
     }
 
@@ -734,6 +776,364 @@ mod test {
         }
     }
 
+    mod paged {
+        use super::*;
+
+        // the riscv-tests are linked at 0x80000000, load them there and put the stack at the top of a 39 bit space
+        const STACK_TOP: usize = 0x3f_ffff_f000;
+
+        macro_rules! rv_paged_test {
+            ( $bytes:literal, $max_pages:expr ) => {
+                static BINARY_BLOB: &AlignedBlob<[u8]> = &AlignedBlob(*include_bytes!($bytes));
+
+                let mut memory = PagedMemory::new();
+                run_test_in(&BINARY_BLOB.0, &mut memory, false, STACK_TOP, Mode::Tick);
+                assert!(memory.page_count() <= $max_pages, "{} pages allocated", memory.page_count());
+            }
+        }
+
+        #[test]
+        fn rv64ui_p_sd() {
+            rv_paged_test!("../test/rv64ui-p-sd", 4);
+        }
+
+        #[test]
+        fn rv64ua_p_lrsc() {
+            rv_paged_test!("../test/rv64ua-p-lrsc", 4);
+        }
+
+        #[test]
+        fn mandelbrot() {
+            rv_paged_test!("../test/mandelbrot", 4);
+        }
+    }
+
+    // the same binaries run a basic block at a time instead of an instruction at a time
+    mod blocks {
+        use super::*;
+
+        macro_rules! rv_block_test {
+            ( $bytes:literal ) => {
+                static BINARY_BLOB: &AlignedBlob<[u8]> = &AlignedBlob(*include_bytes!($bytes));
+
+                let mut target: Vec<u8> = vec![0; MAX_SIZE + STACK_SIZE];
+                run_test_in(&BINARY_BLOB.0, &mut target, true, MAX_SIZE + STACK_SIZE - 1, Mode::Blocks);
+            }
+        }
+
+        #[test]
+        fn rv64ui_p_add() {
+            rv_block_test!("../test/rv64ui-p-add");
+        }
+
+        #[test]
+        fn rv64ui_p_addiw() {
+            rv_block_test!("../test/rv64ui-p-addiw");
+        }
+
+        #[test]
+        fn rv64ui_p_auipc() {
+            rv_block_test!("../test/rv64ui-p-auipc");
+        }
+
+        #[test]
+        fn rv64ui_p_bgeu() {
+            rv_block_test!("../test/rv64ui-p-bgeu");
+        }
+
+        #[test]
+        fn rv64ui_p_blt() {
+            rv_block_test!("../test/rv64ui-p-blt");
+        }
+
+        #[test]
+        fn rv64ui_p_fence_i() {
+            rv_block_test!("../test/rv64ui-p-fence_i");
+        }
+
+        #[test]
+        fn rv64ui_p_jal() {
+            rv_block_test!("../test/rv64ui-p-jal");
+        }
+
+        #[test]
+        fn rv64ui_p_jalr() {
+            rv_block_test!("../test/rv64ui-p-jalr");
+        }
+
+        #[test]
+        fn rv64ui_p_lb() {
+            rv_block_test!("../test/rv64ui-p-lb");
+        }
+
+        #[test]
+        fn rv64ui_p_lwu() {
+            rv_block_test!("../test/rv64ui-p-lwu");
+        }
+
+        #[test]
+        fn rv64ui_p_sd() {
+            rv_block_test!("../test/rv64ui-p-sd");
+        }
+
+        #[test]
+        fn rv64ui_p_sltiu() {
+            rv_block_test!("../test/rv64ui-p-sltiu");
+        }
+
+        #[test]
+        fn rv64ui_p_sra() {
+            rv_block_test!("../test/rv64ui-p-sra");
+        }
+
+        #[test]
+        fn rv64ui_p_srli() {
+            rv_block_test!("../test/rv64ui-p-srli");
+        }
+
+        #[test]
+        fn rv64ui_p_subw() {
+            rv_block_test!("../test/rv64ui-p-subw");
+        }
+
+        #[test]
+        fn rv64ua_p_lrsc() {
+            rv_block_test!("../test/rv64ua-p-lrsc");
+        }
+
+        #[test]
+        fn rv64uc_p_rvc() {
+            rv_block_test!("../test/rv64uc-p-rvc");
+        }
+
+        #[test]
+        fn rv64ud_p_fmadd() {
+            rv_block_test!("../test/rv64ud-p-fmadd");
+        }
+
+        #[test]
+        fn mandelbrot() {
+            rv_block_test!("../test/mandelbrot");
+        }
+
+        #[test]
+        fn mandelbrot_debug() {
+            rv_block_test!("../test/mandelbrot-debug");
+        }
+    }
+
+    // the block tests once more, with every block compiled
+    #[cfg(feature = "jit")]
+    mod jit {
+        use super::*;
+
+        macro_rules! rv_jit_test {
+            ( $bytes:literal ) => {
+                static BINARY_BLOB: &AlignedBlob<[u8]> = &AlignedBlob(*include_bytes!($bytes));
+
+                let mut target: Vec<u8> = vec![0; MAX_SIZE + STACK_SIZE];
+                run_test_in(&BINARY_BLOB.0, &mut target, true, MAX_SIZE + STACK_SIZE - 1, Mode::Jit);
+            }
+        }
+
+        #[test]
+        fn rv64ui_p_add() {
+            rv_jit_test!("../test/rv64ui-p-add");
+        }
+
+        #[test]
+        fn rv64ui_p_addiw() {
+            rv_jit_test!("../test/rv64ui-p-addiw");
+        }
+
+        #[test]
+        fn rv64ui_p_auipc() {
+            rv_jit_test!("../test/rv64ui-p-auipc");
+        }
+
+        #[test]
+        fn rv64ui_p_bgeu() {
+            rv_jit_test!("../test/rv64ui-p-bgeu");
+        }
+
+        #[test]
+        fn rv64ui_p_blt() {
+            rv_jit_test!("../test/rv64ui-p-blt");
+        }
+
+        #[test]
+        fn rv64ui_p_fence_i() {
+            rv_jit_test!("../test/rv64ui-p-fence_i");
+        }
+
+        #[test]
+        fn rv64ui_p_jal() {
+            rv_jit_test!("../test/rv64ui-p-jal");
+        }
+
+        #[test]
+        fn rv64ui_p_jalr() {
+            rv_jit_test!("../test/rv64ui-p-jalr");
+        }
+
+        #[test]
+        fn rv64ui_p_lb() {
+            rv_jit_test!("../test/rv64ui-p-lb");
+        }
+
+        #[test]
+        fn rv64ui_p_lwu() {
+            rv_jit_test!("../test/rv64ui-p-lwu");
+        }
+
+        #[test]
+        fn rv64ui_p_sd() {
+            rv_jit_test!("../test/rv64ui-p-sd");
+        }
+
+        #[test]
+        fn rv64ui_p_sltiu() {
+            rv_jit_test!("../test/rv64ui-p-sltiu");
+        }
+
+        #[test]
+        fn rv64ui_p_sra() {
+            rv_jit_test!("../test/rv64ui-p-sra");
+        }
+
+        #[test]
+        fn rv64ui_p_srli() {
+            rv_jit_test!("../test/rv64ui-p-srli");
+        }
+
+        #[test]
+        fn rv64ui_p_subw() {
+            rv_jit_test!("../test/rv64ui-p-subw");
+        }
+
+        #[test]
+        fn rv64ua_p_lrsc() {
+            rv_jit_test!("../test/rv64ua-p-lrsc");
+        }
+
+        #[test]
+        fn rv64uc_p_rvc() {
+            rv_jit_test!("../test/rv64uc-p-rvc");
+        }
+
+        #[test]
+        fn rv64um_p_mul() {
+            rv_jit_test!("../test/rv64um-p-mul");
+        }
+
+        #[test]
+        fn rv64um_p_mulw() {
+            rv_jit_test!("../test/rv64um-p-mulw");
+        }
+
+        #[test]
+        fn rv64ud_p_fadd() {
+            rv_jit_test!("../test/rv64ud-p-fadd");
+        }
+
+        #[test]
+        fn rv64ud_p_fmadd() {
+            rv_jit_test!("../test/rv64ud-p-fmadd");
+        }
+
+        #[test]
+        fn rv64ud_p_fcvt() {
+            rv_jit_test!("../test/rv64ud-p-fcvt");
+        }
+
+        #[test]
+        fn rv64ud_p_ldst() {
+            rv_jit_test!("../test/rv64ud-p-ldst");
+        }
+
+        #[test]
+        fn mandelbrot() {
+            rv_jit_test!("../test/mandelbrot");
+        }
+
+        #[test]
+        fn mandelbrot_debug() {
+            rv_jit_test!("../test/mandelbrot-debug");
+        }
+    }
+
+    // and again with tick fusing instruction pairs, each kind of pair listed has to fuse at least once
+    mod fused {
+        use super::*;
+
+        macro_rules! rv_fused_test {
+            ( $bytes:literal $(, $pair:ident)* ) => {
+                static BINARY_BLOB: &AlignedBlob<[u8]> = &AlignedBlob(*include_bytes!($bytes));
+
+                let mut target: Vec<u8> = vec![0; MAX_SIZE + STACK_SIZE];
+                // unused by the binaries that fuse nothing
+                #[allow(unused_variables)]
+                let counts = run_test_in(&BINARY_BLOB.0, &mut target, true, MAX_SIZE + STACK_SIZE - 1, Mode::Fused).fusion_counts();
+                $( assert!(counts.$pair > 0, "no {} pairs fused", stringify!($pair)); )*
+            }
+        }
+
+        #[test]
+        fn rv64ui_p_addiw() {
+            rv_fused_test!("../test/rv64ui-p-addiw", lui_addi);
+        }
+
+        #[test]
+        fn rv64ui_p_auipc() {
+            rv_fused_test!("../test/rv64ui-p-auipc", lui_addi);
+        }
+
+        #[test]
+        fn rv64ui_p_jalr() {
+            rv_fused_test!("../test/rv64ui-p-jalr");
+        }
+
+        #[test]
+        fn rv64ui_p_ld() {
+            rv_fused_test!("../test/rv64ui-p-ld", lui_addi);
+        }
+
+        #[test]
+        fn rv64ui_p_lui() {
+            rv_fused_test!("../test/rv64ui-p-lui");
+        }
+
+        #[test]
+        fn rv64ui_p_slli() {
+            rv_fused_test!("../test/rv64ui-p-slli", lui_addi);
+        }
+
+        #[test]
+        fn rv64ui_p_srli() {
+            rv_fused_test!("../test/rv64ui-p-srli", lui_addi);
+        }
+
+        #[test]
+        fn rv64uc_p_rvc() {
+            rv_fused_test!("../test/rv64uc-p-rvc", lui_addi);
+        }
+
+        #[test]
+        fn rv64ud_p_ldst() {
+            rv_fused_test!("../test/rv64ud-p-ldst");
+        }
+
+        #[test]
+        fn mandelbrot() {
+            rv_fused_test!("../test/mandelbrot");
+        }
+
+        #[test]
+        fn mandelbrot_debug() {
+            rv_fused_test!("../test/mandelbrot-debug", auipc_jalr, slli_srli);
+        }
+    }
+
     mod examples {
         use super::*;
 
@@ -1,23 +1,252 @@
 use crate::cpu::{Trap, TrapType};
 use std::convert::TryInto;
 
+mod checked;
+mod cow;
+mod demand;
+mod dirty;
+// guest structs only share the host's repr(C) layout on little endian 64 bit hosts
+#[cfg(all(target_endian = "little", target_pointer_width = "64"))]
+mod guest;
+mod heap;
+mod host;
+mod map;
+mod paged;
+mod trace;
+
+pub use checked::CheckedHostMemory;
+pub use cow::CowMemory;
+pub use demand::{DemandPagedMemory, PageResponse};
+pub use dirty::DirtyTrackingMemory;
+#[cfg(all(target_endian = "little", target_pointer_width = "64"))]
+pub use guest::{GuestPod, GuestPtr, GuestSlice};
+pub use heap::GuestHeap;
+pub use host::HostMemory;
+pub use map::{Device, MemoryMap};
+pub use paged::PagedMemory;
+pub use trace::{TraceRecord, TracingMemory};
+
+pub const PAGE_SIZE: usize = 4096;
+
 pub trait Memory {
-    fn read_i8(&self, address: usize) -> Result<i8, Trap>;
+    fn read_i8(&self, address: usize) -> Result<i8, Trap> {
+        self.read_u8(address).map(|value| value as i8)
+    }
     fn read_u8(&self, address: usize) -> Result<u8, Trap>;
-    fn read_i16(&self, address: usize) -> Result<i16, Trap>;
+    fn read_i16(&self, address: usize) -> Result<i16, Trap> {
+        self.read_u16(address).map(|value| value as i16)
+    }
     fn read_u16(&self, address: usize) -> Result<u16, Trap>;
-    fn read_i32(&self, address: usize) -> Result<i32, Trap>;
+    fn read_i32(&self, address: usize) -> Result<i32, Trap> {
+        self.read_u32(address).map(|value| value as i32)
+    }
     fn read_u32(&self, address: usize) -> Result<u32, Trap>;
-    fn read_i64(&self, address: usize) -> Result<i64, Trap>;
+    fn read_i64(&self, address: usize) -> Result<i64, Trap> {
+        self.read_u64(address).map(|value| value as i64)
+    }
     fn read_u64(&self, address: usize) -> Result<u64, Trap>;
 
     fn write_u8(&mut self, address: usize, value: u8) -> Result<(), Trap>;
     fn write_u16(&mut self, address: usize, value: u16) -> Result<(), Trap>;
     fn write_u32(&mut self, address: usize, value: u32) -> Result<(), Trap>;
     fn write_u64(&mut self, address: usize, value: u64) -> Result<(), Trap>;
+
+    // Instruction fetches are kept apart from data loads so implementations can enforce execute permissions
+    fn fetch_u32(&self, address: usize) -> Result<u32, Trap> {
+        self.read_u32(address).map_err(|trap| match trap.trap_type {
+            TrapType::LoadAccessFault => access_fault(Access::Execute, address),
+            _ => trap
+        })
+    }
+
+    // The atomic operations all return the value that was in memory before the operation. The
+    // defaults are a plain read then write, which is only atomic if nothing else can see the memory.
+    fn fetch_op_u32(&mut self, address: usize, op: AtomicOp, value: u32) -> Result<u32, Trap> {
+        let current = self.read_u32(address)?;
+        self.write_u32(address, op.apply_u32(current, value))?;
+        Ok(current)
+    }
+
+    fn fetch_op_u64(&mut self, address: usize, op: AtomicOp, value: u64) -> Result<u64, Trap> {
+        let current = self.read_u64(address)?;
+        self.write_u64(address, op.apply_u64(current, value))?;
+        Ok(current)
+    }
+
+    // new is only stored if memory holds expected
+    fn compare_exchange_u32(&mut self, address: usize, expected: u32, new: u32) -> Result<u32, Trap> {
+        let current = self.read_u32(address)?;
+        if current == expected {
+            self.write_u32(address, new)?;
+        }
+        Ok(current)
+    }
+
+    fn compare_exchange_u64(&mut self, address: usize, expected: u64, new: u64) -> Result<u64, Trap> {
+        let current = self.read_u64(address)?;
+        if current == expected {
+            self.write_u64(address, new)?;
+        }
+        Ok(current)
+    }
+
+    // Bulk accesses for host code moving buffers in and out of the guest. The defaults go a byte at
+    // a time, implementations that can check the whole range up front should override them.
+    fn read_bytes(&self, address: usize, buffer: &mut [u8]) -> Result<(), Trap> {
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte = self.read_u8(address.wrapping_add(i))?;
+        }
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, address: usize, bytes: &[u8]) -> Result<(), Trap> {
+        for (i, byte) in bytes.iter().enumerate() {
+            self.write_u8(address.wrapping_add(i), *byte)?;
+        }
+        Ok(())
+    }
+
+    fn fill(&mut self, address: usize, length: usize, value: u8) -> Result<(), Trap> {
+        for i in 0..length {
+            self.write_u8(address.wrapping_add(i), value)?;
+        }
+        Ok(())
+    }
+
+    // Reads a NUL terminated string of at most max_len bytes, the terminator is not included
+    fn read_cstr(&self, address: usize, max_len: usize) -> Result<Vec<u8>, Trap> {
+        let mut bytes = Vec::new();
+        while bytes.len() < max_len {
+            match self.read_u8(address.wrapping_add(bytes.len()))? {
+                0 => break,
+                byte => bytes.push(byte)
+            }
+        }
+        Ok(bytes)
+    }
+}
+
+// What Cpu::tick and friends are generic over. A concrete Memory type gets its own copy of the
+// execution loop with every load and store inlined, while dyn Memory keeps working as before. The
+// instruction tables still take dyn Memory, so both can hand themselves over to them.
+pub trait AsMemory: Memory {
+    fn as_memory(&mut self) -> &mut dyn Memory;
+}
+
+impl<M: Memory> AsMemory for M {
+    fn as_memory(&mut self) -> &mut dyn Memory {
+        self
+    }
+}
+
+impl AsMemory for dyn Memory + '_ {
+    fn as_memory(&mut self) -> &mut dyn Memory {
+        self
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AtomicOp {
+    Swap,
+    Add,
+    And,
+    Or,
+    Xor,
+    Min,
+    Max,
+    MinU,
+    MaxU
+}
+
+impl AtomicOp {
+    pub fn apply_u32(self, current: u32, value: u32) -> u32 {
+        match self {
+            AtomicOp::Swap => value,
+            AtomicOp::Add => current.wrapping_add(value),
+            AtomicOp::And => current & value,
+            AtomicOp::Or => current | value,
+            AtomicOp::Xor => current ^ value,
+            AtomicOp::Min => (current as i32).min(value as i32) as u32,
+            AtomicOp::Max => (current as i32).max(value as i32) as u32,
+            AtomicOp::MinU => current.min(value),
+            AtomicOp::MaxU => current.max(value)
+        }
+    }
+
+    pub fn apply_u64(self, current: u64, value: u64) -> u64 {
+        match self {
+            AtomicOp::Swap => value,
+            AtomicOp::Add => current.wrapping_add(value),
+            AtomicOp::And => current & value,
+            AtomicOp::Or => current | value,
+            AtomicOp::Xor => current ^ value,
+            AtomicOp::Min => (current as i64).min(value as i64) as u64,
+            AtomicOp::Max => (current as i64).max(value as i64) as u64,
+            AtomicOp::MinU => current.min(value),
+            AtomicOp::MaxU => current.max(value)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool
+}
+
+impl Permissions {
+    pub const NONE: Permissions = Permissions { read: false, write: false, execute: false };
+    pub const READ_ONLY: Permissions = Permissions { read: true, write: false, execute: false };
+    pub const READ_WRITE: Permissions = Permissions { read: true, write: true, execute: false };
+    pub const READ_EXECUTE: Permissions = Permissions { read: true, write: false, execute: true };
+    pub const ALL: Permissions = Permissions { read: true, write: true, execute: true };
+
+    pub fn allows(&self, access: Access) -> bool {
+        match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+            Access::Execute => self.execute
+        }
+    }
+}
+
+pub(crate) fn access_fault(access: Access, address: usize) -> Trap {
+    Trap {
+        trap_type: match access {
+            Access::Read => TrapType::LoadAccessFault,
+            Access::Write => TrapType::StoreAccessFault,
+            Access::Execute => TrapType::InstructionAccessFault
+        },
+        value: address as u64
+    }
+}
+
+// Splits address..address + length into the (address, length) spans that fall within each page
+pub(crate) fn page_spans(address: usize, length: usize) -> impl Iterator<Item = (usize, usize)> {
+    let mut address = address;
+    let mut remaining = length;
+
+    std::iter::from_fn(move || match remaining {
+        0 => None,
+        _ => {
+            let span = (address, remaining.min(PAGE_SIZE - address % PAGE_SIZE));
+            address = address.wrapping_add(span.1);
+            remaining -= span.1;
+            Some(span)
+        }
+    })
 }
 
 impl Memory for Vec<u8> {
+    #[inline]
     fn read_i8(&self, address: usize) -> Result<i8, Trap> {
         if address < self.len() {
             Ok(self[address] as i8)
@@ -29,6 +258,7 @@ impl Memory for Vec<u8> {
         }
     }
 
+    #[inline]
     fn read_u8(&self, address: usize) -> Result<u8, Trap> {
         if address < self.len() {
             Ok(self[address])
@@ -40,6 +270,7 @@ impl Memory for Vec<u8> {
         }
     }
 
+    #[inline]
     fn read_i16(&self, address: usize) -> Result<i16, Trap> {
         if address + 1 < self.len() {
             Ok(i16::from_le_bytes(self[address..address + 2].try_into().unwrap()))
@@ -51,6 +282,7 @@ impl Memory for Vec<u8> {
         }
     }
 
+    #[inline]
     fn read_u16(&self, address: usize) -> Result<u16, Trap> {
         if address + 1 < self.len() {
             Ok(u16::from_le_bytes(self[address..address + 2].try_into().unwrap()))
@@ -62,6 +294,7 @@ impl Memory for Vec<u8> {
         }
     }
 
+    #[inline]
     fn read_i32(&self, address: usize) -> Result<i32, Trap> {
         if address + 3 < self.len() {
             Ok(i32::from_le_bytes(self[address..address + 4].try_into().unwrap()))
@@ -73,6 +306,7 @@ impl Memory for Vec<u8> {
         }
     }
 
+    #[inline]
     fn read_u32(&self, address: usize) -> Result<u32, Trap> {
         if address + 3 < self.len() {
             Ok(u32::from_le_bytes(self[address..address + 4].try_into().unwrap()))
@@ -84,6 +318,7 @@ impl Memory for Vec<u8> {
         }
     }
 
+    #[inline]
     fn read_i64(&self, address: usize) -> Result<i64, Trap> {
         if address + 7 < self.len() {
             Ok(i64::from_le_bytes(self[address..address + 8].try_into().unwrap()))
@@ -95,6 +330,7 @@ impl Memory for Vec<u8> {
         }
     }
 
+    #[inline]
     fn read_u64(&self, address: usize) -> Result<u64, Trap> {
         if address + 7 < self.len() {
             Ok(u64::from_le_bytes(self[address..address + 8].try_into().unwrap()))
@@ -106,6 +342,7 @@ impl Memory for Vec<u8> {
         }
     }
 
+    #[inline]
     fn write_u8(&mut self, address: usize, value: u8) -> Result<(), Trap> {
         if address < self.len() {
             self[address] = value;
@@ -118,9 +355,10 @@ impl Memory for Vec<u8> {
         }
     }
 
+    #[inline]
     fn write_u16(&mut self, address: usize, value: u16) -> Result<(), Trap> {
         if address + 1 < self.len() {
-            self.splice(address..address+2, value.to_le_bytes());
+            self[address..address + 2].copy_from_slice(&value.to_le_bytes());
             Ok(())
         } else {
             Err(Trap{
@@ -130,9 +368,10 @@ impl Memory for Vec<u8> {
         }
     }
 
+    #[inline]
     fn write_u32(&mut self, address: usize, value: u32) -> Result<(), Trap> {
         if address + 3 < self.len() {
-            self.splice(address..address+4, value.to_le_bytes());
+            self[address..address + 4].copy_from_slice(&value.to_le_bytes());
             Ok(())
         } else {
             Err(Trap{
@@ -142,9 +381,10 @@ impl Memory for Vec<u8> {
         }
     }
 
+    #[inline]
     fn write_u64(&mut self, address: usize, value: u64) -> Result<(), Trap> {
         if address + 7 < self.len() {
-            self.splice(address..address+8, value.to_le_bytes());
+            self[address..address + 8].copy_from_slice(&value.to_le_bytes());
             Ok(())
         } else {
             Err(Trap{
@@ -153,4 +393,88 @@ impl Memory for Vec<u8> {
             })
         }
     }
+
+    fn read_bytes(&self, address: usize, buffer: &mut [u8]) -> Result<(), Trap> {
+        match address.checked_add(buffer.len()).and_then(|end| self.get(address..end)) {
+            Some(bytes) => {
+                buffer.copy_from_slice(bytes);
+                Ok(())
+            },
+            None => Err(access_fault(Access::Read, address))
+        }
+    }
+
+    fn write_bytes(&mut self, address: usize, bytes: &[u8]) -> Result<(), Trap> {
+        match address.checked_add(bytes.len()).and_then(|end| self.get_mut(address..end)) {
+            Some(target) => {
+                target.copy_from_slice(bytes);
+                Ok(())
+            },
+            None => Err(access_fault(Access::Write, address))
+        }
+    }
+
+    fn fill(&mut self, address: usize, length: usize, value: u8) -> Result<(), Trap> {
+        match address.checked_add(length).and_then(|end| self.get_mut(address..end)) {
+            Some(target) => {
+                target.fill(value);
+                Ok(())
+            },
+            None => Err(access_fault(Access::Write, address))
+        }
+    }
+
+    fn read_cstr(&self, address: usize, max_len: usize) -> Result<Vec<u8>, Trap> {
+        let bytes = match self.get(address..) {
+            Some(bytes) => &bytes[..bytes.len().min(max_len)],
+            None => return Err(access_fault(Access::Read, address))
+        };
+
+        match bytes.iter().position(|byte| *byte == 0) {
+            Some(length) => Ok(bytes[..length].to_vec()),
+            // the string ran off the end of memory before max_len or a terminator was reached
+            None if bytes.len() < max_len => Err(access_fault(Access::Read, self.len())),
+            None => Ok(bytes.to_vec())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_memory {
+    use super::*;
+
+    #[test]
+    fn bulk_accesses_check_the_whole_range() {
+        let mut memory: Vec<u8> = vec![0; 16];
+
+        memory.write_bytes(2, b"hello").unwrap();
+        memory.fill(8, 4, b'!').unwrap();
+        let mut buffer = [0; 10];
+        memory.read_bytes(2, &mut buffer).unwrap();
+        assert_eq!(&buffer, b"hello\0!!!!");
+
+        let e = memory.write_bytes(12, b"hello").unwrap_err();
+        assert!(matches!(e.trap_type, TrapType::StoreAccessFault));
+        assert_eq!(e.value, 12);
+        assert_eq!(memory[12..], [0; 4]);
+        assert!(memory.fill(usize::MAX, 2, 0).is_err());
+        assert!(memory.read_bytes(16, &mut []).is_ok());
+        assert!(memory.read_bytes(17, &mut []).is_err());
+    }
+
+    #[test]
+    fn cstr_stops_at_the_terminator() {
+        let mut memory: Vec<u8> = b"one\0three".to_vec();
+
+        assert_eq!(memory.read_cstr(0, 64).unwrap(), b"one");
+        assert_eq!(memory.read_cstr(4, 3).unwrap(), b"thr");
+        assert_eq!(memory.read_cstr(3, 64).unwrap(), b"");
+
+        let e = memory.read_cstr(4, 64).unwrap_err();
+        assert!(matches!(e.trap_type, TrapType::LoadAccessFault));
+        assert_eq!(e.value, 9);
+
+        memory.push(0);
+        assert_eq!(memory.read_cstr(4, 64).unwrap(), b"three");
+    }
 }
\ No newline at end of file
@@ -0,0 +1,281 @@
+use crate::cpu::Trap;
+use crate::memory::{access_fault, Access, AtomicOp, HostMemory, Memory, Permissions};
+
+struct HostRegion {
+    start: usize,
+    end: usize,
+    permissions: Permissions
+}
+
+// Shares the host address space like HostMemory, but only lets the guest touch host ranges that
+// have been registered. Anything else is reported as an access fault instead of being dereferenced.
+pub struct CheckedHostMemory {
+    host: HostMemory,
+    regions: Vec<HostRegion>
+}
+
+impl CheckedHostMemory {
+    pub fn new() -> Self {
+        CheckedHostMemory {
+            // only ever used for addresses that have passed a region check
+            host: unsafe { HostMemory::new() },
+            regions: Vec::new()
+        }
+    }
+
+    /// # Safety
+    ///
+    /// The guest will be allowed to dereference any address in `start..start + length`, so the
+    /// whole range must stay valid host memory until it is removed again.
+    pub unsafe fn add_region(&mut self, start: usize, length: usize, permissions: Permissions) {
+        self.regions.push(HostRegion {
+            start,
+            end: start.saturating_add(length),
+            permissions
+        });
+    }
+
+    pub fn remove_region(&mut self, start: usize) -> bool {
+        let count = self.regions.len();
+        self.regions.retain(|region| region.start != start);
+
+        count != self.regions.len()
+    }
+
+    // how far a single region lets the guest read from address, if at all
+    fn readable_length(&self, address: usize) -> Option<usize> {
+        self.regions.iter()
+            .filter(|region| region.permissions.allows(Access::Read) && region.start <= address && address < region.end)
+            .map(|region| region.end - address)
+            .max()
+    }
+
+    fn check(&self, address: usize, size: usize, access: Access) -> Result<(), Trap> {
+        let allowed = match address.checked_add(size) {
+            Some(end) => self.regions.iter().any(|region| {
+                region.permissions.allows(access) && region.start <= address && end <= region.end
+            }),
+            None => false
+        };
+
+        match allowed {
+            true => Ok(()),
+            false => Err(access_fault(access, address))
+        }
+    }
+}
+
+impl Default for CheckedHostMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Memory for CheckedHostMemory {
+    #[inline]
+    fn read_u8(&self, address: usize) -> Result<u8, Trap> {
+        self.check(address, 1, Access::Read)?;
+        self.host.read_u8(address)
+    }
+
+    #[inline]
+    fn read_u16(&self, address: usize) -> Result<u16, Trap> {
+        self.check(address, 2, Access::Read)?;
+        self.host.read_u16(address)
+    }
+
+    #[inline]
+    fn read_u32(&self, address: usize) -> Result<u32, Trap> {
+        self.check(address, 4, Access::Read)?;
+        self.host.read_u32(address)
+    }
+
+    #[inline]
+    fn read_u64(&self, address: usize) -> Result<u64, Trap> {
+        self.check(address, 8, Access::Read)?;
+        self.host.read_u64(address)
+    }
+
+    #[inline]
+    fn write_u8(&mut self, address: usize, value: u8) -> Result<(), Trap> {
+        self.check(address, 1, Access::Write)?;
+        self.host.write_u8(address, value)
+    }
+
+    #[inline]
+    fn write_u16(&mut self, address: usize, value: u16) -> Result<(), Trap> {
+        self.check(address, 2, Access::Write)?;
+        self.host.write_u16(address, value)
+    }
+
+    #[inline]
+    fn write_u32(&mut self, address: usize, value: u32) -> Result<(), Trap> {
+        self.check(address, 4, Access::Write)?;
+        self.host.write_u32(address, value)
+    }
+
+    #[inline]
+    fn write_u64(&mut self, address: usize, value: u64) -> Result<(), Trap> {
+        self.check(address, 8, Access::Write)?;
+        self.host.write_u64(address, value)
+    }
+
+    fn fetch_u32(&self, address: usize) -> Result<u32, Trap> {
+        self.check(address, 4, Access::Execute)?;
+        self.host.read_u32(address)
+    }
+
+    fn fetch_op_u32(&mut self, address: usize, op: AtomicOp, value: u32) -> Result<u32, Trap> {
+        self.check(address, 4, Access::Read)?;
+        self.check(address, 4, Access::Write)?;
+        self.host.fetch_op_u32(address, op, value)
+    }
+
+    fn fetch_op_u64(&mut self, address: usize, op: AtomicOp, value: u64) -> Result<u64, Trap> {
+        self.check(address, 8, Access::Read)?;
+        self.check(address, 8, Access::Write)?;
+        self.host.fetch_op_u64(address, op, value)
+    }
+
+    fn compare_exchange_u32(&mut self, address: usize, expected: u32, new: u32) -> Result<u32, Trap> {
+        self.check(address, 4, Access::Read)?;
+        self.check(address, 4, Access::Write)?;
+        self.host.compare_exchange_u32(address, expected, new)
+    }
+
+    fn compare_exchange_u64(&mut self, address: usize, expected: u64, new: u64) -> Result<u64, Trap> {
+        self.check(address, 8, Access::Read)?;
+        self.check(address, 8, Access::Write)?;
+        self.host.compare_exchange_u64(address, expected, new)
+    }
+
+    fn read_bytes(&self, address: usize, buffer: &mut [u8]) -> Result<(), Trap> {
+        self.check(address, buffer.len(), Access::Read)?;
+        self.host.read_bytes(address, buffer)
+    }
+
+    fn write_bytes(&mut self, address: usize, bytes: &[u8]) -> Result<(), Trap> {
+        self.check(address, bytes.len(), Access::Write)?;
+        self.host.write_bytes(address, bytes)
+    }
+
+    fn fill(&mut self, address: usize, length: usize, value: u8) -> Result<(), Trap> {
+        self.check(address, length, Access::Write)?;
+        self.host.fill(address, length, value)
+    }
+
+    fn read_cstr(&self, address: usize, max_len: usize) -> Result<Vec<u8>, Trap> {
+        let limit = match self.readable_length(address) {
+            Some(length) => length.min(max_len),
+            None => return Err(access_fault(Access::Read, address))
+        };
+
+        let bytes = self.host.read_cstr(address, limit)?;
+        match bytes.len() == limit && limit < max_len {
+            // ran out of registered memory before finding the terminator
+            true => Err(access_fault(Access::Read, address + limit)),
+            false => Ok(bytes)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_checked {
+    use super::*;
+    use crate::cpu::{Cpu, Register, TrapType};
+    use std::mem::size_of_val;
+
+    #[test]
+    fn rejects_unregistered_addresses() {
+        let mut memory = CheckedHostMemory::new();
+        let mut value: u64 = 7;
+        let address = &mut value as *mut u64 as usize;
+
+        unsafe { memory.add_region(address, 8, Permissions::READ_ONLY) };
+        assert_eq!(memory.read_u64(address).unwrap(), 7);
+
+        let e = memory.read_u64(address + 4).unwrap_err();
+        assert!(matches!(e.trap_type, TrapType::LoadAccessFault));
+        assert_eq!(e.value, (address + 4) as u64);
+
+        let e = memory.write_u8(address, 1).unwrap_err();
+        assert!(matches!(e.trap_type, TrapType::StoreAccessFault));
+        assert_eq!(e.value, address as u64);
+
+        let e = memory.fetch_op_u64(address, AtomicOp::Add, 1).unwrap_err();
+        assert!(matches!(e.trap_type, TrapType::StoreAccessFault));
+        assert_eq!(value, 7);
+
+        assert!(memory.remove_region(address));
+        assert!(memory.read_u8(address).is_err());
+        assert!(memory.read_u8(usize::MAX).is_err());
+    }
+
+    #[test]
+    fn bulk_accesses_stay_inside_regions() {
+        let mut memory = CheckedHostMemory::new();
+        let mut buffer = *b"name\0rest";
+        let address = buffer.as_mut_ptr() as usize;
+
+        unsafe { memory.add_region(address, 9, Permissions::READ_WRITE) };
+        assert_eq!(memory.read_cstr(address, 64).unwrap(), b"name");
+        let e = memory.read_cstr(address + 5, 64).unwrap_err();
+        assert!(matches!(e.trap_type, TrapType::LoadAccessFault));
+        assert_eq!(e.value, (address + 9) as u64);
+        assert_eq!(memory.read_cstr(address + 5, 4).unwrap(), b"rest");
+
+        let e = memory.write_bytes(address + 6, b"four").unwrap_err();
+        assert!(matches!(e.trap_type, TrapType::StoreAccessFault));
+        assert_eq!(e.value, (address + 6) as u64);
+        memory.fill(address, 4, b'x').unwrap();
+        assert_eq!(&buffer, b"xxxx\0rest");
+    }
+
+    #[test]
+    fn guest_faults_instead_of_crashing() {
+        let code: Vec<u32> = vec![
+            0x00053283, // ld t0, 0(a0)
+            0x00128293, // addi t0, t0, 1
+            0x00553023, // sd t0, 0(a0)
+            0x0005b283 // ld t0, 0(a1)
+        ];
+        let mut value: u64 = 1;
+
+        let mut memory = CheckedHostMemory::new();
+        unsafe {
+            memory.add_region(code.as_ptr() as usize, size_of_val(&code[..]), Permissions::READ_EXECUTE);
+            memory.add_region(&mut value as *mut u64 as usize, 8, Permissions::READ_WRITE);
+        }
+
+        let mut cpu = Cpu::new();
+        cpu.update_pc(code.as_ptr() as usize);
+        cpu.set_register(Register::A0, &mut value as *mut u64 as i64);
+        cpu.set_register(Register::A1, 0x10);
+
+        for _ in 0..3 {
+            cpu.tick(&mut memory).expect("cpu failure");
+        }
+
+        let e = cpu.tick(&mut memory).unwrap_err();
+        assert!(matches!(e.trap_type, TrapType::LoadAccessFault));
+        assert_eq!(e.value, 0x10);
+        assert_eq!(value, 2);
+    }
+
+    #[test]
+    fn guest_cannot_execute_data() {
+        let code: Vec<u32> = vec![
+            0x00128293 // addi t0, t0, 1
+        ];
+
+        let mut memory = CheckedHostMemory::new();
+        unsafe { memory.add_region(code.as_ptr() as usize, 4, Permissions::READ_WRITE) };
+
+        let mut cpu = Cpu::new();
+        cpu.update_pc(code.as_ptr() as usize);
+        let e = cpu.tick(&mut memory).unwrap_err();
+        assert!(matches!(e.trap_type, TrapType::InstructionAccessFault));
+        assert_eq!(e.value, code.as_ptr() as u64);
+        assert_eq!(memory.read_u32(code.as_ptr() as usize).unwrap(), 0x00128293);
+    }
+}
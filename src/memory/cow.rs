@@ -0,0 +1,295 @@
+use crate::cpu::Trap;
+use crate::memory::{page_spans, Memory, PAGE_SIZE};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+type Page = [u8; PAGE_SIZE];
+
+// Paged memory that can be forked cheaply. Everything written before a fork is frozen and shared
+// with the children, a shared page is only copied the first time it is written to afterwards.
+pub struct CowMemory {
+    shared: Arc<HashMap<usize, Arc<Page>>>,
+    pages: HashMap<usize, Box<Page>>
+}
+
+impl CowMemory {
+    pub fn new() -> Self {
+        CowMemory {
+            shared: Arc::new(HashMap::new()),
+            pages: HashMap::new()
+        }
+    }
+
+    // Freezes the current contents and returns a child that starts from them. This memory carries
+    // on from the same frozen pages, so both it and the child can be reset back to this point.
+    pub fn fork(&mut self) -> CowMemory {
+        if !self.pages.is_empty() {
+            // only copies the page table if an earlier fork is still using it
+            let shared = Arc::make_mut(&mut self.shared);
+            for (number, page) in self.pages.drain() {
+                shared.insert(number, Arc::from(page));
+            }
+        }
+
+        CowMemory {
+            shared: self.shared.clone(),
+            pages: HashMap::new()
+        }
+    }
+
+    // throws away everything written since the last fork
+    pub fn reset_to_parent(&mut self) {
+        self.pages.clear();
+    }
+
+    // the number of pages that have been copied or allocated since the last fork
+    pub fn page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    fn page(&self, number: usize) -> Option<&Page> {
+        match self.pages.get(&number) {
+            Some(page) => Some(page),
+            None => self.shared.get(&number).map(|page| page.as_ref())
+        }
+    }
+
+    fn page_mut(&mut self, address: usize) -> &mut Page {
+        let number = address / PAGE_SIZE;
+        let shared = &self.shared;
+
+        self.pages.entry(number).or_insert_with(|| match shared.get(&number) {
+            Some(page) => Box::new(**page),
+            None => Box::new([0; PAGE_SIZE])
+        })
+    }
+
+    fn read<const N: usize>(&self, address: usize) -> [u8; N] {
+        let mut bytes = [0; N];
+        let offset = address % PAGE_SIZE;
+
+        if offset + N <= PAGE_SIZE {
+            if let Some(page) = self.page(address / PAGE_SIZE) {
+                bytes.copy_from_slice(&page[offset..offset + N]);
+            }
+        } else {
+            self.copy_out(address, &mut bytes);
+        }
+
+        bytes
+    }
+
+    fn write<const N: usize>(&mut self, address: usize, bytes: [u8; N]) {
+        let offset = address % PAGE_SIZE;
+
+        if offset + N <= PAGE_SIZE {
+            self.page_mut(address)[offset..offset + N].copy_from_slice(&bytes);
+        } else {
+            self.copy_in(address, &bytes);
+        }
+    }
+
+    fn copy_out(&self, address: usize, buffer: &mut [u8]) {
+        let mut done = 0;
+        for (address, length) in page_spans(address, buffer.len()) {
+            let target = &mut buffer[done..done + length];
+            match self.page(address / PAGE_SIZE) {
+                Some(page) => target.copy_from_slice(&page[address % PAGE_SIZE..][..length]),
+                None => target.fill(0)
+            }
+            done += length;
+        }
+    }
+
+    fn copy_in(&mut self, address: usize, bytes: &[u8]) {
+        let mut done = 0;
+        for (address, length) in page_spans(address, bytes.len()) {
+            self.page_mut(address)[address % PAGE_SIZE..][..length].copy_from_slice(&bytes[done..done + length]);
+            done += length;
+        }
+    }
+}
+
+impl Default for CowMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Memory for CowMemory {
+    fn read_u8(&self, address: usize) -> Result<u8, Trap> {
+        Ok(self.read::<1>(address)[0])
+    }
+
+    fn read_u16(&self, address: usize) -> Result<u16, Trap> {
+        Ok(u16::from_le_bytes(self.read(address)))
+    }
+
+    fn read_u32(&self, address: usize) -> Result<u32, Trap> {
+        Ok(u32::from_le_bytes(self.read(address)))
+    }
+
+    fn read_u64(&self, address: usize) -> Result<u64, Trap> {
+        Ok(u64::from_le_bytes(self.read(address)))
+    }
+
+    fn write_u8(&mut self, address: usize, value: u8) -> Result<(), Trap> {
+        self.write(address, [value]);
+        Ok(())
+    }
+
+    fn write_u16(&mut self, address: usize, value: u16) -> Result<(), Trap> {
+        self.write(address, value.to_le_bytes());
+        Ok(())
+    }
+
+    fn write_u32(&mut self, address: usize, value: u32) -> Result<(), Trap> {
+        self.write(address, value.to_le_bytes());
+        Ok(())
+    }
+
+    fn write_u64(&mut self, address: usize, value: u64) -> Result<(), Trap> {
+        self.write(address, value.to_le_bytes());
+        Ok(())
+    }
+
+    fn read_bytes(&self, address: usize, buffer: &mut [u8]) -> Result<(), Trap> {
+        self.copy_out(address, buffer);
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, address: usize, bytes: &[u8]) -> Result<(), Trap> {
+        self.copy_in(address, bytes);
+        Ok(())
+    }
+
+    fn fill(&mut self, address: usize, length: usize, value: u8) -> Result<(), Trap> {
+        for (address, length) in page_spans(address, length) {
+            // zeroing a page that exists nowhere would only allocate it
+            if value != 0 || self.page(address / PAGE_SIZE).is_some() {
+                self.page_mut(address)[address % PAGE_SIZE..][..length].fill(value);
+            }
+        }
+        Ok(())
+    }
+
+    fn read_cstr(&self, address: usize, max_len: usize) -> Result<Vec<u8>, Trap> {
+        let mut bytes = Vec::new();
+        for (address, length) in page_spans(address, max_len) {
+            let page = match self.page(address / PAGE_SIZE) {
+                Some(page) => &page[address % PAGE_SIZE..][..length],
+                // a page that exists nowhere reads as zero, so the string ends here
+                None => break
+            };
+
+            match page.iter().position(|byte| *byte == 0) {
+                Some(end) => {
+                    bytes.extend_from_slice(&page[..end]);
+                    break;
+                },
+                None => bytes.extend_from_slice(page)
+            }
+        }
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod test_cow {
+    use super::*;
+    use crate::cpu::{Cpu, Register};
+
+    #[test]
+    fn children_share_pages_until_written() {
+        let mut parent = CowMemory::new();
+        parent.write_u64(0x1000, 1).unwrap();
+        parent.write_u64(0x2000, 2).unwrap();
+
+        let mut child = parent.fork();
+        assert_eq!(parent.page_count(), 0);
+        assert_eq!(child.page_count(), 0);
+        assert_eq!(child.read_u64(0x2000).unwrap(), 2);
+
+        child.write_u64(0x1008, 3).unwrap();
+        parent.write_u64(0x1000, 4).unwrap();
+        assert_eq!(child.page_count(), 1);
+        assert_eq!(child.read_u64(0x1000).unwrap(), 1);
+        assert_eq!(child.read_u64(0x1008).unwrap(), 3);
+        assert_eq!(parent.read_u64(0x1000).unwrap(), 4);
+        assert_eq!(parent.read_u64(0x1008).unwrap(), 0);
+    }
+
+    #[test]
+    fn reset_returns_to_the_fork() {
+        let mut parent = CowMemory::new();
+        parent.write_bytes(0xffe, b"snapshot").unwrap();
+
+        let mut child = parent.fork();
+        child.write_bytes(0xffe, b"modified").unwrap();
+        child.write_u32(0x9000, 7).unwrap();
+        assert_eq!(child.page_count(), 3);
+
+        child.reset_to_parent();
+        assert_eq!(child.page_count(), 0);
+        assert_eq!(child.read_cstr(0xffe, 8).unwrap(), b"snapshot");
+        assert_eq!(child.read_u32(0x9000).unwrap(), 0);
+
+        // a fork of a fork sees both generations of writes
+        child.write_u8(0xffe, b'S').unwrap();
+        let grandchild = child.fork();
+        assert_eq!(grandchild.read_cstr(0xffe, 8).unwrap(), b"Snapshot");
+        assert_eq!(parent.read_cstr(0xffe, 8).unwrap(), b"snapshot");
+    }
+
+    #[test]
+    fn fill_and_read_cstr_work_page_by_page() {
+        let mut parent = CowMemory::new();
+        parent.fill(0xff0, 0x20, b'a').unwrap();
+        parent.write_u8(0x1008, 0).unwrap();
+
+        let mut child = parent.fork();
+        assert_eq!(child.read_cstr(0xff0, 0x100).unwrap(), vec![b'a'; 0x18]);
+        assert_eq!(child.read_cstr(0xff0, 4).unwrap(), b"aaaa");
+
+        // zeroing untouched memory allocates nothing, zeroing shared memory copies it
+        child.fill(0x5000, 0x2000, 0).unwrap();
+        assert_eq!(child.page_count(), 0);
+        child.fill(0xff8, 0x10, 0).unwrap();
+        assert_eq!(child.page_count(), 2);
+        assert_eq!(child.read_cstr(0xff0, 0x100).unwrap(), b"aaaaaaaa");
+        assert_eq!(parent.read_cstr(0xff0, 0x100).unwrap(), vec![b'a'; 0x18]);
+
+        // a string running into memory that was never written ends there
+        child.fill(0x5ffc, 4, b'b').unwrap();
+        assert_eq!(child.read_cstr(0x5ffc, 0x100).unwrap(), b"bbbb");
+    }
+
+    #[test]
+    fn runs_restart_from_a_snapshot() {
+        let code: Vec<u8> = vec![
+            0x83, 0x32, 0x05, 0x00, // ld t0, 0(a0)
+            0xb3, 0x82, 0xb2, 0x00, // add t0, t0, a1
+            0x23, 0x30, 0x55, 0x00 // sd t0, 0(a0)
+        ];
+        let mut image = CowMemory::new();
+        image.write_bytes(0, &code).unwrap();
+        image.write_u64(0x8000, 100).unwrap();
+
+        let mut run = image.fork();
+        for input in 1..4 {
+            run.reset_to_parent();
+
+            let mut cpu = Cpu::new();
+            cpu.set_register(Register::A0, 0x8000);
+            cpu.set_register(Register::A1, input);
+            for _ in 0..3 {
+                cpu.tick(&mut run).expect("cpu failure");
+            }
+
+            assert_eq!(run.read_u64(0x8000).unwrap(), 100 + input as u64);
+            assert_eq!(run.page_count(), 1);
+        }
+
+        assert_eq!(image.read_u64(0x8000).unwrap(), 100);
+    }
+}
@@ -0,0 +1,247 @@
+use crate::cpu::Trap;
+use crate::memory::{access_fault, page_spans, Access, Memory, PAGE_SIZE};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+// What the fault handler decided to do with a page the guest touched for the first time
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageResponse {
+    ReadWrite,
+    ReadOnly,
+    // nothing is mapped and the access faults, the handler is asked again on the next touch
+    Refuse
+}
+
+struct Page {
+    bytes: Box<[u8; PAGE_SIZE]>,
+    writable: bool
+}
+
+type FaultHandler<'a> = dyn FnMut(usize, &mut [u8]) -> PageResponse + 'a;
+
+// Paged memory where the host supplies every page on first touch. The handler is given the address
+// of the page and a zeroed buffer to fill, so data can be streamed in from a file or decompressor
+// only when the guest actually looks at it.
+pub struct DemandPagedMemory<'a> {
+    pages: RefCell<HashMap<usize, Page>>,
+    handler: RefCell<Box<FaultHandler<'a>>>
+}
+
+impl<'a> DemandPagedMemory<'a> {
+    pub fn new(handler: impl FnMut(usize, &mut [u8]) -> PageResponse + 'a) -> Self {
+        DemandPagedMemory {
+            pages: RefCell::new(HashMap::new()),
+            handler: RefCell::new(Box::new(handler))
+        }
+    }
+
+    pub fn page_count(&self) -> usize {
+        self.pages.borrow().len()
+    }
+
+    pub fn is_mapped(&self, address: usize) -> bool {
+        self.pages.borrow().contains_key(&(address / PAGE_SIZE))
+    }
+
+    // Drops a page so the handler is asked for it again on the next touch, anything the guest
+    // wrote to it is lost
+    pub fn evict(&mut self, address: usize) -> bool {
+        self.pages.get_mut().remove(&(address / PAGE_SIZE)).is_some()
+    }
+
+    // makes sure the page is mapped and allows the access, faults are reported against address
+    fn map(&self, number: usize, access: Access, address: usize) -> Result<(), Trap> {
+        let mut pages = self.pages.borrow_mut();
+        let writable = match pages.get(&number) {
+            Some(page) => page.writable,
+            None => {
+                let mut bytes = Box::new([0; PAGE_SIZE]);
+                let writable = match (self.handler.borrow_mut())(number * PAGE_SIZE, &mut bytes[..]) {
+                    PageResponse::ReadWrite => true,
+                    PageResponse::ReadOnly => false,
+                    PageResponse::Refuse => return Err(access_fault(access, address))
+                };
+                pages.insert(number, Page { bytes, writable });
+                writable
+            }
+        };
+
+        match access {
+            Access::Write if !writable => Err(access_fault(access, address)),
+            _ => Ok(())
+        }
+    }
+
+    fn copy_out(&self, address: usize, buffer: &mut [u8]) -> Result<(), Trap> {
+        for (span, _) in page_spans(address, buffer.len()) {
+            self.map(span / PAGE_SIZE, Access::Read, address)?;
+        }
+
+        let pages = self.pages.borrow();
+        let mut done = 0;
+        for (span, length) in page_spans(address, buffer.len()) {
+            buffer[done..done + length].copy_from_slice(&pages[&(span / PAGE_SIZE)].bytes[span % PAGE_SIZE..][..length]);
+            done += length;
+        }
+        Ok(())
+    }
+
+    // every page is checked before anything is written so a faulting store changes nothing
+    fn copy_in(&mut self, address: usize, bytes: &[u8]) -> Result<(), Trap> {
+        for (span, _) in page_spans(address, bytes.len()) {
+            self.map(span / PAGE_SIZE, Access::Write, address)?;
+        }
+
+        let pages = self.pages.get_mut();
+        let mut done = 0;
+        for (span, length) in page_spans(address, bytes.len()) {
+            let page = pages.get_mut(&(span / PAGE_SIZE)).unwrap();
+            page.bytes[span % PAGE_SIZE..][..length].copy_from_slice(&bytes[done..done + length]);
+            done += length;
+        }
+        Ok(())
+    }
+
+    fn read<const N: usize>(&self, address: usize) -> Result<[u8; N], Trap> {
+        let mut bytes = [0; N];
+        self.copy_out(address, &mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+impl Memory for DemandPagedMemory<'_> {
+    fn read_u8(&self, address: usize) -> Result<u8, Trap> {
+        self.read::<1>(address).map(|bytes| bytes[0])
+    }
+
+    fn read_u16(&self, address: usize) -> Result<u16, Trap> {
+        self.read(address).map(u16::from_le_bytes)
+    }
+
+    fn read_u32(&self, address: usize) -> Result<u32, Trap> {
+        self.read(address).map(u32::from_le_bytes)
+    }
+
+    fn read_u64(&self, address: usize) -> Result<u64, Trap> {
+        self.read(address).map(u64::from_le_bytes)
+    }
+
+    fn write_u8(&mut self, address: usize, value: u8) -> Result<(), Trap> {
+        self.copy_in(address, &[value])
+    }
+
+    fn write_u16(&mut self, address: usize, value: u16) -> Result<(), Trap> {
+        self.copy_in(address, &value.to_le_bytes())
+    }
+
+    fn write_u32(&mut self, address: usize, value: u32) -> Result<(), Trap> {
+        self.copy_in(address, &value.to_le_bytes())
+    }
+
+    fn write_u64(&mut self, address: usize, value: u64) -> Result<(), Trap> {
+        self.copy_in(address, &value.to_le_bytes())
+    }
+
+    fn read_bytes(&self, address: usize, buffer: &mut [u8]) -> Result<(), Trap> {
+        self.copy_out(address, buffer)
+    }
+
+    fn write_bytes(&mut self, address: usize, bytes: &[u8]) -> Result<(), Trap> {
+        self.copy_in(address, bytes)
+    }
+}
+
+#[cfg(test)]
+mod test_demand {
+    use super::*;
+    use crate::cpu::{Cpu, Register, TrapType};
+    use std::cell::Cell;
+
+    const TABLE: usize = 0x1000_0000;
+    const SCRATCH: usize = 0x2000_0000;
+
+    // a read-only table that only exists in the handler, plus one page of scratch space
+    fn table_handler<'a>(table: &'a [u64], faults: &'a Cell<usize>) -> impl FnMut(usize, &mut [u8]) -> PageResponse + 'a {
+        move |address, page| {
+            faults.set(faults.get() + 1);
+            match address {
+                SCRATCH => PageResponse::ReadWrite,
+                _ if address >= TABLE && address < TABLE + table.len() * 8 => {
+                    let first = (address - TABLE) / 8;
+                    for (i, value) in table[first..].iter().take(PAGE_SIZE / 8).enumerate() {
+                        page[i * 8..i * 8 + 8].copy_from_slice(&value.to_le_bytes());
+                    }
+                    PageResponse::ReadOnly
+                },
+                _ => PageResponse::Refuse
+            }
+        }
+    }
+
+    #[test]
+    fn pages_are_supplied_on_first_touch() {
+        let table: Vec<u64> = (0..2000).map(|i| i * i).collect();
+        let faults = Cell::new(0);
+        let mut memory = DemandPagedMemory::new(table_handler(&table, &faults));
+
+        assert_eq!(memory.read_u64(TABLE + 8 * 1999).unwrap(), 1999 * 1999);
+        assert_eq!(memory.read_u64(TABLE + 8 * 1800).unwrap(), 1800 * 1800);
+        assert_eq!(faults.get(), 1);
+        assert_eq!(memory.read_u64(TABLE + 8 * 3).unwrap(), 9);
+        assert_eq!(memory.page_count(), 2);
+
+        let e = memory.write_u64(TABLE, 1).unwrap_err();
+        assert!(matches!(e.trap_type, TrapType::StoreAccessFault));
+        assert_eq!(e.value, TABLE as u64);
+
+        memory.write_u32(SCRATCH, 5).unwrap();
+        assert!(memory.evict(SCRATCH));
+        assert_eq!(memory.read_u32(SCRATCH).unwrap(), 0);
+        assert_eq!(faults.get(), 4);
+    }
+
+    #[test]
+    fn refused_pages_fault() {
+        let faults = Cell::new(0);
+        let mut memory = DemandPagedMemory::new(table_handler(&[], &faults));
+
+        let e = memory.read_u8(0x4000).unwrap_err();
+        assert!(matches!(e.trap_type, TrapType::LoadAccessFault));
+        assert_eq!(e.value, 0x4000);
+
+        // a store straddling into a refused page must not touch the first page either
+        let e = memory.write_u32(SCRATCH + PAGE_SIZE - 2, u32::MAX).unwrap_err();
+        assert!(matches!(e.trap_type, TrapType::StoreAccessFault));
+        assert_eq!(e.value, (SCRATCH + PAGE_SIZE - 2) as u64);
+        assert_eq!(memory.read_u16(SCRATCH + PAGE_SIZE - 2).unwrap(), 0);
+        assert!(!memory.is_mapped(0x4000));
+        assert_eq!(faults.get(), 3);
+    }
+
+    #[test]
+    fn guest_reads_a_lazy_table() {
+        let table: Vec<u64> = (0..2000).map(|i| i * 3).collect();
+        let faults = Cell::new(0);
+        let mut data = table_handler(&table, &faults);
+        let mut memory = DemandPagedMemory::new(move |address, page: &mut [u8]| match address {
+            0 => {
+                page[..8].copy_from_slice(&[
+                    0x83, 0x32, 0x05, 0x00, // ld t0, 0(a0)
+                    0x23, 0x30, 0x5b, 0x00 // sd t0, 0(s6)
+                ]);
+                PageResponse::ReadOnly
+            },
+            _ => data(address, page)
+        });
+
+        let mut cpu = Cpu::new();
+        cpu.set_register(Register::A0, (TABLE + 8 * 1234) as i64);
+        cpu.set_register(Register::S6, SCRATCH as i64);
+        cpu.tick(&mut memory).expect("cpu failure");
+        cpu.tick(&mut memory).expect("cpu failure");
+
+        assert_eq!(memory.read_u64(SCRATCH).unwrap(), 1234 * 3);
+        assert_eq!(memory.page_count(), 3);
+        assert_eq!(faults.get(), 2);
+    }
+}
@@ -0,0 +1,239 @@
+use crate::cpu::Trap;
+use crate::memory::{page_spans, AtomicOp, Memory, PAGE_SIZE};
+use std::collections::BTreeSet;
+use std::ops::Range;
+
+// Wraps another memory and records which pages have been written since the last checkpoint. Only
+// writes that succeed mark a page, reads and instruction fetches are passed straight through.
+pub struct DirtyTrackingMemory<M: Memory> {
+    inner: M,
+    dirty: BTreeSet<usize>
+}
+
+impl<M: Memory> DirtyTrackingMemory<M> {
+    pub fn new(inner: M) -> Self {
+        DirtyTrackingMemory {
+            inner,
+            dirty: BTreeSet::new()
+        }
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    // writes made directly to the inner memory are not tracked
+    pub fn inner_mut(&mut self) -> &mut M {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> M {
+        self.inner
+    }
+
+    // forgets every dirty page, later writes are recorded relative to this point
+    pub fn checkpoint(&mut self) {
+        self.dirty.clear();
+    }
+
+    pub fn is_dirty(&self, address: usize) -> bool {
+        self.dirty.contains(&(address / PAGE_SIZE))
+    }
+
+    // the page numbers written since the last checkpoint in ascending order
+    pub fn dirty_pages(&self) -> impl Iterator<Item = usize> + '_ {
+        self.dirty.iter().copied()
+    }
+
+    // the dirty pages as address ranges, with neighbouring pages merged into one range
+    pub fn dirty_ranges(&self) -> impl Iterator<Item = Range<usize>> + '_ {
+        let mut pages = self.dirty.iter().copied().peekable();
+
+        std::iter::from_fn(move || {
+            let first = pages.next()?;
+            let mut last = first;
+            while pages.next_if(|page| *page == last + 1).is_some() {
+                last += 1;
+            }
+
+            Some(first * PAGE_SIZE..(last + 1).wrapping_mul(PAGE_SIZE))
+        })
+    }
+
+    fn mark(&mut self, address: usize, length: usize) {
+        for (address, _) in page_spans(address, length) {
+            self.dirty.insert(address / PAGE_SIZE);
+        }
+    }
+
+    fn track<T>(&mut self, address: usize, length: usize, result: Result<T, Trap>) -> Result<T, Trap> {
+        if result.is_ok() {
+            self.mark(address, length);
+        }
+        result
+    }
+}
+
+impl<M: Memory> Memory for DirtyTrackingMemory<M> {
+    fn read_i8(&self, address: usize) -> Result<i8, Trap> {
+        self.inner.read_i8(address)
+    }
+
+    fn read_u8(&self, address: usize) -> Result<u8, Trap> {
+        self.inner.read_u8(address)
+    }
+
+    fn read_i16(&self, address: usize) -> Result<i16, Trap> {
+        self.inner.read_i16(address)
+    }
+
+    fn read_u16(&self, address: usize) -> Result<u16, Trap> {
+        self.inner.read_u16(address)
+    }
+
+    fn read_i32(&self, address: usize) -> Result<i32, Trap> {
+        self.inner.read_i32(address)
+    }
+
+    fn read_u32(&self, address: usize) -> Result<u32, Trap> {
+        self.inner.read_u32(address)
+    }
+
+    fn read_i64(&self, address: usize) -> Result<i64, Trap> {
+        self.inner.read_i64(address)
+    }
+
+    fn read_u64(&self, address: usize) -> Result<u64, Trap> {
+        self.inner.read_u64(address)
+    }
+
+    fn write_u8(&mut self, address: usize, value: u8) -> Result<(), Trap> {
+        let result = self.inner.write_u8(address, value);
+        self.track(address, 1, result)
+    }
+
+    fn write_u16(&mut self, address: usize, value: u16) -> Result<(), Trap> {
+        let result = self.inner.write_u16(address, value);
+        self.track(address, 2, result)
+    }
+
+    fn write_u32(&mut self, address: usize, value: u32) -> Result<(), Trap> {
+        let result = self.inner.write_u32(address, value);
+        self.track(address, 4, result)
+    }
+
+    fn write_u64(&mut self, address: usize, value: u64) -> Result<(), Trap> {
+        let result = self.inner.write_u64(address, value);
+        self.track(address, 8, result)
+    }
+
+    fn fetch_u32(&self, address: usize) -> Result<u32, Trap> {
+        self.inner.fetch_u32(address)
+    }
+
+    fn fetch_op_u32(&mut self, address: usize, op: AtomicOp, value: u32) -> Result<u32, Trap> {
+        let result = self.inner.fetch_op_u32(address, op, value);
+        self.track(address, 4, result)
+    }
+
+    fn fetch_op_u64(&mut self, address: usize, op: AtomicOp, value: u64) -> Result<u64, Trap> {
+        let result = self.inner.fetch_op_u64(address, op, value);
+        self.track(address, 8, result)
+    }
+
+    fn compare_exchange_u32(&mut self, address: usize, expected: u32, new: u32) -> Result<u32, Trap> {
+        let result = self.inner.compare_exchange_u32(address, expected, new);
+        match result {
+            Ok(current) if current == expected => self.track(address, 4, result),
+            _ => result
+        }
+    }
+
+    fn compare_exchange_u64(&mut self, address: usize, expected: u64, new: u64) -> Result<u64, Trap> {
+        let result = self.inner.compare_exchange_u64(address, expected, new);
+        match result {
+            Ok(current) if current == expected => self.track(address, 8, result),
+            _ => result
+        }
+    }
+
+    fn read_bytes(&self, address: usize, buffer: &mut [u8]) -> Result<(), Trap> {
+        self.inner.read_bytes(address, buffer)
+    }
+
+    fn write_bytes(&mut self, address: usize, bytes: &[u8]) -> Result<(), Trap> {
+        let result = self.inner.write_bytes(address, bytes);
+        self.track(address, bytes.len(), result)
+    }
+
+    fn fill(&mut self, address: usize, length: usize, value: u8) -> Result<(), Trap> {
+        let result = self.inner.fill(address, length, value);
+        self.track(address, length, result)
+    }
+
+    fn read_cstr(&self, address: usize, max_len: usize) -> Result<Vec<u8>, Trap> {
+        self.inner.read_cstr(address, max_len)
+    }
+}
+
+#[cfg(test)]
+mod test_dirty {
+    use super::*;
+    use crate::cpu::{Cpu, Register};
+    use crate::memory::PagedMemory;
+
+    #[test]
+    fn records_written_pages() {
+        let mut memory = DirtyTrackingMemory::new(PagedMemory::new());
+
+        memory.write_u64(PAGE_SIZE * 2 - 4, 1).unwrap();
+        memory.write_u8(PAGE_SIZE * 7, 1).unwrap();
+        memory.fill(PAGE_SIZE * 9, PAGE_SIZE * 2, 0xff).unwrap();
+        memory.read_u64(PAGE_SIZE * 20).unwrap();
+        assert!(!memory.is_dirty(0));
+        assert!(memory.is_dirty(PAGE_SIZE * 10 + 5));
+        assert_eq!(memory.dirty_pages().collect::<Vec<_>>(), vec![1, 2, 7, 9, 10]);
+        assert_eq!(memory.dirty_ranges().collect::<Vec<_>>(), vec![
+            PAGE_SIZE..PAGE_SIZE * 3,
+            PAGE_SIZE * 7..PAGE_SIZE * 8,
+            PAGE_SIZE * 9..PAGE_SIZE * 11
+        ]);
+
+        memory.checkpoint();
+        assert_eq!(memory.dirty_pages().count(), 0);
+        memory.compare_exchange_u32(PAGE_SIZE * 7, 0, 5).unwrap();
+        assert_eq!(memory.dirty_pages().count(), 0);
+        memory.compare_exchange_u32(PAGE_SIZE * 7, 1, 5).unwrap();
+        assert_eq!(memory.dirty_pages().collect::<Vec<_>>(), vec![7]);
+    }
+
+    #[test]
+    fn failed_writes_stay_clean() {
+        let mut memory = DirtyTrackingMemory::new(vec![0u8; PAGE_SIZE]);
+
+        assert!(memory.write_u32(PAGE_SIZE - 2, 1).is_err());
+        assert!(memory.write_bytes(PAGE_SIZE * 3, b"nope").is_err());
+        assert_eq!(memory.dirty_pages().count(), 0);
+        assert_eq!(memory.into_inner().len(), PAGE_SIZE);
+    }
+
+    #[test]
+    fn tracks_guest_stores() {
+        let code: Vec<u8> = vec![
+            0x23, 0x30, 0xb5, 0x00, // sd a1, 0(a0)
+            0x23, 0x34, 0xb5, 0x00 // sd a1, 8(a0)
+        ];
+        let mut memory = DirtyTrackingMemory::new(PagedMemory::new());
+        memory.write_bytes(0, &code).unwrap();
+        memory.checkpoint();
+
+        let mut cpu = Cpu::new();
+        cpu.set_register(Register::A0, (PAGE_SIZE * 4 - 8) as i64);
+        cpu.set_register(Register::A1, -1);
+        cpu.tick(&mut memory).expect("cpu failure");
+        cpu.tick(&mut memory).expect("cpu failure");
+
+        assert_eq!(memory.dirty_ranges().collect::<Vec<_>>(), vec![PAGE_SIZE * 3..PAGE_SIZE * 5]);
+        assert_eq!(memory.inner().page_count(), 3);
+    }
+}
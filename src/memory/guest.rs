@@ -0,0 +1,237 @@
+use crate::cpu::{Trap, TrapType};
+use crate::memory::{access_fault, Access, Memory};
+use std::marker::PhantomData;
+use std::mem::{align_of, size_of, MaybeUninit};
+use std::{ptr, slice};
+
+/// # Safety
+///
+/// Values are copied to and from guest memory byte for byte, so the type must be `repr(C)` (or a
+/// primitive), contain no padding bytes, be valid for any bit pattern and lay out the same way as
+/// the equivalent C type under the RISC-V LP64D ABI. Only plain integers, floats, arrays of them
+/// and structs built out of those qualify, pointers have to be passed as `u64`.
+pub unsafe trait GuestPod: Copy {}
+
+unsafe impl GuestPod for u8 {}
+unsafe impl GuestPod for i8 {}
+unsafe impl GuestPod for u16 {}
+unsafe impl GuestPod for i16 {}
+unsafe impl GuestPod for u32 {}
+unsafe impl GuestPod for i32 {}
+unsafe impl GuestPod for u64 {}
+unsafe impl GuestPod for i64 {}
+unsafe impl GuestPod for f32 {}
+unsafe impl GuestPod for f64 {}
+unsafe impl<T: GuestPod, const N: usize> GuestPod for [T; N] {}
+
+// LP64D pointers have to be naturally aligned, which for GuestPod types is the host alignment
+fn check_alignment<T>(address: usize, trap_type: TrapType) -> Result<(), Trap> {
+    match address % align_of::<T>() {
+        0 => Ok(()),
+        _ => Err(Trap { trap_type, value: address as u64 })
+    }
+}
+
+// A typed pointer into guest memory. Nothing is checked until it is read or written, at which
+// point misalignment and guest memory faults come back as the usual traps.
+#[derive(Clone, Copy, Debug)]
+pub struct GuestPtr<T: GuestPod> {
+    address: usize,
+    _type: PhantomData<T>
+}
+
+impl<T: GuestPod> GuestPtr<T> {
+    pub fn new(address: usize) -> Self {
+        GuestPtr {
+            address,
+            _type: PhantomData
+        }
+    }
+
+    pub fn address(&self) -> usize {
+        self.address
+    }
+
+    // the pointer count elements further on, like pointer arithmetic in C
+    pub fn offset(self, count: usize) -> Self {
+        GuestPtr::new(self.address.wrapping_add(count.wrapping_mul(size_of::<T>())))
+    }
+
+    // a pointer to a field of the struct, use std::mem::offset_of! to get the offset
+    pub fn field<U: GuestPod>(self, offset: usize) -> GuestPtr<U> {
+        GuestPtr::new(self.address.wrapping_add(offset))
+    }
+
+    pub fn cast<U: GuestPod>(self) -> GuestPtr<U> {
+        GuestPtr::new(self.address)
+    }
+
+    pub fn read<M: Memory + ?Sized>(&self, memory: &M) -> Result<T, Trap> {
+        check_alignment::<T>(self.address, TrapType::LoadAddressMisaligned)?;
+
+        let mut value = MaybeUninit::<T>::zeroed();
+        let bytes = unsafe { slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>()) };
+        memory.read_bytes(self.address, bytes)?;
+
+        // GuestPod types are valid for any bit pattern
+        Ok(unsafe { value.assume_init() })
+    }
+
+    pub fn write<M: Memory + ?Sized>(&self, memory: &mut M, value: T) -> Result<(), Trap> {
+        check_alignment::<T>(self.address, TrapType::StoreAddressMisaligned)?;
+
+        // GuestPod types have no padding, so every byte is initialised
+        let bytes = unsafe { slice::from_raw_parts(&value as *const T as *const u8, size_of::<T>()) };
+        memory.write_bytes(self.address, bytes)
+    }
+}
+
+// A guest array of len elements starting at a GuestPtr
+#[derive(Clone, Copy, Debug)]
+pub struct GuestSlice<T: GuestPod> {
+    start: GuestPtr<T>,
+    len: usize
+}
+
+impl<T: GuestPod> GuestSlice<T> {
+    pub fn new(address: usize, len: usize) -> Self {
+        GuestSlice {
+            start: GuestPtr::new(address),
+            len
+        }
+    }
+
+    pub fn as_ptr(&self) -> GuestPtr<T> {
+        self.start
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, index: usize) -> Option<GuestPtr<T>> {
+        match index < self.len {
+            true => Some(self.start.offset(index)),
+            false => None
+        }
+    }
+
+    // the whole slice has to fit in the address space before any of it is touched
+    fn byte_len(&self, access: Access) -> Result<usize, Trap> {
+        let address = self.start.address;
+        match self.len.checked_mul(size_of::<T>()).filter(|length| address.checked_add(*length).is_some()) {
+            Some(length) => Ok(length),
+            None => Err(access_fault(access, address))
+        }
+    }
+
+    pub fn read<M: Memory + ?Sized>(&self, memory: &M) -> Result<Vec<T>, Trap> {
+        check_alignment::<T>(self.start.address, TrapType::LoadAddressMisaligned)?;
+        let length = self.byte_len(Access::Read)?;
+
+        let mut values: Vec<T> = Vec::with_capacity(self.len);
+        let bytes = unsafe {
+            ptr::write_bytes(values.as_mut_ptr(), 0, self.len);
+            slice::from_raw_parts_mut(values.as_mut_ptr() as *mut u8, length)
+        };
+        memory.read_bytes(self.start.address, bytes)?;
+
+        // every element has been filled in and GuestPod types are valid for any bit pattern
+        unsafe { values.set_len(self.len) };
+        Ok(values)
+    }
+
+    // panics if values is not the same length as the slice
+    pub fn write<M: Memory + ?Sized>(&self, memory: &mut M, values: &[T]) -> Result<(), Trap> {
+        assert_eq!(values.len(), self.len, "guest slice and host values differ in length");
+        check_alignment::<T>(self.start.address, TrapType::StoreAddressMisaligned)?;
+        let length = self.byte_len(Access::Write)?;
+
+        let bytes = unsafe { slice::from_raw_parts(values.as_ptr() as *const u8, length) };
+        memory.write_bytes(self.start.address, bytes)
+    }
+}
+
+#[cfg(test)]
+mod test_guest {
+    use super::*;
+    use crate::cpu::{Cpu, Register};
+    use std::mem::offset_of;
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    struct Header {
+        magic: u32,
+        count: u32,
+        total: u64,
+        samples: [u16; 4],
+        scale: f64
+    }
+
+    unsafe impl GuestPod for Header {}
+
+    #[test]
+    fn structs_follow_lp64d_layout() {
+        assert_eq!(size_of::<Header>(), 32);
+        assert_eq!(align_of::<Header>(), 8);
+        assert_eq!(offset_of!(Header, total), 8);
+        assert_eq!(offset_of!(Header, samples), 16);
+        assert_eq!(offset_of!(Header, scale), 24);
+    }
+
+    #[test]
+    fn guest_sums_a_host_struct() {
+        let code: Vec<u8> = vec![
+            0x83, 0x62, 0x45, 0x00, // lwu t0, 4(a0)
+            0x13, 0x03, 0x05, 0x01, // addi t1, a0, 16
+            0x83, 0x53, 0x03, 0x00, // lhu t2, 0(t1)
+            0x33, 0x0e, 0x7e, 0x00, // add t3, t3, t2
+            0x13, 0x03, 0x23, 0x00, // addi t1, t1, 2
+            0x93, 0x82, 0xf2, 0xff, // addi t0, t0, -1
+            0xe3, 0x98, 0x02, 0xfe, // bnez t0, -16
+            0x23, 0x34, 0xc5, 0x01 // sd t3, 8(a0)
+        ];
+        let mut memory: Vec<u8> = vec![0; 0x200];
+        memory[..code.len()].copy_from_slice(&code);
+
+        let header = GuestPtr::<Header>::new(0x100);
+        header.write(&mut memory, Header { magic: 0xfeed, count: 3, total: 0, samples: [100, 200, 300, 400], scale: 0.5 }).unwrap();
+
+        let mut cpu = Cpu::new();
+        cpu.set_register(Register::A0, 0x100);
+        for _ in 0..2 + 5 * 3 + 1 {
+            cpu.tick(&mut memory).expect("cpu failure");
+        }
+
+        let result = header.read(&memory).unwrap();
+        assert_eq!(result.total, 600);
+        assert_eq!(result.scale, 0.5);
+        assert_eq!(header.field::<u64>(offset_of!(Header, total)).read(&memory).unwrap(), 600);
+        assert_eq!(header.field::<[u16; 4]>(offset_of!(Header, samples)).cast::<u16>().offset(3).read(&memory).unwrap(), 400);
+    }
+
+    #[test]
+    fn slices_check_bounds_and_alignment() {
+        let mut memory: Vec<u8> = vec![0; 64];
+        let values = GuestSlice::<u32>::new(40, 6);
+
+        values.write(&mut memory, &[1, 2, 3, 4, 5, 6]).unwrap();
+        assert_eq!(values.read(&memory).unwrap(), vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(values.get(5).unwrap().read(&memory).unwrap(), 6);
+        assert!(values.get(6).is_none());
+
+        let e = GuestSlice::<u32>::new(48, 5).read(&memory).unwrap_err();
+        assert!(matches!(e.trap_type, TrapType::LoadAccessFault));
+        assert_eq!(e.value, 48);
+
+        let e = GuestPtr::<u64>::new(44).write(&mut memory, 0).unwrap_err();
+        assert!(matches!(e.trap_type, TrapType::StoreAddressMisaligned));
+        let e = GuestSlice::<u64>::new(8, usize::MAX / 4).read(&memory).unwrap_err();
+        assert!(matches!(e.trap_type, TrapType::LoadAccessFault));
+        assert_eq!(memory[40..44], [1, 0, 0, 0]);
+    }
+}
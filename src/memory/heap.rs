@@ -0,0 +1,262 @@
+use crate::cpu::{Cpu, Register, Trap};
+use crate::memory::Memory;
+use std::collections::{BTreeMap, HashMap};
+
+// the same guarantee malloc gives on RV64, sizes are rounded up to it as well
+const MIN_ALIGN: usize = 16;
+
+fn round_up(size: usize, align: usize) -> Option<usize> {
+    Some(size.checked_add(align - 1)? & !(align - 1))
+}
+
+#[derive(Clone, Copy)]
+struct Allocation {
+    size: usize,
+    align: usize
+}
+
+// A first fit allocator handing out blocks of a region of guest memory. The bookkeeping is kept on
+// the host so a misbehaving guest cannot corrupt it. The guest can share the heap with the host by
+// bouncing the ECALL_* calls out of its ecall handler and passing them to handle_ecall.
+pub struct GuestHeap {
+    free: BTreeMap<usize, usize>, // start to end of every free block, neighbours are always merged
+    allocations: HashMap<usize, Allocation>
+}
+
+impl GuestHeap {
+    // a0 = size, a1 = alignment, returns the address in a0 or 0 if the heap is full
+    pub const ECALL_ALLOC: i64 = 0x1000;
+    // a0 = address, returns 0 in a0 or -1 if the address was not allocated
+    pub const ECALL_FREE: i64 = 0x1001;
+    // a0 = address, a1 = new size, returns the new address in a0 or 0 if it could not be resized
+    pub const ECALL_REALLOC: i64 = 0x1002;
+
+    pub fn new(start: usize, size: usize) -> Self {
+        let end = start.checked_add(size).expect("heap wraps around the address space");
+        let mut free = BTreeMap::new();
+        if start < end {
+            free.insert(start, end);
+        }
+
+        GuestHeap {
+            free,
+            allocations: HashMap::new()
+        }
+    }
+
+    // returns None if there is no free block big enough, align must be a power of two
+    pub fn alloc(&mut self, size: usize, align: usize) -> Option<usize> {
+        assert!(align.is_power_of_two(), "alignment {} is not a power of two", align);
+        let align = align.max(MIN_ALIGN);
+        let size = round_up(size.max(1), MIN_ALIGN)?;
+
+        let (start, end, address) = self.free.iter().find_map(|(&start, &end)| {
+            let address = round_up(start, align)?;
+            match address.checked_add(size) {
+                Some(block_end) if block_end <= end => Some((start, end, address)),
+                _ => None
+            }
+        })?;
+
+        self.free.remove(&start);
+        if start < address {
+            self.free.insert(start, address);
+        }
+        if address + size < end {
+            self.free.insert(address + size, end);
+        }
+        self.allocations.insert(address, Allocation { size, align });
+
+        Some(address)
+    }
+
+    // returns false if the address is not the start of an allocation
+    pub fn free(&mut self, address: usize) -> bool {
+        match self.allocations.remove(&address) {
+            Some(allocation) => {
+                self.release(address, address + allocation.size);
+                true
+            },
+            None => false
+        }
+    }
+
+    // Resizes in place when it can, otherwise moves the contents to a new block. A zero address
+    // allocates like C's realloc, and None means nothing changed.
+    pub fn realloc<M: Memory + ?Sized>(&mut self, memory: &mut M, address: usize, size: usize) -> Result<Option<usize>, Trap> {
+        if address == 0 {
+            return Ok(self.alloc(size, MIN_ALIGN));
+        }
+        let (allocation, new_size) = match (self.allocations.get(&address), round_up(size.max(1), MIN_ALIGN)) {
+            (Some(allocation), Some(new_size)) => (*allocation, new_size),
+            _ => return Ok(None)
+        };
+        let end = address + allocation.size;
+        let next_end = self.free.get(&end).copied().unwrap_or(end);
+
+        if new_size <= allocation.size {
+            self.release(address + new_size, end);
+        } else if new_size <= next_end - address {
+            // grow into the free block that follows
+            self.free.remove(&end);
+            if address + new_size < next_end {
+                self.free.insert(address + new_size, next_end);
+            }
+        } else {
+            let new_address = match self.alloc(new_size, allocation.align) {
+                Some(new_address) => new_address,
+                None => return Ok(None)
+            };
+
+            let mut contents = vec![0; allocation.size];
+            let copied = memory.read_bytes(address, &mut contents).and_then(|_| memory.write_bytes(new_address, &contents));
+            if let Err(trap) = copied {
+                self.free(new_address);
+                return Err(trap);
+            }
+
+            self.free(address);
+            return Ok(Some(new_address));
+        }
+
+        self.allocations.insert(address, Allocation { size: new_size, ..allocation });
+        Ok(Some(address))
+    }
+
+    // the usable size of an allocation, which may be more than was asked for
+    pub fn size_of(&self, address: usize) -> Option<usize> {
+        self.allocations.get(&address).map(|allocation| allocation.size)
+    }
+
+    // Services one of the ECALL_* calls using the guest's registers, returns false for any other call
+    pub fn handle_ecall<M: Memory + ?Sized>(&mut self, cpu: &mut Cpu, memory: &mut M) -> Result<bool, Trap> {
+        let a0 = cpu.get_register(Register::A0) as usize;
+        let a1 = cpu.get_register(Register::A1) as usize;
+
+        let result = match cpu.get_register(Register::A7) {
+            GuestHeap::ECALL_ALLOC => match a1.is_power_of_two() {
+                true => self.alloc(a0, a1).unwrap_or(0) as i64,
+                false => 0
+            },
+            GuestHeap::ECALL_FREE => match a0 == 0 || self.free(a0) {
+                true => 0,
+                false => -1
+            },
+            GuestHeap::ECALL_REALLOC => self.realloc(memory, a0, a1)?.unwrap_or(0) as i64,
+            _ => return Ok(false)
+        };
+
+        cpu.set_register(Register::A0, result);
+        Ok(true)
+    }
+
+    fn release(&mut self, start: usize, end: usize) {
+        if start == end {
+            return;
+        }
+
+        let mut start = start;
+        let mut end = end;
+        if let Some((&previous_start, &previous_end)) = self.free.range(..start).next_back() {
+            if previous_end == start {
+                self.free.remove(&previous_start);
+                start = previous_start;
+            }
+        }
+        if let Some(next_end) = self.free.remove(&end) {
+            end = next_end;
+        }
+
+        self.free.insert(start, end);
+    }
+}
+
+#[cfg(test)]
+mod test_heap {
+    use super::*;
+    use crate::cpu::instruction::Instruction;
+    use crate::cpu::TrapType;
+
+    #[test]
+    fn freed_blocks_are_merged_and_reused() {
+        let mut heap = GuestHeap::new(0x1000, 0x100);
+
+        let a = heap.alloc(10, 1).unwrap();
+        let b = heap.alloc(64, 64).unwrap();
+        let c = heap.alloc(16, 8).unwrap();
+        assert_eq!((a, b, c), (0x1000, 0x1040, 0x1010));
+        assert_eq!(heap.size_of(a), Some(16));
+        assert!(heap.alloc(0x100, 8).is_none());
+
+        assert!(heap.free(a));
+        assert!(!heap.free(a));
+        assert!(heap.free(c));
+        assert_eq!(heap.alloc(48, 16), Some(0x1000));
+        assert!(heap.free(0x1000));
+        assert!(heap.free(b));
+        assert_eq!(heap.alloc(0x100, 16), Some(0x1000));
+    }
+
+    #[test]
+    fn realloc_keeps_the_contents() {
+        let mut memory: Vec<u8> = vec![0; 0x200];
+        let mut heap = GuestHeap::new(0x100, 0x100);
+
+        let a = heap.alloc(16, 16).unwrap();
+        memory.write_bytes(a, b"grow in place").unwrap();
+        assert_eq!(heap.realloc(&mut memory, a, 40).unwrap(), Some(a));
+        assert_eq!(heap.size_of(a), Some(48));
+
+        let b = heap.alloc(16, 16).unwrap();
+        let moved = heap.realloc(&mut memory, a, 64).unwrap().unwrap();
+        assert_eq!(moved, b + 16);
+        assert_eq!(memory.read_cstr(moved, 64).unwrap(), b"grow in place");
+
+        assert_eq!(heap.realloc(&mut memory, moved, 1).unwrap(), Some(moved));
+        assert_eq!(heap.realloc(&mut memory, b, 0x1000).unwrap(), None);
+        assert_eq!(heap.alloc(32, 16), Some(a));
+    }
+
+    #[test]
+    fn guest_and_host_share_the_heap() {
+        let mut memory: Vec<u8> = vec![
+            0xb7, 0x18, 0x00, 0x00, // lui a7, 1
+            0x13, 0x05, 0x80, 0x01, // addi a0, zero, 24
+            0x93, 0x05, 0x80, 0x00, // addi a1, zero, 8
+            0x73, 0x00, 0x00, 0x00, // ecall
+            0x93, 0x02, 0xa0, 0x02, // addi t0, zero, 42
+            0x23, 0x30, 0x55, 0x00, // sd t0, 0(a0)
+            0x93, 0x88, 0x28, 0x00, // addi a7, a7, 2
+            0x93, 0x05, 0x40, 0x06, // addi a1, zero, 100
+            0x73, 0x00, 0x00, 0x00 // ecall
+        ];
+        memory.resize(0x1000, 0);
+
+        let mut heap = GuestHeap::new(0x800, 0x800);
+        let host_block = heap.alloc(32, 16).unwrap();
+
+        let mut cpu = Cpu::new();
+        cpu.set_ecall_handler(Some(Instruction {
+            name: "ECALL",
+            operation: |cpu, _memory, _word, _address| {
+                Err(Trap { trap_type: TrapType::EnvironmentCallFromUMode, value: cpu.get_register(Register::A7) as u64 })
+            }
+        }));
+
+        for _ in 0..9 {
+            match cpu.tick(&mut memory) {
+                Ok(()) => {},
+                Err(Trap { trap_type: TrapType::EnvironmentCallFromUMode, .. }) => {
+                    assert!(heap.handle_ecall(&mut cpu, &mut memory).expect("heap call failed"));
+                },
+                Err(e) => panic!("CPU failure: {:?}", e)
+            }
+        }
+
+        let guest_block = cpu.get_register(Register::A0) as usize;
+        assert_eq!(guest_block, host_block + 32);
+        assert_eq!(heap.size_of(guest_block), Some(112));
+        assert_eq!(memory.read_u64(guest_block).unwrap(), 42);
+        assert!(heap.free(guest_block));
+    }
+}
@@ -0,0 +1,330 @@
+use crate::cpu::{Trap, TrapType};
+use crate::memory::{AtomicOp, Memory};
+use std::ptr;
+use std::sync::atomic::{AtomicI32, AtomicI64, AtomicU32, AtomicU64, Ordering};
+
+// Guest addresses are host addresses, every access is a raw load or store through a host pointer.
+// Accesses may be unaligned as the guest is free to pack its data however it likes.
+pub struct HostMemory {
+    _private: ()
+}
+
+impl HostMemory {
+    /// # Safety
+    ///
+    /// Nothing stops the guest from dereferencing a bad pointer, so the caller must be sure that
+    /// every address the guest program touches is valid in the host process.
+    pub unsafe fn new() -> Self {
+        HostMemory {
+            _private: ()
+        }
+    }
+}
+
+// Host atomics need natural alignment, so misaligned atomics are refused rather than torn
+fn check_atomic_alignment(address: usize, size: usize) -> Result<(), Trap> {
+    match address % size {
+        0 => Ok(()),
+        _ => Err(Trap { trap_type: TrapType::StoreAddressMisaligned, value: address as u64 })
+    }
+}
+
+impl Memory for HostMemory {
+    #[inline]
+    fn read_i8(&self, address: usize) -> Result<i8, Trap> {
+        Ok(unsafe { ptr::read(address as *const i8) })
+    }
+
+    #[inline]
+    fn read_u8(&self, address: usize) -> Result<u8, Trap> {
+        Ok(unsafe { ptr::read(address as *const u8) })
+    }
+
+    #[inline]
+    fn read_i16(&self, address: usize) -> Result<i16, Trap> {
+        Ok(unsafe { ptr::read_unaligned(address as *const i16) })
+    }
+
+    #[inline]
+    fn read_u16(&self, address: usize) -> Result<u16, Trap> {
+        Ok(unsafe { ptr::read_unaligned(address as *const u16) })
+    }
+
+    #[inline]
+    fn read_i32(&self, address: usize) -> Result<i32, Trap> {
+        Ok(unsafe { ptr::read_unaligned(address as *const i32) })
+    }
+
+    #[inline]
+    fn read_u32(&self, address: usize) -> Result<u32, Trap> {
+        Ok(unsafe { ptr::read_unaligned(address as *const u32) })
+    }
+
+    #[inline]
+    fn read_i64(&self, address: usize) -> Result<i64, Trap> {
+        Ok(unsafe { ptr::read_unaligned(address as *const i64) })
+    }
+
+    #[inline]
+    fn read_u64(&self, address: usize) -> Result<u64, Trap> {
+        Ok(unsafe { ptr::read_unaligned(address as *const u64) })
+    }
+
+    #[inline]
+    fn write_u8(&mut self, address: usize, value: u8) -> Result<(), Trap> {
+        unsafe { ptr::write(address as *mut u8, value) };
+        Ok(())
+    }
+
+    #[inline]
+    fn write_u16(&mut self, address: usize, value: u16) -> Result<(), Trap> {
+        unsafe { ptr::write_unaligned(address as *mut u16, value) };
+        Ok(())
+    }
+
+    #[inline]
+    fn write_u32(&mut self, address: usize, value: u32) -> Result<(), Trap> {
+        unsafe { ptr::write_unaligned(address as *mut u32, value) };
+        Ok(())
+    }
+
+    #[inline]
+    fn write_u64(&mut self, address: usize, value: u64) -> Result<(), Trap> {
+        unsafe { ptr::write_unaligned(address as *mut u64, value) };
+        Ok(())
+    }
+
+    // These map straight onto host atomics so that a guest sharing memory with host threads sees
+    // the same guarantees they do
+    fn fetch_op_u32(&mut self, address: usize, op: AtomicOp, value: u32) -> Result<u32, Trap> {
+        check_atomic_alignment(address, 4)?;
+        let unsigned = unsafe { AtomicU32::from_ptr(address as *mut u32) };
+        let signed = unsafe { AtomicI32::from_ptr(address as *mut i32) };
+
+        Ok(match op {
+            AtomicOp::Swap => unsigned.swap(value, Ordering::SeqCst),
+            AtomicOp::Add => unsigned.fetch_add(value, Ordering::SeqCst),
+            AtomicOp::And => unsigned.fetch_and(value, Ordering::SeqCst),
+            AtomicOp::Or => unsigned.fetch_or(value, Ordering::SeqCst),
+            AtomicOp::Xor => unsigned.fetch_xor(value, Ordering::SeqCst),
+            AtomicOp::Min => signed.fetch_min(value as i32, Ordering::SeqCst) as u32,
+            AtomicOp::Max => signed.fetch_max(value as i32, Ordering::SeqCst) as u32,
+            AtomicOp::MinU => unsigned.fetch_min(value, Ordering::SeqCst),
+            AtomicOp::MaxU => unsigned.fetch_max(value, Ordering::SeqCst)
+        })
+    }
+
+    fn fetch_op_u64(&mut self, address: usize, op: AtomicOp, value: u64) -> Result<u64, Trap> {
+        check_atomic_alignment(address, 8)?;
+        let unsigned = unsafe { AtomicU64::from_ptr(address as *mut u64) };
+        let signed = unsafe { AtomicI64::from_ptr(address as *mut i64) };
+
+        Ok(match op {
+            AtomicOp::Swap => unsigned.swap(value, Ordering::SeqCst),
+            AtomicOp::Add => unsigned.fetch_add(value, Ordering::SeqCst),
+            AtomicOp::And => unsigned.fetch_and(value, Ordering::SeqCst),
+            AtomicOp::Or => unsigned.fetch_or(value, Ordering::SeqCst),
+            AtomicOp::Xor => unsigned.fetch_xor(value, Ordering::SeqCst),
+            AtomicOp::Min => signed.fetch_min(value as i64, Ordering::SeqCst) as u64,
+            AtomicOp::Max => signed.fetch_max(value as i64, Ordering::SeqCst) as u64,
+            AtomicOp::MinU => unsigned.fetch_min(value, Ordering::SeqCst),
+            AtomicOp::MaxU => unsigned.fetch_max(value, Ordering::SeqCst)
+        })
+    }
+
+    fn compare_exchange_u32(&mut self, address: usize, expected: u32, new: u32) -> Result<u32, Trap> {
+        check_atomic_alignment(address, 4)?;
+        let atomic = unsafe { AtomicU32::from_ptr(address as *mut u32) };
+
+        Ok(match atomic.compare_exchange(expected, new, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(current) => current,
+            Err(current) => current
+        })
+    }
+
+    fn compare_exchange_u64(&mut self, address: usize, expected: u64, new: u64) -> Result<u64, Trap> {
+        check_atomic_alignment(address, 8)?;
+        let atomic = unsafe { AtomicU64::from_ptr(address as *mut u64) };
+
+        Ok(match atomic.compare_exchange(expected, new, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(current) => current,
+            Err(current) => current
+        })
+    }
+
+    fn read_bytes(&self, address: usize, buffer: &mut [u8]) -> Result<(), Trap> {
+        unsafe { ptr::copy(address as *const u8, buffer.as_mut_ptr(), buffer.len()) };
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, address: usize, bytes: &[u8]) -> Result<(), Trap> {
+        unsafe { ptr::copy(bytes.as_ptr(), address as *mut u8, bytes.len()) };
+        Ok(())
+    }
+
+    fn fill(&mut self, address: usize, length: usize, value: u8) -> Result<(), Trap> {
+        unsafe { ptr::write_bytes(address as *mut u8, value, length) };
+        Ok(())
+    }
+
+    fn read_cstr(&self, address: usize, max_len: usize) -> Result<Vec<u8>, Trap> {
+        // the length has to be found a byte at a time, nothing past the terminator may be touched
+        let length = (0..max_len)
+            .find(|i| unsafe { ptr::read((address + i) as *const u8) } == 0)
+            .unwrap_or(max_len);
+
+        let mut bytes = vec![0; length];
+        self.read_bytes(address, &mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod test_host {
+    use super::*;
+    use crate::cpu::instruction::Instruction;
+    use crate::cpu::{Cpu, Register, TrapType};
+
+    #[repr(C)]
+    struct Counter {
+        count: u64,
+        scale: u32,
+        flags: u32
+    }
+
+    fn stop_on_ecall(cpu: &mut Cpu) {
+        cpu.set_ecall_handler(Some(Instruction {
+            name: "ECALL",
+            operation: |cpu, _memory, _word, _address| {
+                Err(Trap { trap_type: TrapType::Stop, value: cpu.get_register(Register::A0) as u64 })
+            }
+        }));
+    }
+
+    #[test]
+    fn reads_and_writes_host_values() {
+        let mut memory = unsafe { HostMemory::new() };
+        let mut values: [u8; 16] = [0; 16];
+        let base = values.as_mut_ptr() as usize;
+
+        memory.write_u64(base + 1, 0x8877665544332211).expect("write failed");
+        memory.write_u16(base + 9, 0xfffe).expect("write failed");
+        assert_eq!(values[1], 0x11);
+        assert_eq!(values[8], 0x88);
+        assert_eq!(memory.read_u32(base + 3).unwrap(), 0x66554433);
+        assert_eq!(memory.read_i16(base + 9).unwrap(), -2);
+        assert_eq!(memory.read_i8(base + 8).unwrap(), -120);
+    }
+
+    #[test]
+    fn guest_updates_host_struct_in_place() {
+        let code: Vec<u32> = vec![
+            0x00053283, // ld t0, 0(a0)
+            0x00128293, // addi t0, t0, 1
+            0x00553023, // sd t0, 0(a0)
+            0x00852303, // lw t1, 8(a0)
+            0x0063033b, // addw t1, t1, t1
+            0x00652423, // sw t1, 8(a0)
+            0x00000073 // ecall
+        ];
+        let mut counter = Counter {
+            count: 41,
+            scale: 21,
+            flags: 0xf00d
+        };
+
+        let mut memory = unsafe { HostMemory::new() };
+        let mut cpu = Cpu::new();
+        stop_on_ecall(&mut cpu);
+        cpu.update_pc(code.as_ptr() as usize);
+        cpu.set_register(Register::A0, &mut counter as *mut Counter as i64);
+
+        loop {
+            match cpu.tick(&mut memory) {
+                Ok(_) => {},
+                Err(Trap { trap_type: TrapType::Stop, .. }) => break,
+                Err(e) => panic!("CPU failure: {:?}", e)
+            }
+        }
+
+        assert_eq!(counter.count, 42);
+        assert_eq!(counter.scale, 42);
+        assert_eq!(counter.flags, 0xf00d);
+    }
+
+    #[test]
+    fn copies_host_buffers() {
+        let mut memory = unsafe { HostMemory::new() };
+        let message = b"hello\0world";
+        let mut buffer = [0u8; 8];
+        let base = buffer.as_mut_ptr() as usize;
+
+        memory.fill(base, 8, b'-').unwrap();
+        memory.write_bytes(base + 1, &message[..5]).unwrap();
+        assert_eq!(&buffer, b"-hello--");
+        assert_eq!(memory.read_cstr(message.as_ptr() as usize, 64).unwrap(), b"hello");
+        assert_eq!(memory.read_cstr(message.as_ptr() as usize + 6, 5).unwrap(), b"world");
+
+        let mut copy = [0u8; 4];
+        memory.read_bytes(base + 2, &mut copy).unwrap();
+        assert_eq!(&copy, b"ello");
+    }
+
+    #[test]
+    fn guest_atomics_race_host_threads() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::thread;
+
+        const ROUNDS: u64 = 10000;
+        let code: Vec<u32> = vec![
+            0x00100293, // addi t0, zero, 1
+            0x0055302f, // amoadd.d zero, t0, 0(a0)
+            0xfff58593, // addi a1, a1, -1
+            0xfe059ce3, // bnez a1, -8
+            0x00000073 // ecall
+        ];
+        let counter = AtomicU64::new(0);
+        let address = counter.as_ptr() as usize;
+
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                for _ in 0..ROUNDS {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                }
+            });
+
+            let mut memory = unsafe { HostMemory::new() };
+            let mut cpu = Cpu::new();
+            stop_on_ecall(&mut cpu);
+            cpu.update_pc(code.as_ptr() as usize);
+            cpu.set_register(Register::A0, address as i64);
+            cpu.set_register(Register::A1, ROUNDS as i64);
+
+            loop {
+                match cpu.tick(&mut memory) {
+                    Ok(_) => {},
+                    Err(Trap { trap_type: TrapType::Stop, .. }) => break,
+                    Err(e) => panic!("CPU failure: {:?}", e)
+                }
+            }
+        });
+
+        assert_eq!(counter.load(Ordering::SeqCst), ROUNDS * 2);
+    }
+
+    #[test]
+    fn host_atomics_must_be_aligned() {
+        let mut memory = unsafe { HostMemory::new() };
+        let mut values: [u64; 2] = [5, 0];
+        let base = values.as_mut_ptr() as usize;
+
+        assert_eq!(memory.fetch_op_u64(base, AtomicOp::Min, -3i64 as u64).unwrap(), 5);
+        assert_eq!(memory.compare_exchange_u64(base, 1, 9).unwrap(), -3i64 as u64);
+        assert_eq!(memory.compare_exchange_u32(base, -3i32 as u32, 9).unwrap(), -3i32 as u32);
+        assert_eq!(values[0], 0xffff_ffff_0000_0009);
+
+        let e = memory.fetch_op_u32(base + 2, AtomicOp::Add, 1).unwrap_err();
+        assert!(matches!(e.trap_type, TrapType::StoreAddressMisaligned));
+        assert_eq!(e.value, (base + 2) as u64);
+    }
+}
@@ -0,0 +1,388 @@
+use crate::cpu::Trap;
+use crate::memory::{access_fault, Access, Memory, Permissions};
+use std::cell::RefCell;
+use std::convert::TryInto;
+
+// A memory mapped device. Offsets are relative to the start of the region the device is mapped at.
+// Only the byte wide accesses have to be implemented, wider ones default to little endian byte accesses.
+pub trait Device {
+    fn read_u8(&mut self, offset: usize) -> Result<u8, Trap>;
+    fn write_u8(&mut self, offset: usize, value: u8) -> Result<(), Trap>;
+
+    fn read_u16(&mut self, offset: usize) -> Result<u16, Trap> {
+        Ok(u16::from_le_bytes([self.read_u8(offset)?, self.read_u8(offset + 1)?]))
+    }
+
+    fn read_u32(&mut self, offset: usize) -> Result<u32, Trap> {
+        Ok(self.read_u16(offset)? as u32 | (self.read_u16(offset + 2)? as u32) << 16)
+    }
+
+    fn read_u64(&mut self, offset: usize) -> Result<u64, Trap> {
+        Ok(self.read_u32(offset)? as u64 | (self.read_u32(offset + 4)? as u64) << 32)
+    }
+
+    fn write_u16(&mut self, offset: usize, value: u16) -> Result<(), Trap> {
+        self.write_u8(offset, value as u8)?;
+        self.write_u8(offset + 1, (value >> 8) as u8)
+    }
+
+    fn write_u32(&mut self, offset: usize, value: u32) -> Result<(), Trap> {
+        self.write_u16(offset, value as u16)?;
+        self.write_u16(offset + 2, (value >> 16) as u16)
+    }
+
+    fn write_u64(&mut self, offset: usize, value: u64) -> Result<(), Trap> {
+        self.write_u32(offset, value as u32)?;
+        self.write_u32(offset + 4, (value >> 32) as u32)
+    }
+}
+
+enum RegionKind<'a> {
+    Ram(Vec<u8>),
+    Rom(Vec<u8>),
+    // host buffers borrowed for as long as the map exists, accesses go straight to them
+    Slice(&'a mut [u8]),
+    ReadOnlySlice(&'a [u8]),
+    // reads go through the Memory trait which only has &self, but device reads often have side effects
+    Device(RefCell<Box<dyn Device + 'a>>)
+}
+
+struct Region<'a> {
+    start: usize,
+    size: usize,
+    permissions: Permissions,
+    kind: RegionKind<'a>
+}
+
+// Dispatches every access to the region that contains it. An access has to fit entirely inside a
+// single region, anything else is an access fault.
+pub struct MemoryMap<'a> {
+    regions: Vec<Region<'a>> // sorted by start address and never overlapping
+}
+
+impl<'a> MemoryMap<'a> {
+    pub fn new() -> Self {
+        MemoryMap {
+            regions: Vec::new()
+        }
+    }
+
+    pub fn add_ram(&mut self, start: usize, size: usize, permissions: Permissions) {
+        self.add_region(start, size, permissions, RegionKind::Ram(vec![0; size]));
+    }
+
+    // ROM can never be written to, whatever the permissions say
+    pub fn add_rom(&mut self, start: usize, contents: Vec<u8>, permissions: Permissions) {
+        let permissions = Permissions { write: false, ..permissions };
+        self.add_region(start, contents.len(), permissions, RegionKind::Rom(contents));
+    }
+
+    // Maps a host buffer at the given guest address without copying it. The guest works on the
+    // buffer in place, and the host gets it back once the map is dropped.
+    pub fn map_slice(&mut self, start: usize, slice: &'a mut [u8], permissions: Permissions) {
+        self.add_region(start, slice.len(), permissions, RegionKind::Slice(slice));
+    }
+
+    // a shared borrow can never be written to, whatever the permissions say
+    pub fn map_read_only_slice(&mut self, start: usize, slice: &'a [u8], permissions: Permissions) {
+        let permissions = Permissions { write: false, ..permissions };
+        self.add_region(start, slice.len(), permissions, RegionKind::ReadOnlySlice(slice));
+    }
+
+    // code is never fetched from a device
+    pub fn add_device(&mut self, start: usize, size: usize, device: Box<dyn Device + 'a>) {
+        self.add_region(start, size, Permissions::READ_WRITE, RegionKind::Device(RefCell::new(device)));
+    }
+
+    // changes the permissions of the region starting at the given address, returns false if there is no such region
+    pub fn protect(&mut self, start: usize, permissions: Permissions) -> bool {
+        match self.regions.iter_mut().find(|region| region.start == start) {
+            Some(region) => {
+                region.permissions = match region.kind {
+                    RegionKind::Ram(_) | RegionKind::Slice(_) => permissions,
+                    RegionKind::Rom(_) | RegionKind::ReadOnlySlice(_) => Permissions { write: false, ..permissions },
+                    RegionKind::Device(_) => Permissions { execute: false, ..permissions }
+                };
+                true
+            },
+            None => false
+        }
+    }
+
+    // panics if the new region overlaps one that is already mapped
+    fn add_region(&mut self, start: usize, size: usize, permissions: Permissions, kind: RegionKind<'a>) {
+        let end = start.checked_add(size).expect("region wraps around the address space");
+        let index = self.regions.partition_point(|region| region.start < start);
+
+        if index > 0 {
+            let previous = &self.regions[index - 1];
+            assert!(previous.start + previous.size <= start, "region at {:#x} overlaps the region at {:#x}", start, previous.start);
+        }
+        if let Some(next) = self.regions.get(index) {
+            assert!(end <= next.start, "region at {:#x} overlaps the region at {:#x}", start, next.start);
+        }
+
+        self.regions.insert(index, Region { start, size, permissions, kind });
+    }
+
+    fn index_of(&self, address: usize, size: usize, access: Access) -> Option<(usize, usize)> {
+        let index = self.regions.partition_point(|region| region.start <= address).checked_sub(1)?;
+        let region = &self.regions[index];
+        let offset = address - region.start;
+
+        if offset.checked_add(size)? <= region.size && region.permissions.allows(access) {
+            Some((index, offset))
+        } else {
+            None
+        }
+    }
+
+    fn read<const N: usize>(&self, address: usize, access: Access, device_read: fn(&mut dyn Device, usize) -> Result<[u8; N], Trap>) -> Result<[u8; N], Trap> {
+        match self.index_of(address, N, access) {
+            Some((index, offset)) => match &self.regions[index].kind {
+                RegionKind::Ram(bytes) | RegionKind::Rom(bytes) => Ok(bytes[offset..offset + N].try_into().unwrap()),
+                RegionKind::Slice(bytes) => Ok(bytes[offset..offset + N].try_into().unwrap()),
+                RegionKind::ReadOnlySlice(bytes) => Ok(bytes[offset..offset + N].try_into().unwrap()),
+                RegionKind::Device(device) => device_read(device.borrow_mut().as_mut(), offset)
+            },
+            None => Err(access_fault(access, address))
+        }
+    }
+
+    fn write<const N: usize>(&mut self, address: usize, value: [u8; N], device_write: fn(&mut dyn Device, usize, [u8; N]) -> Result<(), Trap>) -> Result<(), Trap> {
+        match self.index_of(address, N, Access::Write) {
+            Some((index, offset)) => match &mut self.regions[index].kind {
+                RegionKind::Ram(bytes) => {
+                    bytes[offset..offset + N].copy_from_slice(&value);
+                    Ok(())
+                },
+                RegionKind::Slice(bytes) => {
+                    bytes[offset..offset + N].copy_from_slice(&value);
+                    Ok(())
+                },
+                RegionKind::Rom(_) | RegionKind::ReadOnlySlice(_) => Err(access_fault(Access::Write, address)),
+                RegionKind::Device(device) => device_write(device.get_mut().as_mut(), offset, value)
+            },
+            None => Err(access_fault(Access::Write, address))
+        }
+    }
+}
+
+impl Default for MemoryMap<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Memory for MemoryMap<'_> {
+    fn read_u8(&self, address: usize) -> Result<u8, Trap> {
+        self.read(address, Access::Read, |device, offset| Ok([device.read_u8(offset)?])).map(u8::from_le_bytes)
+    }
+
+    fn read_u16(&self, address: usize) -> Result<u16, Trap> {
+        self.read(address, Access::Read, |device, offset| Ok(device.read_u16(offset)?.to_le_bytes())).map(u16::from_le_bytes)
+    }
+
+    fn read_u32(&self, address: usize) -> Result<u32, Trap> {
+        self.read(address, Access::Read, |device, offset| Ok(device.read_u32(offset)?.to_le_bytes())).map(u32::from_le_bytes)
+    }
+
+    fn read_u64(&self, address: usize) -> Result<u64, Trap> {
+        self.read(address, Access::Read, |device, offset| Ok(device.read_u64(offset)?.to_le_bytes())).map(u64::from_le_bytes)
+    }
+
+    fn write_u8(&mut self, address: usize, value: u8) -> Result<(), Trap> {
+        self.write(address, [value], |device, offset, value| device.write_u8(offset, value[0]))
+    }
+
+    fn write_u16(&mut self, address: usize, value: u16) -> Result<(), Trap> {
+        self.write(address, value.to_le_bytes(), |device, offset, value| device.write_u16(offset, u16::from_le_bytes(value)))
+    }
+
+    fn write_u32(&mut self, address: usize, value: u32) -> Result<(), Trap> {
+        self.write(address, value.to_le_bytes(), |device, offset, value| device.write_u32(offset, u32::from_le_bytes(value)))
+    }
+
+    fn write_u64(&mut self, address: usize, value: u64) -> Result<(), Trap> {
+        self.write(address, value.to_le_bytes(), |device, offset, value| device.write_u64(offset, u64::from_le_bytes(value)))
+    }
+
+    fn fetch_u32(&self, address: usize) -> Result<u32, Trap> {
+        self.read(address, Access::Execute, |device, offset| Ok(device.read_u32(offset)?.to_le_bytes())).map(u32::from_le_bytes)
+    }
+}
+
+#[cfg(test)]
+mod test_map {
+    use super::*;
+    use crate::cpu::{Cpu, Register, TrapType};
+    use std::rc::Rc;
+
+    struct Console {
+        output: Rc<RefCell<Vec<u8>>>
+    }
+
+    impl Device for Console {
+        fn read_u8(&mut self, _offset: usize) -> Result<u8, Trap> {
+            Ok(0)
+        }
+
+        fn write_u8(&mut self, _offset: usize, value: u8) -> Result<(), Trap> {
+            self.output.borrow_mut().push(value);
+            Ok(())
+        }
+    }
+
+    struct Timer {
+        ticks: u64
+    }
+
+    impl Device for Timer {
+        fn read_u8(&mut self, offset: usize) -> Result<u8, Trap> {
+            Err(Trap { trap_type: TrapType::LoadAccessFault, value: offset as u64 })
+        }
+
+        fn write_u8(&mut self, offset: usize, _value: u8) -> Result<(), Trap> {
+            Err(Trap { trap_type: TrapType::StoreAccessFault, value: offset as u64 })
+        }
+
+        fn read_u64(&mut self, _offset: usize) -> Result<u64, Trap> {
+            self.ticks += 1;
+            Ok(self.ticks)
+        }
+    }
+
+    #[test]
+    fn dispatches_to_regions() {
+        let mut memory = MemoryMap::new();
+        memory.add_rom(0x1000, vec![1, 2, 3, 4], Permissions::READ_ONLY);
+        memory.add_ram(0x2000, 0x100, Permissions::READ_WRITE);
+
+        assert_eq!(memory.read_u32(0x1000).unwrap(), 0x04030201);
+        memory.write_u16(0x20fe, 0xbeef).unwrap();
+        assert_eq!(memory.read_u16(0x20fe).unwrap(), 0xbeef);
+
+        let e = memory.write_u8(0x1000, 0).unwrap_err();
+        assert!(matches!(e.trap_type, TrapType::StoreAccessFault));
+        let e = memory.read_u32(0x20fe).unwrap_err();
+        assert!(matches!(e.trap_type, TrapType::LoadAccessFault));
+        assert_eq!(e.value, 0x20fe);
+        assert!(memory.read_u8(0x1004).is_err());
+        assert!(memory.read_u8(0).is_err());
+    }
+
+    #[test]
+    #[should_panic]
+    fn regions_cannot_overlap() {
+        let mut memory = MemoryMap::new();
+        memory.add_ram(0x2000, 0x100, Permissions::ALL);
+        memory.add_ram(0x1f00, 0x101, Permissions::ALL);
+    }
+
+    #[test]
+    fn guest_talks_to_devices() {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let code: Vec<u32> = vec![
+            0x06800293, // addi t0, zero, 0x68
+            0x00550023, // sb t0, 0(a0)
+            0x06900293, // addi t0, zero, 0x69
+            0x00550023, // sb t0, 0(a0)
+            0x0005b303, // ld t1, 0(a1)
+            0x0005b303, // ld t1, 0(a1)
+            0x00058023 // sb zero, 0(a1)
+        ];
+
+        let mut memory = MemoryMap::new();
+        memory.add_rom(0, code.iter().flat_map(|word| word.to_le_bytes()).collect(), Permissions::READ_EXECUTE);
+        memory.add_device(0x1000_0000, 1, Box::new(Console { output: output.clone() }));
+        memory.add_device(0x1000_1000, 8, Box::new(Timer { ticks: 0 }));
+
+        let mut cpu = Cpu::new();
+        cpu.set_register(Register::A0, 0x1000_0000);
+        cpu.set_register(Register::A1, 0x1000_1000);
+        for _ in 0..6 {
+            cpu.tick(&mut memory).expect("cpu failure");
+        }
+
+        assert_eq!(output.borrow().as_slice(), b"hi");
+        assert_eq!(cpu.get_register(Register::T1), 2);
+
+        let e = cpu.tick(&mut memory).unwrap_err();
+        assert!(matches!(e.trap_type, TrapType::StoreAccessFault));
+    }
+
+    #[test]
+    fn guest_works_on_host_buffers_in_place() {
+        let code: Vec<u8> = vec![
+            0x83, 0x42, 0x05, 0x00, // lbu t0, 0(a0)
+            0x03, 0xc3, 0x05, 0x00, // lbu t1, 0(a1)
+            0xb3, 0x82, 0x62, 0x00, // add t0, t0, t1
+            0x23, 0x00, 0x55, 0x00, // sb t0, 0(a0)
+            0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+            0x93, 0x85, 0x15, 0x00, // addi a1, a1, 1
+            0x13, 0x06, 0xf6, 0xff, // addi a2, a2, -1
+            0xe3, 0x12, 0x06, 0xfe // bnez a2, -28
+        ];
+        let offsets = [1, 2, 3, 4];
+        let mut image = vec![10u8, 20, 30, 40];
+
+        {
+            let mut memory = MemoryMap::new();
+            memory.map_read_only_slice(0, &code, Permissions::READ_EXECUTE);
+            memory.map_read_only_slice(0x1000, &offsets, Permissions::READ_WRITE);
+            memory.map_slice(0x2000, &mut image, Permissions::READ_WRITE);
+
+            let mut cpu = Cpu::new();
+            cpu.set_register(Register::A0, 0x2000);
+            cpu.set_register(Register::A1, 0x1000);
+            cpu.set_register(Register::A2, 4);
+            for _ in 0..32 {
+                cpu.tick(&mut memory).expect("cpu failure");
+            }
+
+            let e = memory.write_u8(0x1000, 0).unwrap_err();
+            assert!(matches!(e.trap_type, TrapType::StoreAccessFault));
+            assert!(memory.read_u8(0x2004).is_err());
+        }
+
+        assert_eq!(image, [11, 22, 33, 44]);
+    }
+
+    #[test]
+    fn enforces_write_xor_execute() {
+        let code: Vec<u8> = vec![
+            0x93, 0x82, 0x12, 0x00, // addi t0, t0, 1
+            0x23, 0x20, 0x50, 0x00 // sw t0, 0(zero)
+        ];
+
+        let mut memory = MemoryMap::new();
+        memory.add_ram(0, 0x1000, Permissions::READ_WRITE);
+        memory.add_ram(0x1000, 0x1000, Permissions::READ_WRITE);
+        for (i, byte) in code.iter().enumerate() {
+            memory.write_u8(i, *byte).unwrap();
+        }
+
+        let mut cpu = Cpu::new();
+        let e = cpu.tick(&mut memory).unwrap_err();
+        assert!(matches!(e.trap_type, TrapType::InstructionAccessFault));
+        assert_eq!(e.value, 0);
+
+        assert!(memory.protect(0, Permissions::READ_EXECUTE));
+        cpu.update_pc(0);
+        cpu.tick(&mut memory).expect("cpu failure");
+        let e = cpu.tick(&mut memory).unwrap_err();
+        assert!(matches!(e.trap_type, TrapType::StoreAccessFault));
+        assert_eq!(memory.read_u32(0).unwrap(), 0x00128293);
+
+        // code that has already run is fetched again, so taking away execute is noticed straight away
+        assert!(memory.protect(0, Permissions::READ_WRITE));
+        cpu.update_pc(0);
+        let e = cpu.tick(&mut memory).unwrap_err();
+        assert!(matches!(e.trap_type, TrapType::InstructionAccessFault));
+
+        // a region can be locked down completely, but only regions that are mapped can be protected
+        assert!(memory.protect(0x1000, Permissions::NONE));
+        assert!(memory.read_u8(0x1000).is_err());
+        assert!(!memory.protect(0x2000, Permissions::ALL));
+    }
+}
@@ -0,0 +1,233 @@
+use crate::cpu::Trap;
+use crate::memory::{page_spans, Memory, PAGE_SIZE};
+use std::collections::HashMap;
+
+type Page = [u8; PAGE_SIZE];
+
+// Sparse guest memory covering the whole address space. Pages are only allocated when they are
+// first written to, until then they read as zero.
+pub struct PagedMemory {
+    pages: HashMap<usize, Box<Page>>
+}
+
+impl PagedMemory {
+    pub fn new() -> Self {
+        PagedMemory {
+            pages: HashMap::new()
+        }
+    }
+
+    // the number of pages that have actually been allocated
+    pub fn page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    fn read<const N: usize>(&self, address: usize) -> [u8; N] {
+        let mut bytes = [0; N];
+        let offset = address % PAGE_SIZE;
+
+        if offset + N <= PAGE_SIZE {
+            if let Some(page) = self.pages.get(&(address / PAGE_SIZE)) {
+                bytes.copy_from_slice(&page[offset..offset + N]);
+            }
+        } else {
+            // the access straddles two pages
+            for (i, byte) in bytes.iter_mut().enumerate() {
+                let address = address.wrapping_add(i);
+                if let Some(page) = self.pages.get(&(address / PAGE_SIZE)) {
+                    *byte = page[address % PAGE_SIZE];
+                }
+            }
+        }
+
+        bytes
+    }
+
+    fn page_mut(&mut self, address: usize) -> &mut Page {
+        self.pages.entry(address / PAGE_SIZE).or_insert_with(|| Box::new([0; PAGE_SIZE]))
+    }
+
+    fn write<const N: usize>(&mut self, address: usize, bytes: [u8; N]) {
+        let offset = address % PAGE_SIZE;
+
+        if offset + N <= PAGE_SIZE {
+            self.page_mut(address)[offset..offset + N].copy_from_slice(&bytes);
+        } else {
+            for (i, byte) in bytes.iter().enumerate() {
+                let address = address.wrapping_add(i);
+                self.page_mut(address)[address % PAGE_SIZE] = *byte;
+            }
+        }
+    }
+}
+
+impl Default for PagedMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Memory for PagedMemory {
+    #[inline]
+    fn read_u8(&self, address: usize) -> Result<u8, Trap> {
+        Ok(self.read::<1>(address)[0])
+    }
+
+    #[inline]
+    fn read_u16(&self, address: usize) -> Result<u16, Trap> {
+        Ok(u16::from_le_bytes(self.read(address)))
+    }
+
+    #[inline]
+    fn read_u32(&self, address: usize) -> Result<u32, Trap> {
+        Ok(u32::from_le_bytes(self.read(address)))
+    }
+
+    #[inline]
+    fn read_u64(&self, address: usize) -> Result<u64, Trap> {
+        Ok(u64::from_le_bytes(self.read(address)))
+    }
+
+    #[inline]
+    fn write_u8(&mut self, address: usize, value: u8) -> Result<(), Trap> {
+        self.write(address, [value]);
+        Ok(())
+    }
+
+    #[inline]
+    fn write_u16(&mut self, address: usize, value: u16) -> Result<(), Trap> {
+        self.write(address, value.to_le_bytes());
+        Ok(())
+    }
+
+    #[inline]
+    fn write_u32(&mut self, address: usize, value: u32) -> Result<(), Trap> {
+        self.write(address, value.to_le_bytes());
+        Ok(())
+    }
+
+    #[inline]
+    fn write_u64(&mut self, address: usize, value: u64) -> Result<(), Trap> {
+        self.write(address, value.to_le_bytes());
+        Ok(())
+    }
+
+    fn read_bytes(&self, address: usize, buffer: &mut [u8]) -> Result<(), Trap> {
+        let mut done = 0;
+        for (address, length) in page_spans(address, buffer.len()) {
+            let target = &mut buffer[done..done + length];
+            match self.pages.get(&(address / PAGE_SIZE)) {
+                Some(page) => target.copy_from_slice(&page[address % PAGE_SIZE..][..length]),
+                None => target.fill(0)
+            }
+            done += length;
+        }
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, address: usize, bytes: &[u8]) -> Result<(), Trap> {
+        let mut done = 0;
+        for (address, length) in page_spans(address, bytes.len()) {
+            self.page_mut(address)[address % PAGE_SIZE..][..length].copy_from_slice(&bytes[done..done + length]);
+            done += length;
+        }
+        Ok(())
+    }
+
+    fn fill(&mut self, address: usize, length: usize, value: u8) -> Result<(), Trap> {
+        for (address, length) in page_spans(address, length) {
+            // zeroing a page that was never allocated would only allocate it
+            if value != 0 || self.pages.contains_key(&(address / PAGE_SIZE)) {
+                self.page_mut(address)[address % PAGE_SIZE..][..length].fill(value);
+            }
+        }
+        Ok(())
+    }
+
+    fn read_cstr(&self, address: usize, max_len: usize) -> Result<Vec<u8>, Trap> {
+        let mut bytes = Vec::new();
+        for (address, length) in page_spans(address, max_len) {
+            let page = match self.pages.get(&(address / PAGE_SIZE)) {
+                Some(page) => &page[address % PAGE_SIZE..][..length],
+                // an unallocated page reads as zero, so the string ends here
+                None => break
+            };
+
+            match page.iter().position(|byte| *byte == 0) {
+                Some(end) => {
+                    bytes.extend_from_slice(&page[..end]);
+                    break;
+                },
+                None => bytes.extend_from_slice(page)
+            }
+        }
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod test_paged {
+    use super::*;
+
+    #[test]
+    fn untouched_memory_reads_as_zero() {
+        let memory = PagedMemory::new();
+
+        assert_eq!(memory.read_u64(0x8000_0000).unwrap(), 0);
+        assert_eq!(memory.read_u8(usize::MAX).unwrap(), 0);
+        assert_eq!(memory.page_count(), 0);
+    }
+
+    #[test]
+    fn pages_are_allocated_on_write() {
+        let mut memory = PagedMemory::new();
+
+        memory.write_u32(0x8000_0010, 0xdeadbeef).unwrap();
+        memory.write_u64(0x3f_ffff_fff8, u64::MAX).unwrap();
+        assert_eq!(memory.page_count(), 2);
+        assert_eq!(memory.read_u32(0x8000_0010).unwrap(), 0xdeadbeef);
+        assert_eq!(memory.read_i64(0x3f_ffff_fff8).unwrap(), -1);
+        assert_eq!(memory.read_u16(0x8000_0012).unwrap(), 0xdead);
+    }
+
+    #[test]
+    fn accesses_can_straddle_pages() {
+        let mut memory = PagedMemory::new();
+
+        memory.write_u64(PAGE_SIZE * 3 - 3, 0x0807060504030201).unwrap();
+        assert_eq!(memory.page_count(), 2);
+        assert_eq!(memory.read_u64(PAGE_SIZE * 3 - 3).unwrap(), 0x0807060504030201);
+        assert_eq!(memory.read_u16(PAGE_SIZE * 3 - 1).unwrap(), 0x0403);
+        assert_eq!(memory.read_u8(PAGE_SIZE * 3).unwrap(), 0x04);
+    }
+
+    #[test]
+    fn bulk_accesses_span_pages() {
+        let mut memory = PagedMemory::new();
+        let data: Vec<u8> = (0..PAGE_SIZE * 2).map(|i| (i % 251) as u8 + 1).collect();
+
+        memory.write_bytes(PAGE_SIZE - 10, &data).unwrap();
+        assert_eq!(memory.page_count(), 3);
+        let mut buffer = vec![0xff; PAGE_SIZE * 2 + 20];
+        memory.read_bytes(PAGE_SIZE - 20, &mut buffer).unwrap();
+        assert_eq!(buffer[..10], [0; 10]);
+        assert_eq!(buffer[10..PAGE_SIZE * 2 + 10], data[..]);
+        assert_eq!(buffer[PAGE_SIZE * 2 + 10..], [0; 10]);
+
+        memory.fill(PAGE_SIZE * 2 - 1, 2, 0).unwrap();
+        memory.fill(PAGE_SIZE * 8, PAGE_SIZE * 4, 0).unwrap();
+        assert_eq!(memory.page_count(), 3);
+        assert_eq!(memory.read_u16(PAGE_SIZE * 2 - 1).unwrap(), 0);
+    }
+
+    #[test]
+    fn cstr_can_cross_pages() {
+        let mut memory = PagedMemory::new();
+
+        memory.write_bytes(PAGE_SIZE - 3, b"hello\0world").unwrap();
+        assert_eq!(memory.read_cstr(PAGE_SIZE - 3, 64).unwrap(), b"hello");
+        assert_eq!(memory.read_cstr(PAGE_SIZE + 3, 64).unwrap(), b"world");
+        assert_eq!(memory.read_cstr(PAGE_SIZE + 3, 2).unwrap(), b"wo");
+        assert_eq!(memory.read_cstr(PAGE_SIZE * 5, 64).unwrap(), b"");
+    }
+}
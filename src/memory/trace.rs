@@ -0,0 +1,266 @@
+use crate::cpu::Trap;
+use crate::memory::{Access, AtomicOp, Memory};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
+// One completed load or store. Signed reads record the raw bits zero extended to 64 bits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub access: Access,
+    pub address: usize,
+    pub width: usize,
+    pub value: u64
+}
+
+impl TraceRecord {
+    pub fn read(address: usize, width: usize, value: u64) -> Self {
+        TraceRecord { access: Access::Read, address, width, value }
+    }
+
+    pub fn write(address: usize, width: usize, value: u64) -> Self {
+        TraceRecord { access: Access::Write, address, width, value }
+    }
+}
+
+enum TraceSink<'a> {
+    // keeps the most recent records, dropping the oldest once full
+    Buffer(VecDeque<TraceRecord>, usize),
+    Callback(Box<dyn FnMut(&TraceRecord) + 'a>)
+}
+
+// Wraps another memory and records every load and store that succeeds. Instruction fetches are not
+// traced. The bulk accesses fall back to the default byte loops so every byte shows up as its own
+// record, and each atomic shows up as the read of the old value followed by the write of the new one.
+pub struct TracingMemory<'a, M: Memory> {
+    inner: M,
+    sink: RefCell<TraceSink<'a>>
+}
+
+impl<'a, M: Memory> TracingMemory<'a, M> {
+    pub fn with_capacity(inner: M, capacity: usize) -> Self {
+        TracingMemory {
+            inner,
+            sink: RefCell::new(TraceSink::Buffer(VecDeque::with_capacity(capacity), capacity))
+        }
+    }
+
+    pub fn with_callback(inner: M, callback: impl FnMut(&TraceRecord) + 'a) -> Self {
+        TracingMemory {
+            inner,
+            sink: RefCell::new(TraceSink::Callback(Box::new(callback)))
+        }
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> M {
+        self.inner
+    }
+
+    // the buffered records from oldest to newest, always empty when tracing to a callback
+    pub fn records(&self) -> Vec<TraceRecord> {
+        match &*self.sink.borrow() {
+            TraceSink::Buffer(records, _) => records.iter().copied().collect(),
+            TraceSink::Callback(_) => Vec::new()
+        }
+    }
+
+    pub fn clear(&mut self) {
+        if let TraceSink::Buffer(records, _) = self.sink.get_mut() {
+            records.clear();
+        }
+    }
+
+    fn record(&self, record: TraceRecord) {
+        match &mut *self.sink.borrow_mut() {
+            TraceSink::Buffer(records, capacity) => {
+                if *capacity == 0 {
+                    return;
+                }
+                if records.len() == *capacity {
+                    records.pop_front();
+                }
+                records.push_back(record);
+            },
+            TraceSink::Callback(callback) => callback(&record)
+        }
+    }
+
+    fn traced<T: Copy>(&self, record: impl Fn(T) -> TraceRecord, result: Result<T, Trap>) -> Result<T, Trap> {
+        if let Ok(value) = result {
+            self.record(record(value));
+        }
+        result
+    }
+}
+
+impl<M: Memory> Memory for TracingMemory<'_, M> {
+    fn read_i8(&self, address: usize) -> Result<i8, Trap> {
+        self.traced(|value| TraceRecord::read(address, 1, value as u8 as u64), self.inner.read_i8(address))
+    }
+
+    fn read_u8(&self, address: usize) -> Result<u8, Trap> {
+        self.traced(|value| TraceRecord::read(address, 1, value as u64), self.inner.read_u8(address))
+    }
+
+    fn read_i16(&self, address: usize) -> Result<i16, Trap> {
+        self.traced(|value| TraceRecord::read(address, 2, value as u16 as u64), self.inner.read_i16(address))
+    }
+
+    fn read_u16(&self, address: usize) -> Result<u16, Trap> {
+        self.traced(|value| TraceRecord::read(address, 2, value as u64), self.inner.read_u16(address))
+    }
+
+    fn read_i32(&self, address: usize) -> Result<i32, Trap> {
+        self.traced(|value| TraceRecord::read(address, 4, value as u32 as u64), self.inner.read_i32(address))
+    }
+
+    fn read_u32(&self, address: usize) -> Result<u32, Trap> {
+        self.traced(|value| TraceRecord::read(address, 4, value as u64), self.inner.read_u32(address))
+    }
+
+    fn read_i64(&self, address: usize) -> Result<i64, Trap> {
+        self.traced(|value| TraceRecord::read(address, 8, value as u64), self.inner.read_i64(address))
+    }
+
+    fn read_u64(&self, address: usize) -> Result<u64, Trap> {
+        self.traced(|value| TraceRecord::read(address, 8, value), self.inner.read_u64(address))
+    }
+
+    fn write_u8(&mut self, address: usize, value: u8) -> Result<(), Trap> {
+        let result = self.inner.write_u8(address, value);
+        self.traced(|_| TraceRecord::write(address, 1, value as u64), result)
+    }
+
+    fn write_u16(&mut self, address: usize, value: u16) -> Result<(), Trap> {
+        let result = self.inner.write_u16(address, value);
+        self.traced(|_| TraceRecord::write(address, 2, value as u64), result)
+    }
+
+    fn write_u32(&mut self, address: usize, value: u32) -> Result<(), Trap> {
+        let result = self.inner.write_u32(address, value);
+        self.traced(|_| TraceRecord::write(address, 4, value as u64), result)
+    }
+
+    fn write_u64(&mut self, address: usize, value: u64) -> Result<(), Trap> {
+        let result = self.inner.write_u64(address, value);
+        self.traced(|_| TraceRecord::write(address, 8, value), result)
+    }
+
+    fn fetch_u32(&self, address: usize) -> Result<u32, Trap> {
+        self.inner.fetch_u32(address)
+    }
+
+    fn fetch_op_u32(&mut self, address: usize, op: AtomicOp, value: u32) -> Result<u32, Trap> {
+        let result = self.inner.fetch_op_u32(address, op, value);
+        if let Ok(old) = result {
+            self.record(TraceRecord::read(address, 4, old as u64));
+            self.record(TraceRecord::write(address, 4, op.apply_u32(old, value) as u64));
+        }
+        result
+    }
+
+    fn fetch_op_u64(&mut self, address: usize, op: AtomicOp, value: u64) -> Result<u64, Trap> {
+        let result = self.inner.fetch_op_u64(address, op, value);
+        if let Ok(old) = result {
+            self.record(TraceRecord::read(address, 8, old));
+            self.record(TraceRecord::write(address, 8, op.apply_u64(old, value)));
+        }
+        result
+    }
+
+    fn compare_exchange_u32(&mut self, address: usize, expected: u32, new: u32) -> Result<u32, Trap> {
+        let result = self.inner.compare_exchange_u32(address, expected, new);
+        if let Ok(current) = result {
+            self.record(TraceRecord::read(address, 4, current as u64));
+            if current == expected {
+                self.record(TraceRecord::write(address, 4, new as u64));
+            }
+        }
+        result
+    }
+
+    fn compare_exchange_u64(&mut self, address: usize, expected: u64, new: u64) -> Result<u64, Trap> {
+        let result = self.inner.compare_exchange_u64(address, expected, new);
+        if let Ok(current) = result {
+            self.record(TraceRecord::read(address, 8, current));
+            if current == expected {
+                self.record(TraceRecord::write(address, 8, new));
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test_trace {
+    use super::*;
+    use crate::cpu::{Cpu, Register};
+    use crate::memory::PagedMemory;
+
+    #[test]
+    fn records_every_load_and_store() {
+        let mut memory = TracingMemory::with_capacity(vec![0u8; 64], 16);
+
+        memory.write_u32(8, 0xffff_fff0).unwrap();
+        assert_eq!(memory.read_i16(10).unwrap(), -1);
+        assert_eq!(memory.read_i64(0).unwrap(), 0);
+        assert!(memory.read_u64(60).is_err());
+        memory.fetch_op_u32(8, AtomicOp::Add, 0x20).unwrap();
+
+        assert_eq!(memory.records(), vec![
+            TraceRecord::write(8, 4, 0xffff_fff0),
+            TraceRecord::read(10, 2, 0xffff),
+            TraceRecord::read(0, 8, 0),
+            TraceRecord::read(8, 4, 0xffff_fff0),
+            TraceRecord::write(8, 4, 0x10)
+        ]);
+
+        memory.clear();
+        assert!(memory.records().is_empty());
+    }
+
+    #[test]
+    fn buffer_keeps_the_latest_records() {
+        let mut memory = TracingMemory::with_capacity(PagedMemory::new(), 3);
+
+        for i in 0..10 {
+            memory.write_u8(i, i as u8).unwrap();
+        }
+        assert_eq!(memory.records(), vec![
+            TraceRecord::write(7, 1, 7),
+            TraceRecord::write(8, 1, 8),
+            TraceRecord::write(9, 1, 9)
+        ]);
+    }
+
+    #[test]
+    fn callback_sees_guest_accesses() {
+        let code: Vec<u8> = vec![
+            0x83, 0x32, 0x05, 0x00, // ld t0, 0(a0)
+            0x93, 0x82, 0x12, 0x00, // addi t0, t0, 1
+            0x23, 0x34, 0x55, 0x00 // sd t0, 8(a0)
+        ];
+        let mut touched = Vec::new();
+        {
+            let mut inner = PagedMemory::new();
+            inner.write_bytes(0, &code).unwrap();
+            inner.write_u64(0x1000, 41).unwrap();
+            let mut memory = TracingMemory::with_callback(inner, |record| touched.push(*record));
+
+            let mut cpu = Cpu::new();
+            cpu.set_register(Register::A0, 0x1000);
+            for _ in 0..3 {
+                cpu.tick(&mut memory).expect("cpu failure");
+            }
+            assert!(memory.records().is_empty());
+        }
+
+        assert_eq!(touched, vec![
+            TraceRecord::read(0x1000, 8, 41),
+            TraceRecord::write(0x1008, 8, 42)
+        ]);
+    }
+}